@@ -0,0 +1,167 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::collections::VecDeque;
+
+use crate::{
+    ClockSyncResponseMessageData,
+    DateTime,
+};
+
+/// Number of most recent exchanges the clock offset is estimated from.
+pub const CLOCK_SYNC_WINDOW: usize = 8;
+
+/// Outcome of a single NTP-style exchange with the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// Time to add to the local clock to obtain the server's clock.
+    pub offset: chrono::Duration,
+    /// Round-trip time of the exchange without the server's processing time.
+    pub delay: chrono::Duration,
+}
+
+impl ClockSample {
+    pub fn new(
+        client_transmit: DateTime,
+        server_receive: DateTime,
+        server_transmit: DateTime,
+        client_receive: DateTime,
+    ) -> Self {
+        Self {
+            offset: ((server_receive - client_transmit) + (server_transmit - client_receive)) / 2,
+            delay: (client_receive - client_transmit) - (server_transmit - server_receive),
+        }
+    }
+
+    pub fn from_response(data: &ClockSyncResponseMessageData, client_receive: DateTime) -> Self {
+        Self::new(
+            data.client_transmit,
+            data.server_receive,
+            data.server_transmit,
+            client_receive,
+        )
+    }
+}
+
+/// Estimates the offset of the local clock to the server's clock from recent exchanges.
+///
+/// The exchange with the lowest delay is the least affected by queuing on either leg, so its
+/// offset is taken as the estimate.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, sample: ClockSample) {
+        if self.samples.len() == CLOCK_SYNC_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Estimated offset of the local clock to the server's clock, once a sample has been taken.
+    pub fn offset(&self) -> Option<chrono::Duration> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.delay)
+            .map(|sample| sample.offset)
+    }
+
+    /// Converts a local time to the server's clock.
+    pub fn to_server_time(&self, local: DateTime) -> Option<DateTime> {
+        self.offset().map(|offset| local + offset)
+    }
+
+    /// The current time on the server's clock.
+    pub fn server_now(&self) -> Option<DateTime> {
+        self.to_server_time(chrono::Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        Duration,
+        TimeZone,
+        Utc,
+    };
+
+    use super::*;
+
+    fn at(millis: i64) -> DateTime {
+        Utc.timestamp_millis(1_600_000_000_000 + millis)
+    }
+
+    /// Exchange with a server whose clock is ahead by `offset`, given the time each leg takes and
+    /// the time the server takes to respond, all in milliseconds.
+    fn exchange(offset: i64, uplink: i64, processing: i64, downlink: i64) -> ClockSample {
+        let client_transmit = at(0);
+        let server_receive = at(offset + uplink);
+        let server_transmit = at(offset + uplink + processing);
+        let client_receive = at(uplink + processing + downlink);
+
+        ClockSample::from_response(
+            &ClockSyncResponseMessageData {
+                client_transmit,
+                server_receive,
+                server_transmit,
+            },
+            client_receive,
+        )
+    }
+
+    #[test]
+    fn symmetric_paths_yield_the_exact_offset() {
+        let sample = exchange(100, 20, 5, 20);
+        assert_eq!(sample.offset, Duration::milliseconds(100));
+        assert_eq!(sample.delay, Duration::milliseconds(40));
+
+        let sample = exchange(-250, 15, 0, 15);
+        assert_eq!(sample.offset, Duration::milliseconds(-250));
+        assert_eq!(sample.delay, Duration::milliseconds(30));
+    }
+
+    #[test]
+    fn asymmetric_paths_skew_the_offset_by_half_the_difference() {
+        let sample = exchange(100, 30, 5, 10);
+        assert_eq!(sample.offset, Duration::milliseconds(110));
+        assert_eq!(sample.delay, Duration::milliseconds(40));
+
+        let sample = exchange(100, 10, 5, 30);
+        assert_eq!(sample.offset, Duration::milliseconds(90));
+        assert_eq!(sample.delay, Duration::milliseconds(40));
+    }
+
+    #[test]
+    fn offset_is_taken_from_the_sample_with_the_lowest_delay() {
+        let mut clock_sync = ClockSync::new();
+        assert_eq!(clock_sync.offset(), None);
+        assert_eq!(clock_sync.to_server_time(at(0)), None);
+
+        clock_sync.add(exchange(100, 80, 5, 20));
+        clock_sync.add(exchange(100, 10, 5, 10));
+        clock_sync.add(exchange(100, 20, 5, 60));
+        assert_eq!(clock_sync.offset(), Some(Duration::milliseconds(100)));
+        assert_eq!(clock_sync.to_server_time(at(0)), Some(at(100)));
+    }
+
+    #[test]
+    fn samples_outside_the_window_are_forgotten() {
+        let mut clock_sync = ClockSync::new();
+        clock_sync.add(exchange(100, 10, 5, 10));
+        for _ in 0..CLOCK_SYNC_WINDOW - 1 {
+            clock_sync.add(exchange(100, 60, 5, 20));
+        }
+        assert_eq!(clock_sync.offset(), Some(Duration::milliseconds(100)));
+
+        clock_sync.add(exchange(100, 60, 5, 20));
+        assert_eq!(clock_sync.offset(), Some(Duration::milliseconds(120)));
+    }
+}
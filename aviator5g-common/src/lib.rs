@@ -3,6 +3,9 @@
  * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
  */
 
+mod clock;
+mod media;
+
 use std::str::FromStr;
 
 use serde::{
@@ -11,6 +14,17 @@ use serde::{
 };
 use uuid::Uuid;
 
+pub use crate::clock::{
+    ClockSample,
+    ClockSync,
+    CLOCK_SYNC_WINDOW,
+};
+pub use crate::media::{
+    VideoFrame,
+    MAX_STREAM_ID_LENGTH,
+    VIDEO_FRAME_VERSION,
+};
+
 pub type Id = Uuid;
 pub type DateTime = chrono::DateTime<chrono::Utc>;
 
@@ -18,11 +32,13 @@ pub fn id_from_str(id: &str) -> Id {
     Uuid::from_str(id).expect("Invalid UUID string")
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientType {
     Pilot,
     Vehicle,
+    /// Receives everything sent within its group but may not send control messages.
+    Observer,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,19 +47,33 @@ pub struct IdentificationMessageData {
     pub id: Id,
     pub group_id: Id,
     pub client_type: ClientType,
+    /// Shared secret required by groups whose access control list is based on credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct ControlMessageData {
     pub axes: Vec<f64>,
+    /// Vehicle to which the message is routed instead of all vehicles in the group.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<Id>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct LatencyRequestMessageData {
     pub initiator_id: Id,
+    /// Time at which the request was sent on the initiator's clock.
     pub timestamp: DateTime,
+    /// Client to which the request is routed instead of all clients in the group.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<Id>,
+    /// Time at which the request was sent on the server's clock, if the initiator has
+    /// synchronized its clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,20 +81,296 @@ pub struct LatencyRequestMessageData {
 pub struct LatencyResponseMessageData {
     pub initiator_id: Id,
     pub responder_id: Id,
+    /// The request's timestamp on the initiator's clock, from which the round-trip time follows.
     pub timestamp: DateTime,
+    /// The request's `sent_at`, echoed so that the initiator can compute the one-way latency.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_sent_at: Option<DateTime>,
+    /// Time at which the request was received on the server's clock, if the responder has
+    /// synchronized its clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_received_at: Option<DateTime>,
+    /// Time at which the response was sent on the server's clock, if the responder has
+    /// synchronized its clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime>,
+}
+
+impl LatencyResponseMessageData {
+    /// Latency from the initiator to the responder, if both have synchronized their clocks.
+    pub fn request_latency(&self) -> Option<chrono::Duration> {
+        Some(self.request_received_at? - self.request_sent_at?)
+    }
+
+    /// Latency from the responder to the initiator, given the time at which the response has been
+    /// received on the server's clock.
+    pub fn response_latency(&self, received_at: DateTime) -> Option<chrono::Duration> {
+        Some(received_at - self.sent_at?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityConflictOutcome {
+    Rejected,
+    Superseded,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct IdentityConflictMessageData {
+    pub id: Id,
+    pub outcome: IdentityConflictOutcome,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Malformed,
+    NotIdentified,
+    AlreadyIdentified,
+    Unauthorized,
+    Forbidden,
+    UnknownTarget,
+    SessionExpired,
+    RateLimited,
+    MessageTooLarge,
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ErrorMessageData {
+    pub code: ErrorCode,
+    pub message: String,
+    pub fatal: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GoingAwayMessageData {
+    pub reason: String,
+    pub deadline: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SessionMessageData {
+    /// Token with which a reconnecting client resumes its session within the grace period.
+    pub resume_token: String,
+    /// Whether an existing session has been resumed rather than a new one started.
+    pub resumed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ResumeMessageData {
+    pub resume_token: String,
+}
+
+/// Starts an NTP-style exchange with which a client estimates the offset of its clock to the
+/// server's clock.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ClockSyncRequestMessageData {
+    pub client_transmit: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ClockSyncResponseMessageData {
+    /// The request's `client_transmit`, echoed.
+    pub client_transmit: DateTime,
+    /// Time at which the server received the request.
+    pub server_receive: DateTime,
+    /// Time at which the server sent this response.
+    pub server_transmit: DateTime,
+}
+
+/// Quality of the link between the server and one client, measured by the server.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct LinkQuality {
+    pub id: Id,
+    pub client_type: ClientType,
+    /// Mean round-trip time in milliseconds over the recent probes, if any have been answered.
+    pub rtt_ms: Option<f64>,
+    pub min_rtt_ms: Option<f64>,
+    pub max_rtt_ms: Option<f64>,
+    /// Mean difference in milliseconds between consecutive round-trip times.
+    pub jitter_ms: Option<f64>,
+    /// Share of the recent probes that have not been answered, from 0 to 1.
+    pub loss: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LinkQualityMessageData {
+    pub links: Vec<LinkQuality>,
+}
+
+/// Announces the video streams a vehicle sends, replacing its previous advertisement.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct StreamAdvertisementMessageData {
+    /// The advertising vehicle.
+    pub source_id: Id,
+    pub stream_ids: Vec<String>,
+}
+
+/// Starts or stops the delivery of a vehicle's video stream to the sender.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct StreamSubscriptionMessageData {
+    pub source_id: Id,
+    pub stream_id: String,
+}
+
+/// Region of a video frame in which a frame processor has detected something. Coordinates and
+/// sizes are relative to the frame's width and height, from 0 to 1.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct BoundingBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+}
+
+/// Describes a video frame as a whole.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Label {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+}
+
+/// Annotations of a video frame by one of the server's frame processors. Once a processor stops
+/// finding anything, it sends a single message without annotations to clear the previous ones.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AnnotationsMessageData {
+    pub source_id: Id,
+    pub stream_id: String,
+    pub captured_at: DateTime,
+    pub processor: String,
+    pub boxes: Vec<BoundingBox>,
+    pub labels: Vec<Label>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Raised by one of the server's frame processors about an event in a video stream.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AlertMessageData {
+    pub source_id: Id,
+    pub stream_id: String,
+    pub captured_at: DateTime,
+    pub processor: String,
+    pub severity: AlertSeverity,
+    pub message: String,
+}
+
+/// State of a vehicle as reported by its flight controller. Values the flight controller has not
+/// reported yet are omitted.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct TelemetryMessageData {
+    /// The reporting vehicle.
+    pub source_id: Id,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub armed: Option<bool>,
+    /// Flight mode as numbered by the flight controller's firmware.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Battery voltage in volts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_voltage: Option<f64>,
+    /// Remaining battery capacity from 0 to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_remaining: Option<f64>,
+    /// Latitude in degrees.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    /// Longitude in degrees.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Altitude above mean sea level in meters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    /// Altitude above the home position in meters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_altitude: Option<f64>,
+    /// Roll angle in radians.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roll: Option<f64>,
+    /// Pitch angle in radians.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f64>,
+    /// Yaw angle in radians.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yaw: Option<f64>,
+    /// Heading in degrees from north.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<f64>,
+    /// Airspeed in meters per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub airspeed: Option<f64>,
+    /// Groundspeed in meters per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groundspeed: Option<f64>,
+    /// Climb rate in meters per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub climb_rate: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ControlMessage {
     Identification(IdentificationMessageData),
+    IdentityConflict(IdentityConflictMessageData),
     Control(ControlMessageData),
     LatencyRequest(LatencyRequestMessageData),
     LatencyResponse(LatencyResponseMessageData),
+    Error(ErrorMessageData),
+    GoingAway(GoingAwayMessageData),
+    Session(SessionMessageData),
+    Resume(ResumeMessageData),
+    LinkQuality(LinkQualityMessageData),
+    ClockSyncRequest(ClockSyncRequestMessageData),
+    ClockSyncResponse(ClockSyncResponseMessageData),
+    Annotations(AnnotationsMessageData),
+    Alert(AlertMessageData),
+    StreamAdvertisement(StreamAdvertisementMessageData),
+    Subscribe(StreamSubscriptionMessageData),
+    Unsubscribe(StreamSubscriptionMessageData),
+    Telemetry(TelemetryMessageData),
+}
+
+impl ControlMessage {
+    /// Whether the message describes the sender's current state, so that only its most recent
+    /// instance is relevant to clients joining later.
+    pub fn is_state(&self) -> bool {
+        matches!(
+            self,
+            Self::Control(_) | Self::StreamAdvertisement(_) | Self::Telemetry(_)
+        )
+    }
 }
 
 pub fn parse_control_message(message: &str) -> Result<ControlMessage, String> {
-    serde_json::from_str(&message).map_err(|e| e.to_string())
+    serde_json::from_str(message).map_err(|e| e.to_string())
 }
 
 pub fn build_control_message(control_message: &ControlMessage) -> String {
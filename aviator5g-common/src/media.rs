@@ -0,0 +1,162 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use chrono::TimeZone;
+
+use crate::{
+    DateTime,
+    Id,
+};
+
+/// Version of the binary video frame layout.
+pub const VIDEO_FRAME_VERSION: u8 = 1;
+
+/// Maximum length of a stream id in bytes.
+pub const MAX_STREAM_ID_LENGTH: usize = u8::MAX as usize;
+
+const HEADER_LENGTH: usize = 1 + 16 + 8 + 1;
+
+/// A JPEG-encoded video frame, sent as a binary WebSocket message.
+///
+/// The frame is laid out as the version, the id of the vehicle that captured it, the capture time
+/// in microseconds since the Unix epoch as a big-endian `i64`, the length of the stream id, the
+/// UTF-8 stream id and finally the JPEG data. Vehicles may leave the source id nil, the server
+/// replaces it with the sender's id before relaying the frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+    pub source_id: Id,
+    pub stream_id: String,
+    pub captured_at: DateTime,
+    pub jpeg: Vec<u8>,
+}
+
+impl VideoFrame {
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let stream_id = self.stream_id.as_bytes();
+        if stream_id.len() > MAX_STREAM_ID_LENGTH {
+            return Err(format!(
+                "Stream id of {} bytes exceeds the maximum of {} bytes",
+                stream_id.len(),
+                MAX_STREAM_ID_LENGTH
+            ));
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + stream_id.len() + self.jpeg.len());
+        bytes.push(VIDEO_FRAME_VERSION);
+        bytes.extend_from_slice(self.source_id.as_bytes());
+        let micros = self.captured_at.timestamp() * 1_000_000
+            + i64::from(self.captured_at.timestamp_subsec_micros());
+        bytes.extend_from_slice(&micros.to_be_bytes());
+        bytes.push(stream_id.len() as u8);
+        bytes.extend_from_slice(stream_id);
+        bytes.extend_from_slice(&self.jpeg);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(format!("Video frame of {} bytes is truncated", bytes.len()));
+        }
+        if bytes[0] != VIDEO_FRAME_VERSION {
+            return Err(format!("Unsupported video frame version {}", bytes[0]));
+        }
+
+        let source_id = Id::from_slice(&bytes[1..17]).map_err(|e| e.to_string())?;
+        let micros = i64::from_be_bytes(bytes[17..25].try_into().unwrap());
+        let captured_at = chrono::Utc
+            .timestamp_opt(
+                micros.div_euclid(1_000_000),
+                (micros.rem_euclid(1_000_000) * 1000) as u32,
+            )
+            .single()
+            .ok_or_else(|| format!("Invalid capture time {}", micros))?;
+
+        let stream_id_end = HEADER_LENGTH + bytes[25] as usize;
+        let stream_id = bytes
+            .get(HEADER_LENGTH..stream_id_end)
+            .ok_or_else(|| "Video frame is truncated within its stream id".to_owned())?;
+        let stream_id = String::from_utf8(stream_id.to_vec()).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            source_id,
+            stream_id,
+            captured_at,
+            jpeg: bytes[stream_id_end..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(stream_id: &str) -> VideoFrame {
+        VideoFrame {
+            source_id: Id::from_u128(0x0011_2233_4455_6677_8899_aabb_ccdd_eeff),
+            stream_id: stream_id.into(),
+            captured_at: chrono::Utc
+                .timestamp_opt(1_600_000_000, 123_456_000)
+                .unwrap(),
+            jpeg: vec![0xff, 0xd8, 0xff, 0xd9],
+        }
+    }
+
+    #[test]
+    fn frames_are_laid_out_as_documented() {
+        let bytes = frame("fpv").encode().unwrap();
+
+        assert_eq!(bytes[0], VIDEO_FRAME_VERSION);
+        assert_eq!(
+            bytes[1..17],
+            [
+                0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+        assert_eq!(bytes[17..25], 1_600_000_000_123_456i64.to_be_bytes());
+        assert_eq!(bytes[25..], [3, b'f', b'p', b'v', 0xff, 0xd8, 0xff, 0xd9]);
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip() {
+        for frame in [
+            frame("fpv"),
+            frame(""),
+            frame(&"x".repeat(MAX_STREAM_ID_LENGTH)),
+            VideoFrame {
+                captured_at: chrono::Utc.timestamp_opt(-1, 999_999_000).unwrap(),
+                jpeg: Vec::new(),
+                ..frame("survey")
+            },
+        ] {
+            assert_eq!(VideoFrame::decode(&frame.encode().unwrap()), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn overlong_stream_ids_are_not_encoded() {
+        assert!(frame(&"x".repeat(MAX_STREAM_ID_LENGTH + 1))
+            .encode()
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let bytes = frame("fpv").encode().unwrap();
+
+        assert!(VideoFrame::decode(&[]).is_err());
+        assert!(VideoFrame::decode(&bytes[..HEADER_LENGTH - 1]).is_err());
+        assert!(VideoFrame::decode(&bytes[..HEADER_LENGTH + 2]).is_err());
+        assert!(VideoFrame::decode(&bytes[..HEADER_LENGTH + 3]).is_ok());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut bytes = frame("fpv").encode().unwrap();
+        bytes[0] = VIDEO_FRAME_VERSION + 1;
+
+        assert!(VideoFrame::decode(&bytes).is_err());
+    }
+}
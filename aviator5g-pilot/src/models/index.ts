@@ -8,6 +8,18 @@ import settings from "@/store/settings";
 export type Opaque<K, T> = T & { __TYPE__: K };
 export type Uuid = Opaque<"Uuid", string>;
 
+export type ClientType = "pilot" | "vehicle" | "observer";
+
+export interface ILinkQuality {
+    id: Uuid;
+    client_type: ClientType;
+    rtt_ms: number | null;
+    min_rtt_ms: number | null;
+    max_rtt_ms: number | null;
+    jitter_ms: number | null;
+    loss: number;
+}
+
 export interface IVehicleState {
     aileronsValue: number;
     aileronsTrim: number;
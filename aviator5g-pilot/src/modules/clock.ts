@@ -0,0 +1,51 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+import { DateTime, Duration } from "luxon";
+
+const CLOCK_SYNC_WINDOW = 8;
+
+interface IClockSample {
+    offset: number;
+    delay: number;
+}
+
+/**
+ * Estimates the offset of the local clock to the server's clock from NTP-style exchanges,
+ * using the recent exchange with the lowest delay as it is the least affected by queuing.
+ */
+export class ClockSync {
+    private samples: IClockSample[] = [];
+
+    add(clientTransmit: DateTime, serverReceive: DateTime, serverTransmit: DateTime, clientReceive: DateTime): void {
+        const t0 = clientTransmit.toMillis();
+        const t1 = serverReceive.toMillis();
+        const t2 = serverTransmit.toMillis();
+        const t3 = clientReceive.toMillis();
+
+        this.samples.push({
+            offset: ((t1 - t0) + (t2 - t3)) / 2,
+            delay: (t3 - t0) - (t2 - t1),
+        });
+
+        if(this.samples.length > CLOCK_SYNC_WINDOW) {
+            this.samples.shift();
+        }
+    }
+
+    offset(): Duration | null {
+        if(this.samples.length === 0) {
+            return null;
+        }
+
+        const best = this.samples.reduce((a, b) => b.delay < a.delay ? b : a);
+        return Duration.fromMillis(best.offset);
+    }
+
+    serverNow(): DateTime | null {
+        const offset = this.offset();
+        return offset ? DateTime.now().plus(offset) : null;
+    }
+}
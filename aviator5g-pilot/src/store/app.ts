@@ -18,10 +18,12 @@ import {
 import store from "@/store";
 
 import * as utils from "@/modules/utils";
+import { ClockSync } from "@/modules/clock";
 import settings from "@/store/settings";
 
 import {
     defaultVehicleState,
+    ILinkQuality,
     IVehicleState,
     Uuid,
 } from "@/models";
@@ -30,10 +32,14 @@ VuexModuleDecoratorsConfig.rawError = true;
 
 const SOCKET_ENDPOINT = process.env.VUE_APP_SOCKET_ENDPOINT_URL;
 const CAMERA_STREAM_ENDPOINT = process.env.VUE_APP_CAMERA_STREAM_ENDPOINT_URL;
+const CAMERA_STREAM_ID = process.env.VUE_APP_CAMERA_STREAM_ID || "camera";
 
 const DEFAULT_GROUP_ID: Uuid = process.env.VUE_APP_DEFAULT_GROUP_ID;
+const CREDENTIAL: string | undefined = process.env.VUE_APP_CREDENTIAL || undefined;
 const LATENCY_CHECK_INTERVAL_MS = 2000;
 
+const clockSync = new ClockSync();
+
 function calculateAxisValue(value: number, trim: number, reverse: boolean): number {
     const r = reverse ? +1 : -1;
     return value * r + trim * r;
@@ -49,14 +55,41 @@ export class AppModule extends VuexModule {
     private rws: ReconnectingWebSocket | null = null;
     private latencyInterval = 0;
 
-    cameraStreamEndpointUrl = CAMERA_STREAM_ENDPOINT;
-
     isConnected = false;
+    resumeToken: string | null = null;
     roundTripLatency: Duration = Duration.fromMillis(0);
+    requestLatency: Duration | null = null;
+    responseLatency: Duration | null = null;
+    links: ILinkQuality[] = [];
+    streams: { [vehicleId: string]: string[] } = {};
 
     vehicleId = utils.uuid4();
     vehicleState: IVehicleState = defaultVehicleState();
 
+    get cameraStreamEndpointUrl(): string | null {
+        const vehicle = this.links.find(link => link.client_type === "vehicle");
+        if(!vehicle) {
+            return null;
+        }
+
+        // Prefer the configured stream unless the vehicle advertises others only.
+        const streamIds = this.streams[vehicle.id] || [];
+        const streamId = streamIds.length === 0 || streamIds.includes(CAMERA_STREAM_ID)
+            ? CAMERA_STREAM_ID
+            : streamIds[0];
+
+        // The stream is only served to clients that identify like they do on the socket.
+        const query = new URLSearchParams({
+            "group_id": DEFAULT_GROUP_ID,
+            "id": this.vehicleId,
+        });
+        if(CREDENTIAL) {
+            query.set("credential", CREDENTIAL);
+        }
+
+        return `${CAMERA_STREAM_ENDPOINT}/streams/${vehicle.id}/${encodeURIComponent(streamId)}?${query}`;
+    }
+
     @Mutation
     initializeStore(): void {
         this.vehicleId = settings.vehicleId || utils.uuid4();
@@ -73,7 +106,10 @@ export class AppModule extends VuexModule {
         this.rws.addEventListener("close", () => app.doHandleCloseConnection());
         this.rws.addEventListener("message", e => app.doHandleMessage(e));
 
-        this.latencyInterval = setInterval(() => app.doSendLatencyRequest(), LATENCY_CHECK_INTERVAL_MS);
+        this.latencyInterval = setInterval(() => {
+            app.doSendClockSyncRequest();
+            app.doSendLatencyRequest();
+        }, LATENCY_CHECK_INTERVAL_MS);
     }
 
     @Mutation
@@ -93,11 +129,32 @@ export class AppModule extends VuexModule {
         this.isConnected = isConnected;
     }
 
+    @Mutation
+    setResumeToken(resumeToken: string | null): void {
+        this.resumeToken = resumeToken;
+    }
+
     @Mutation
     setRoundTripLatency(roundTripLatency: Duration): void {
         this.roundTripLatency = roundTripLatency;
     }
 
+    @Mutation
+    setOneWayLatencies(latencies: { request: Duration | null; response: Duration | null }): void {
+        this.requestLatency = latencies.request;
+        this.responseLatency = latencies.response;
+    }
+
+    @Mutation
+    setLinks(links: ILinkQuality[]): void {
+        this.links = links;
+    }
+
+    @Mutation
+    setStreams(payload: { vehicleId: Uuid; streamIds: string[] }): void {
+        this.streams = { ...this.streams, [payload.vehicleId]: payload.streamIds };
+    }
+
     @Mutation
     updateVehicleState(state: Partial<IVehicleState>): void {
         Object.assign(this.vehicleState, state);
@@ -107,7 +164,12 @@ export class AppModule extends VuexModule {
     @Action
     doHandleOpenConnection(): void {
         this.context.commit("setConnectionState", true);
-        this.doSendIdentification();
+
+        if(this.resumeToken) {
+            this.doSendResume();
+        } else {
+            this.doSendIdentification();
+        }
     }
 
     @Action
@@ -122,6 +184,33 @@ export class AppModule extends VuexModule {
         if(message.type === "latency_response") {
             const timestamp = DateTime.fromISO(message.timestamp);
             this.context.commit("setRoundTripLatency", DateTime.now().diff(timestamp));
+
+            // One-way latencies are only known once both sides have synchronized their clocks.
+            const serverNow = clockSync.serverNow();
+            this.context.commit("setOneWayLatencies", {
+                request: message.request_sent_at && message.request_received_at
+                    ? DateTime.fromISO(message.request_received_at).diff(DateTime.fromISO(message.request_sent_at))
+                    : null,
+                response: message.sent_at && serverNow
+                    ? serverNow.diff(DateTime.fromISO(message.sent_at))
+                    : null,
+            });
+        } else if(message.type === "clock_sync_response") {
+            clockSync.add(
+                DateTime.fromISO(message.client_transmit),
+                DateTime.fromISO(message.server_receive),
+                DateTime.fromISO(message.server_transmit),
+                DateTime.now(),
+            );
+        } else if(message.type === "link_quality") {
+            this.context.commit("setLinks", message.links);
+        } else if(message.type === "stream_advertisement") {
+            this.context.commit("setStreams", { vehicleId: message.source_id, streamIds: message.stream_ids });
+        } else if(message.type === "session") {
+            this.context.commit("setResumeToken", message.resume_token);
+        } else if(message.type === "error" && message.code === "session_expired") {
+            this.context.commit("setResumeToken", null);
+            this.doSendIdentification();
         }
     }
 
@@ -136,6 +225,31 @@ export class AppModule extends VuexModule {
             "group_id": DEFAULT_GROUP_ID,
             "id": this.vehicleId,
             "client_type": "pilot",
+            "credential": CREDENTIAL,
+        }));
+    }
+
+    @Action
+    doSendResume(): void {
+        if(!this.isConnected || !this.rws) {
+            return;
+        }
+
+        this.rws.send(JSON.stringify({
+            "type": "resume",
+            "resume_token": this.resumeToken,
+        }));
+    }
+
+    @Action
+    doSendClockSyncRequest(): void {
+        if(!this.isConnected || !this.rws) {
+            return;
+        }
+
+        this.rws.send(JSON.stringify({
+            "type": "clock_sync_request",
+            "client_transmit": DateTime.now().toISO(),
         }));
     }
 
@@ -149,6 +263,7 @@ export class AppModule extends VuexModule {
             "type": "latency_request",
             "initiator_id": this.vehicleId,
             "timestamp": DateTime.now().toISO(),
+            "sent_at": clockSync.serverNow()?.toISO(),
         }));
     }
 
@@ -0,0 +1,159 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Measures the broker's forwarding throughput with many concurrent connections.
+//!
+//! Connections are split into groups of one pilot and several vehicles. Every pilot sends a
+//! number of control messages that the broker fans out to all vehicles of its group, and the
+//! benchmark reports how long it took until every vehicle has received every message.
+//!
+//! Start the server with rate limits that do not throttle the benchmark and without a group
+//! registry, as every run uses new groups, for example:
+//!
+//! ```text
+//! ulimit -n 8192
+//! cargo run --release -- --rate-limit 1e9 --rate-limit-burst 1e9 \
+//!     --group-rate-limit 1e9 --group-rate-limit-burst 1e9 --allow-unknown-groups true
+//! cargo run --release --example fanout_benchmark -- --connections 1000
+//! ```
+
+use std::time::Instant;
+
+use argh::FromArgs;
+use aviator5g_common::{
+    ClientType,
+    ControlMessage,
+    ControlMessageData,
+    Id,
+    IdentificationMessageData,
+};
+use futures_util::{
+    future,
+    SinkExt,
+    StreamExt,
+};
+
+/// Aviator5G fan-out benchmark.
+#[derive(Debug, Clone, FromArgs)]
+struct Args {
+    /// the server's endpoint.
+    #[argh(option, default = r#""ws://localhost:9000".into()"#)]
+    url: String,
+
+    /// total number of connections to open.
+    #[argh(option, default = "1000")]
+    connections: usize,
+
+    /// number of connections per group, one of which is the pilot.
+    #[argh(option, default = "10")]
+    group_size: usize,
+
+    /// number of control messages each pilot sends.
+    #[argh(option, default = "1000")]
+    messages: usize,
+}
+
+fn identification(group_id: Id, client_type: ClientType) -> tungstenite::Message {
+    tungstenite::Message::Text(aviator5g_common::build_control_message(
+        &ControlMessage::Identification(IdentificationMessageData {
+            id: Id::new_v4(),
+            group_id,
+            client_type,
+            credential: None,
+        }),
+    ))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Args = argh::from_env();
+    let url = url::Url::parse(&args.url)?;
+    let group_count = args.connections / args.group_size;
+    let vehicles_per_group = args.group_size - 1;
+
+    println!(
+        "Connecting {} groups of 1 pilot and {} vehicles...",
+        group_count, vehicles_per_group
+    );
+
+    let mut pilots = Vec::with_capacity(group_count);
+    let mut vehicles = Vec::with_capacity(group_count * vehicles_per_group);
+
+    for _ in 0..group_count {
+        let group_id = Id::new_v4();
+
+        for _ in 0..vehicles_per_group {
+            let (mut ws_stream, _) = tokio_tungstenite::connect_async(url.clone()).await?;
+            ws_stream
+                .send(identification(group_id, ClientType::Vehicle))
+                .await?;
+            vehicles.push(ws_stream);
+        }
+
+        let (mut ws_stream, _) = tokio_tungstenite::connect_async(url.clone()).await?;
+        ws_stream
+            .send(identification(group_id, ClientType::Pilot))
+            .await?;
+        pilots.push(ws_stream);
+    }
+
+    let control = tungstenite::Message::Text(aviator5g_common::build_control_message(
+        &ControlMessage::Control(ControlMessageData {
+            axes: vec![0.0, 0.0, 0.0, 0.0],
+            target_id: None,
+        }),
+    ));
+
+    let messages = args.messages;
+    let receivers = vehicles.into_iter().map(|mut ws_stream| {
+        tokio::spawn(async move {
+            let mut received = 0;
+            while received < messages {
+                match ws_stream.next().await {
+                    Some(Ok(tungstenite::Message::Text(_))) => received += 1,
+                    Some(Ok(_)) => {}
+                    _ => break,
+                }
+            }
+            received
+        })
+    });
+
+    let started_at = Instant::now();
+
+    let senders = pilots.into_iter().map(|mut ws_stream| {
+        let control = control.clone();
+        tokio::spawn(async move {
+            for _ in 0..messages {
+                ws_stream.feed(control.clone()).await?;
+            }
+            ws_stream.flush().await?;
+
+            // Keep the pilot connected until all vehicles have received their messages.
+            Ok::<_, tungstenite::Error>(ws_stream)
+        })
+    });
+
+    let (senders, receivers) =
+        future::join(future::join_all(senders), future::join_all(receivers)).await;
+    let elapsed = started_at.elapsed();
+
+    for sender in senders {
+        sender??;
+    }
+
+    let delivered: usize = receivers.into_iter().map(|r| r.unwrap_or(0)).sum();
+    let expected = group_count * vehicles_per_group * messages;
+
+    println!(
+        "Delivered {}/{} messages in {:.3}s ({:.0} messages/s)",
+        delivered,
+        expected,
+        elapsed.as_secs_f64(),
+        delivered as f64 / elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use aviator5g_common::{
+    ClientType,
+    Id,
+    IdentificationMessageData,
+};
+use serde::Deserialize;
+
+use crate::ServerConfig;
+
+/// A group registered with the server and the clients that may join it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub id: Id,
+    /// Human-readable name used in log messages.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub members: Vec<MemberConfig>,
+}
+
+/// Grants the listed roles to clients with the given id, the given credential, or both.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemberConfig {
+    #[serde(default)]
+    pub id: Option<Id>,
+    #[serde(default)]
+    pub credential: Option<String>,
+    pub roles: Vec<ClientType>,
+}
+
+impl MemberConfig {
+    fn matches(&self, id: Id, credential: Option<&str>) -> bool {
+        let id_matches = self.id.is_none_or(|expected| expected == id);
+        let credential_matches = match (&self.credential, credential) {
+            (Some(expected), Some(credential)) => constant_time_eq(expected, credential),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        id_matches && credential_matches
+    }
+}
+
+impl GroupConfig {
+    pub fn permits(&self, id: Id, credential: Option<&str>, client_type: ClientType) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.roles.contains(&client_type) && member.matches(id, credential))
+    }
+}
+
+impl std::fmt::Display for GroupConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", self.id, name),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// Checks the identification against the group registry and returns the reason if the client
+/// may not join the requested group in the requested role.
+pub fn authorize(config: &ServerConfig, data: &IdentificationMessageData) -> Result<(), String> {
+    match config.groups.iter().find(|group| group.id == data.group_id) {
+        Some(group) if group.permits(data.id, data.credential.as_deref(), data.client_type) => {
+            Ok(())
+        }
+        Some(group) => Err(format!(
+            "Client {} may not join group {} as {:?}",
+            data.id, group, data.client_type
+        )),
+        None if config.allow_unknown_groups => Ok(()),
+        None => Err(format!("Unknown group {}", data.group_id)),
+    }
+}
+
+/// Compares credentials without returning early so the comparison does not leak their prefix.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
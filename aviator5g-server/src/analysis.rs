@@ -0,0 +1,175 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Analyses video streams with frame processors, which receive the decoded frames of a stream and
+//! report what they find to the stream's group as annotations and alerts.
+
+use std::sync::Arc;
+
+use aviator5g_common::{
+    AlertMessageData,
+    AlertSeverity,
+    AnnotationsMessageData,
+    BoundingBox,
+    ControlMessage,
+    DateTime,
+    Id,
+    Label,
+    VideoFrame,
+};
+use jpeg_decoder::PixelFormat;
+use tokio::sync::watch;
+
+use crate::media::LatestFrame;
+
+/// A video frame decoded to RGB pixels.
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub source_id: Id,
+    pub stream_id: String,
+    pub captured_at: DateTime,
+    pub width: u16,
+    pub height: u16,
+    /// Pixels row by row from the top left, three bytes per pixel.
+    pub rgb: Vec<u8>,
+}
+
+impl DecodedFrame {
+    pub fn decode(frame: &VideoFrame) -> Result<Self, String> {
+        let mut decoder = jpeg_decoder::Decoder::new(frame.jpeg.as_slice());
+        let pixels = decoder.decode().map_err(|e| e.to_string())?;
+        let info = decoder.info().expect("Decoded image has no info");
+
+        let rgb = match info.pixel_format {
+            PixelFormat::RGB24 => pixels,
+            PixelFormat::L8 => pixels.iter().flat_map(|&l| [l, l, l]).collect(),
+            format => return Err(format!("Unsupported pixel format {:?}", format)),
+        };
+
+        Ok(Self {
+            source_id: frame.source_id,
+            stream_id: frame.stream_id.clone(),
+            captured_at: frame.captured_at,
+            width: info.width,
+            height: info.height,
+            rgb,
+        })
+    }
+
+    /// Brightness of the pixel from 0 to 255.
+    pub fn luma(&self, x: usize, y: usize) -> u8 {
+        let offset = (y * usize::from(self.width) + x) * 3;
+        let [r, g, b] = [0, 1, 2].map(|channel| u32::from(self.rgb[offset + channel]));
+        ((77 * r + 150 * g + 29 * b) >> 8) as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub severity: AlertSeverity,
+    pub message: String,
+}
+
+/// What a frame processor has found in a frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    pub boxes: Vec<BoundingBox>,
+    pub labels: Vec<Label>,
+    pub alerts: Vec<Alert>,
+}
+
+/// Analyses the frames of one video stream.
+///
+/// Processors run on a blocking thread. Frames arriving while the processors are busy are
+/// skipped, so that the annotations keep up with the stream.
+pub trait FrameProcessor: Send {
+    /// Identifies the processor in the messages sent to the group.
+    fn name(&self) -> &str;
+
+    fn process(&mut self, frame: &DecodedFrame) -> Annotations;
+}
+
+/// Creates a processor for a stream that has just started, given the id of the vehicle and the
+/// stream id, or returns `None` to leave the stream alone.
+pub type FrameProcessorFactory =
+    Arc<dyn Fn(Id, &str) -> Option<Box<dyn FrameProcessor>> + Send + Sync>;
+
+/// Runs the processors on the frames of a stream until it ends and publishes what they find.
+pub async fn analyze(
+    mut frames: watch::Receiver<LatestFrame>,
+    mut processors: Vec<Box<dyn FrameProcessor>>,
+    publish: impl Fn(&ControlMessage),
+) {
+    // Whether the previous annotations of each processor have yet to be cleared.
+    let mut annotated = vec![false; processors.len()];
+
+    loop {
+        let frame = frames.borrow_and_update().clone();
+        if let Some(frame) = frame {
+            let decoding = frame.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let results = match DecodedFrame::decode(&decoding) {
+                    Ok(decoded) => processors
+                        .iter_mut()
+                        .map(|processor| processor.process(&decoded))
+                        .collect(),
+                    Err(e) => {
+                        log::warn!("Skipping analysis of a frame that cannot be decoded: {}", e);
+                        Vec::new()
+                    }
+                };
+                (processors, results)
+            })
+            .await;
+
+            let results = match result {
+                Ok((next, results)) => {
+                    processors = next;
+                    results
+                }
+                Err(e) => {
+                    log::error!("Stopped analysing stream: {}", e);
+                    return;
+                }
+            };
+
+            for ((processor, annotated), annotations) in
+                processors.iter().zip(&mut annotated).zip(results)
+            {
+                let processor = processor.name().to_owned();
+
+                for alert in annotations.alerts {
+                    publish(&ControlMessage::Alert(AlertMessageData {
+                        source_id: frame.source_id,
+                        stream_id: frame.stream_id.clone(),
+                        captured_at: frame.captured_at,
+                        processor: processor.clone(),
+                        severity: alert.severity,
+                        message: alert.message,
+                    }));
+                }
+
+                let is_empty = annotations.boxes.is_empty() && annotations.labels.is_empty();
+                if is_empty && !*annotated {
+                    continue;
+                }
+
+                *annotated = !is_empty;
+                publish(&ControlMessage::Annotations(AnnotationsMessageData {
+                    source_id: frame.source_id,
+                    stream_id: frame.stream_id.clone(),
+                    captured_at: frame.captured_at,
+                    processor,
+                    boxes: annotations.boxes,
+                    labels: annotations.labels,
+                }));
+            }
+        }
+
+        if frames.changed().await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,497 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    acl::GroupConfig,
+    listener::{
+        ListenAddress,
+        ListenerConfig,
+    },
+    rate_limit::RateLimit,
+};
+
+/// Prefix of environment variables that override settings, e.g. `AVIATOR5G_PING_INTERVAL`.
+pub const ENV_PREFIX: &str = "AVIATOR5G_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateIdPolicy {
+    /// Closes the new connection and keeps the existing one.
+    Reject,
+    /// Closes the existing connection and hands its identity to the new one.
+    Supersede,
+}
+
+impl FromStr for DuplicateIdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "supersede" => Ok(Self::Supersede),
+            _ => Err(format!(
+                "Invalid duplicate id policy '{}', expected 'reject' or 'supersede'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfigError {
+    #[error("Could not read configuration file {0}: {1}")]
+    IoError(PathBuf, std::io::Error),
+
+    #[error("Could not parse configuration file {0}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+
+    #[error("Invalid value '{1}' for environment variable {0}: {2}")]
+    EnvironmentError(String, String, String),
+
+    #[error("Invalid configuration: {0}")]
+    ValidationError(String),
+}
+
+impl std::fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        aviator5g_common::error_chain_fmt(self, f)
+    }
+}
+
+/// Comma-separated list of axes, e.g. `0,0,0,-1`.
+pub fn parse_axes(s: &str) -> Result<Vec<f64>, String> {
+    s.split(',')
+        .map(|axis| axis.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid axes '{}': {}", s, e))
+}
+
+/// Comma-separated list of listen addresses without TLS, e.g. `[::]:9000,unix:/run/aviator5g.sock`.
+pub fn parse_listeners(s: &str) -> Result<Vec<ListenerConfig>, String> {
+    s.split(',')
+        .map(|address| {
+            address
+                .trim()
+                .parse::<ListenAddress>()
+                .map(ListenerConfig::from)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Endpoints on which the server accepts connections. Port 0 picks an ephemeral port.
+    pub listeners: Vec<ListenerConfig>,
+    /// Endpoints on which the server serves the video streams of vehicles over HTTP.
+    pub http_listeners: Vec<ListenerConfig>,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub identification_timeout: Duration,
+    pub duplicate_id_policy: DuplicateIdPolicy,
+    pub connection_rate_limit: RateLimit,
+    pub group_rate_limit: RateLimit,
+    pub max_rate_limit_violations: u32,
+    pub max_message_size: usize,
+    /// Maximum size of a binary WebSocket message carrying a video frame, applied to new
+    /// connections.
+    pub max_video_frame_size: usize,
+    /// Directory in which the video streams of vehicles are recorded. Changes apply to streams
+    /// started afterwards.
+    pub recording_directory: Option<PathBuf>,
+    /// Share of a video frame, from 0 to 1, that must have changed since the previous frame for
+    /// motion to be reported to the group. Changes apply to streams started afterwards.
+    pub motion_detection_threshold: Option<f64>,
+    /// Time clients are given to disconnect on their own after a shutdown has been announced.
+    pub drain_timeout: Duration,
+    /// Axes sent to all vehicles as a control message when the server shuts down.
+    pub failsafe_axes: Option<Vec<f64>>,
+    /// Time for which the identity and queued messages of a disconnected client are kept so that
+    /// it can resume its session. Zero disables session resumption.
+    pub resume_grace_period: Duration,
+    /// Interval at which the round-trip times measured to each member are reported to the
+    /// group. Zero disables the reports.
+    pub link_report_interval: Duration,
+    /// Whether clients may join groups that are not listed in `groups`.
+    pub allow_unknown_groups: bool,
+    /// Registry of groups and the clients that may join them. Changes apply to subsequent
+    /// identifications only.
+    pub groups: Vec<GroupConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listeners: vec![ListenAddress::Tcp("localhost:9000".into()).into()],
+            http_listeners: vec![ListenAddress::Tcp("localhost:8554".into()).into()],
+            ping_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(15),
+            identification_timeout: Duration::from_secs(10),
+            duplicate_id_policy: DuplicateIdPolicy::Reject,
+            connection_rate_limit: RateLimit {
+                rate: 100.0,
+                burst: 50.0,
+            },
+            group_rate_limit: RateLimit {
+                rate: 500.0,
+                burst: 200.0,
+            },
+            max_rate_limit_violations: 100,
+            max_message_size: 65536,
+            max_video_frame_size: 1048576,
+            recording_directory: None,
+            motion_detection_threshold: None,
+            drain_timeout: Duration::from_secs(10),
+            failsafe_axes: None,
+            resume_grace_period: Duration::from_secs(30),
+            link_report_interval: Duration::from_secs(5),
+            allow_unknown_groups: false,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the defaults, the optional configuration file, environment
+    /// variables and the given overrides, in increasing order of precedence.
+    pub fn load(path: Option<&Path>, overrides: &ConfigOverrides) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+
+        if let Some(path) = path {
+            ConfigOverrides::from_file(path)?.apply(&mut config);
+        }
+
+        ConfigOverrides::from_env(|name| std::env::var(name).ok())?.apply(&mut config);
+        overrides.apply(&mut config);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::ValidationError(message.into()));
+
+        if self.listeners.is_empty() {
+            return invalid("listeners must contain at least one listener");
+        }
+
+        if self.ping_interval.is_zero() {
+            return invalid("ping_interval must be greater than 0");
+        }
+
+        if self.idle_timeout <= self.ping_interval {
+            return invalid("idle_timeout must be greater than ping_interval");
+        }
+
+        if self.identification_timeout.is_zero() {
+            return invalid("identification_timeout must be greater than 0");
+        }
+
+        for (name, limit) in [
+            ("rate_limit", &self.connection_rate_limit),
+            ("group_rate_limit", &self.group_rate_limit),
+        ] {
+            // Written so that NaN fails the comparisons as well.
+            let valid = limit.rate > 0.0
+                && limit.rate.is_finite()
+                && limit.burst >= 1.0
+                && limit.burst.is_finite();
+            if !valid {
+                return Err(ConfigError::ValidationError(format!(
+                    "{} must have a finite rate greater than 0 and a finite burst of at least 1",
+                    name
+                )));
+            }
+        }
+
+        if self.max_message_size == 0 {
+            return invalid("max_message_size must be greater than 0");
+        }
+
+        if self.max_video_frame_size == 0 {
+            return invalid("max_video_frame_size must be greater than 0");
+        }
+
+        if self
+            .recording_directory
+            .as_ref()
+            .is_some_and(|directory| directory.as_os_str().is_empty())
+        {
+            return invalid("recording_directory must not be empty");
+        }
+
+        if self
+            .motion_detection_threshold
+            .is_some_and(|threshold| !(threshold > 0.0 && threshold <= 1.0))
+        {
+            return invalid("motion_detection_threshold must be within (0, 1]");
+        }
+
+        if let Some(axes) = &self.failsafe_axes {
+            if axes.is_empty() || axes.iter().any(|axis| !(-1.0..=1.0).contains(axis)) {
+                return invalid("failsafe_axes must contain at least one axis within [-1, 1]");
+            }
+        }
+
+        for (index, group) in self.groups.iter().enumerate() {
+            if self.groups[..index]
+                .iter()
+                .any(|other| other.id == group.id)
+            {
+                return Err(ConfigError::ValidationError(format!(
+                    "Group {} is listed more than once",
+                    group
+                )));
+            }
+
+            for member in &group.members {
+                if member.id.is_none() && member.credential.is_none() {
+                    return Err(ConfigError::ValidationError(format!(
+                        "Members of group {} must have an id, a credential or both",
+                        group
+                    )));
+                }
+
+                if member.credential.as_deref() == Some("") || member.roles.is_empty() {
+                    return Err(ConfigError::ValidationError(format!(
+                        "Members of group {} must have a non-empty credential and at least one role",
+                        group
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes over all settings from `new` that can be changed while the server is running and
+    /// returns the names of the settings that differ but require a restart.
+    fn reload_from(&mut self, new: ServerConfig) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        if new.listeners != self.listeners {
+            ignored.push("listeners");
+        }
+        if new.http_listeners != self.http_listeners {
+            ignored.push("http_listeners");
+        }
+
+        *self = Self {
+            listeners: std::mem::take(&mut self.listeners),
+            http_listeners: std::mem::take(&mut self.http_listeners),
+            ..new
+        };
+
+        ignored
+    }
+}
+
+/// Optional settings from one configuration source that override previous sources.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigOverrides {
+    pub listeners: Option<Vec<ListenerConfig>>,
+    pub http_listeners: Option<Vec<ListenerConfig>>,
+    pub ping_interval: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub identification_timeout: Option<u64>,
+    pub duplicate_id_policy: Option<DuplicateIdPolicy>,
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub group_rate_limit: Option<f64>,
+    pub group_rate_limit_burst: Option<f64>,
+    pub max_rate_limit_violations: Option<u32>,
+    pub max_message_size: Option<usize>,
+    pub max_video_frame_size: Option<usize>,
+    pub recording_directory: Option<PathBuf>,
+    pub motion_detection_threshold: Option<f64>,
+    pub drain_timeout: Option<u64>,
+    pub failsafe_axes: Option<Vec<f64>>,
+    pub resume_grace_period: Option<u64>,
+    pub link_report_interval: Option<u64>,
+    pub allow_unknown_groups: Option<bool>,
+    pub groups: Option<Vec<GroupConfig>>,
+}
+
+impl ConfigOverrides {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::IoError(path.to_owned(), e))?;
+
+        toml::from_str(&contents).map_err(|e| ConfigError::ParseError(path.to_owned(), e))
+    }
+
+    /// Reads overrides from variables named after the settings with the [`ENV_PREFIX`].
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        fn parse<T: FromStr>(
+            var: &impl Fn(&str) -> Option<String>,
+            key: &str,
+        ) -> Result<Option<T>, ConfigError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            parse_with(var, key, |value| {
+                value.parse().map_err(|e: T::Err| e.to_string())
+            })
+        }
+
+        fn parse_with<T>(
+            var: &impl Fn(&str) -> Option<String>,
+            key: &str,
+            parse: impl Fn(&str) -> Result<T, String>,
+        ) -> Result<Option<T>, ConfigError> {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            match var(&name) {
+                Some(value) => parse(&value)
+                    .map(Some)
+                    .map_err(|e| ConfigError::EnvironmentError(name, value, e)),
+                None => Ok(None),
+            }
+        }
+
+        Ok(Self {
+            listeners: parse_with(&var, "listeners", parse_listeners)?,
+            http_listeners: parse_with(&var, "http_listeners", parse_listeners)?,
+            ping_interval: parse(&var, "ping_interval")?,
+            idle_timeout: parse(&var, "idle_timeout")?,
+            identification_timeout: parse(&var, "identification_timeout")?,
+            duplicate_id_policy: parse(&var, "duplicate_id_policy")?,
+            rate_limit: parse(&var, "rate_limit")?,
+            rate_limit_burst: parse(&var, "rate_limit_burst")?,
+            group_rate_limit: parse(&var, "group_rate_limit")?,
+            group_rate_limit_burst: parse(&var, "group_rate_limit_burst")?,
+            max_rate_limit_violations: parse(&var, "max_rate_limit_violations")?,
+            max_message_size: parse(&var, "max_message_size")?,
+            max_video_frame_size: parse(&var, "max_video_frame_size")?,
+            recording_directory: parse(&var, "recording_directory")?,
+            motion_detection_threshold: parse(&var, "motion_detection_threshold")?,
+            drain_timeout: parse(&var, "drain_timeout")?,
+            failsafe_axes: parse_with(&var, "failsafe_axes", parse_axes)?,
+            resume_grace_period: parse(&var, "resume_grace_period")?,
+            link_report_interval: parse(&var, "link_report_interval")?,
+            allow_unknown_groups: parse(&var, "allow_unknown_groups")?,
+            groups: None,
+        })
+    }
+
+    pub fn apply(&self, config: &mut ServerConfig) {
+        if let Some(listeners) = &self.listeners {
+            config.listeners = listeners.clone();
+        }
+        if let Some(listeners) = &self.http_listeners {
+            config.http_listeners = listeners.clone();
+        }
+        if let Some(secs) = self.ping_interval {
+            config.ping_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.idle_timeout {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.identification_timeout {
+            config.identification_timeout = Duration::from_secs(secs);
+        }
+        if let Some(policy) = self.duplicate_id_policy {
+            config.duplicate_id_policy = policy;
+        }
+        if let Some(rate) = self.rate_limit {
+            config.connection_rate_limit.rate = rate;
+        }
+        if let Some(burst) = self.rate_limit_burst {
+            config.connection_rate_limit.burst = burst;
+        }
+        if let Some(rate) = self.group_rate_limit {
+            config.group_rate_limit.rate = rate;
+        }
+        if let Some(burst) = self.group_rate_limit_burst {
+            config.group_rate_limit.burst = burst;
+        }
+        if let Some(max) = self.max_rate_limit_violations {
+            config.max_rate_limit_violations = max;
+        }
+        if let Some(size) = self.max_message_size {
+            config.max_message_size = size;
+        }
+        if let Some(size) = self.max_video_frame_size {
+            config.max_video_frame_size = size;
+        }
+        if let Some(directory) = &self.recording_directory {
+            config.recording_directory = Some(directory.clone());
+        }
+        if let Some(threshold) = self.motion_detection_threshold {
+            config.motion_detection_threshold = Some(threshold);
+        }
+        if let Some(secs) = self.drain_timeout {
+            config.drain_timeout = Duration::from_secs(secs);
+        }
+        if let Some(axes) = &self.failsafe_axes {
+            config.failsafe_axes = Some(axes.clone());
+        }
+        if let Some(secs) = self.resume_grace_period {
+            config.resume_grace_period = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.link_report_interval {
+            config.link_report_interval = Duration::from_secs(secs);
+        }
+        if let Some(allow) = self.allow_unknown_groups {
+            config.allow_unknown_groups = allow;
+        }
+        if let Some(groups) = &self.groups {
+            config.groups = groups.clone();
+        }
+    }
+}
+
+/// Gives access to the current configuration of a running [`crate::Server`] and allows
+/// replacing it without dropping connections.
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl ConfigHandle {
+    pub(crate) fn new(config: ServerConfig) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Validates and applies a new configuration. Settings that require a restart keep their
+    /// current value and a warning is logged.
+    pub fn reload(&self, new: ServerConfig) -> Result<(), ConfigError> {
+        new.validate()?;
+
+        let mut current = self.current.write().unwrap();
+        let mut config = ServerConfig::clone(&current);
+        let ignored = config.reload_from(new);
+        *current = Arc::new(config);
+
+        if !ignored.is_empty() {
+            log::warn!(
+                "Changes to {} require a restart and have been ignored",
+                ignored.join(", ")
+            );
+        }
+
+        log::info!("Configuration has been reloaded");
+        Ok(())
+    }
+}
@@ -0,0 +1,689 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    sync::Arc,
+    time::Instant,
+};
+
+use aviator5g_common::{
+    ClientType,
+    ControlMessage,
+    IdentityConflictOutcome,
+    StreamAdvertisementMessageData,
+    TelemetryMessageData,
+    VideoFrame,
+};
+use futures_channel::mpsc::unbounded;
+use futures_util::{
+    future,
+    pin_mut,
+    stream::TryStreamExt,
+    SinkExt,
+    StreamExt,
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+use tungstenite::protocol::{
+    frame::coding::CloseCode,
+    WebSocketConfig,
+};
+
+use crate::{
+    acl,
+    rate_limit::RateLimitVerdict,
+    state::{
+        ConnectionCommand,
+        Disconnection,
+        IdClaim,
+        ServerState,
+    },
+    ConfigHandle,
+    ConnectionId,
+    ControlMessageKind,
+    DuplicateIdPolicy,
+    ServerConfig,
+    ServerError,
+};
+
+enum ControlMessageAction {
+    None,
+    /// Forwards to all members of the sender's group, or only to the member with the given id.
+    /// Messages with a kind to retain are delivered to members joining later as well.
+    ForwardToGroup {
+        target_id: Option<aviator5g_common::Id>,
+        retain: Option<ControlMessageKind>,
+    },
+    ForwardSingle(aviator5g_common::Id),
+    /// Relays a video frame to the pilots and observers of the sender's group.
+    RelayFrame(VideoFrame),
+}
+
+fn handle_identification(
+    server_state: &ServerState,
+    config: &ServerConfig,
+    connection_id: ConnectionId,
+    data: aviator5g_common::IdentificationMessageData,
+) -> Result<ControlMessageAction, ServerError> {
+    let is_identified = server_state
+        .with_connection(connection_id, |c| c.is_identified())
+        .expect("Unknown connection");
+
+    if is_identified {
+        return Err(ServerError::AlreadyIdentifiedError);
+    }
+
+    if let Err(reason) = acl::authorize(config, &data) {
+        log::warn!("Rejected identification from {}: {}", connection_id, reason);
+        return Err(ServerError::UnauthorizedError(reason));
+    }
+
+    server_state.release_detached_holder(data.id);
+
+    let supersede = config.duplicate_id_policy == DuplicateIdPolicy::Supersede;
+    let (existing_connection_id, outcome) =
+        match server_state.claim_id(connection_id, data.id, supersede) {
+            IdClaim::Claimed => {
+                server_state.identify(connection_id, data.group_id, data.id, data.client_type);
+                open_session(server_state, config, connection_id);
+                return Ok(ControlMessageAction::None);
+            }
+            IdClaim::Rejected(existing_connection_id) => {
+                (existing_connection_id, IdentityConflictOutcome::Rejected)
+            }
+            IdClaim::Superseded(existing_connection_id) => {
+                (existing_connection_id, IdentityConflictOutcome::Superseded)
+            }
+        };
+
+    log::warn!(
+        "Duplicate identity {} from {}, already in use by {}: {:?}",
+        data.id,
+        connection_id,
+        existing_connection_id,
+        outcome
+    );
+
+    let notification =
+        ControlMessage::IdentityConflict(aviator5g_common::IdentityConflictMessageData {
+            id: data.id,
+            outcome,
+        });
+
+    server_state.with_connection(existing_connection_id, |existing| {
+        existing.send_control_message(&notification);
+        if supersede {
+            existing.close(CloseCode::Policy, "Superseded by a new connection");
+        }
+    });
+
+    server_state.with_connection(connection_id, |connection| {
+        connection.send_control_message(&notification);
+        if !supersede {
+            connection.close(CloseCode::Policy, "Identity is already in use");
+        }
+    });
+
+    if supersede {
+        server_state.identify(connection_id, data.group_id, data.id, data.client_type);
+        open_session(server_state, config, connection_id);
+    }
+
+    Ok(ControlMessageAction::None)
+}
+
+/// Hands out a resume token unless session resumption has been disabled.
+fn open_session(server_state: &ServerState, config: &ServerConfig, connection_id: ConnectionId) {
+    if config.resume_grace_period.is_zero() {
+        return;
+    }
+
+    let resume_token = server_state.open_session(connection_id);
+    server_state.with_connection(connection_id, |connection| {
+        connection.send_control_message(&ControlMessage::Session(
+            aviator5g_common::SessionMessageData {
+                resume_token,
+                resumed: false,
+            },
+        ));
+    });
+}
+
+fn handle_resume(
+    server_state: &ServerState,
+    connection_id: ConnectionId,
+    data: aviator5g_common::ResumeMessageData,
+) -> Result<ControlMessageAction, ServerError> {
+    let is_identified = server_state
+        .with_connection(connection_id, |c| c.is_identified())
+        .expect("Unknown connection");
+
+    if is_identified {
+        return Err(ServerError::AlreadyIdentifiedError);
+    }
+
+    if !server_state.resume(connection_id, &data.resume_token) {
+        return Err(ServerError::SessionExpiredError);
+    }
+
+    Ok(ControlMessageAction::None)
+}
+
+fn handle_subscription(
+    server_state: &ServerState,
+    connection_id: ConnectionId,
+    client_type: Option<ClientType>,
+    data: aviator5g_common::StreamSubscriptionMessageData,
+    subscribe: bool,
+) -> Result<ControlMessageAction, ServerError> {
+    match client_type {
+        None => return Err(ServerError::NotIdentifiedError),
+        Some(ClientType::Vehicle) => {
+            return Err(ServerError::ForbiddenError(
+                "Vehicles do not receive video streams".into(),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    server_state.subscribe(connection_id, (data.source_id, data.stream_id), subscribe);
+    Ok(ControlMessageAction::None)
+}
+
+fn handle_control_message(
+    server_state: &ServerState,
+    config: &ServerConfig,
+    connection_id: ConnectionId,
+    control_message: ControlMessage,
+) -> Result<ControlMessageAction, ServerError> {
+    log::debug!(
+        "Handling control message: {} {:?}",
+        connection_id,
+        control_message
+    );
+
+    let kind = std::mem::discriminant(&control_message);
+    let retain = control_message.is_state().then_some(kind);
+
+    match server_state.check_rate_limit(connection_id, kind, config, Instant::now()) {
+        RateLimitVerdict::Allow => {}
+        RateLimitVerdict::Drop => return Ok(ControlMessageAction::None),
+        RateLimitVerdict::Reject => return Err(ServerError::RateLimitedError),
+        RateLimitVerdict::Disconnect => return Err(ServerError::RateLimitExceededError),
+    }
+
+    let (is_identified, client_type) = server_state
+        .with_connection(connection_id, |c| (c.is_identified(), c.client_type))
+        .expect("Unknown connection");
+
+    match control_message {
+        ControlMessage::Identification(data) => {
+            handle_identification(server_state, config, connection_id, data)
+        }
+
+        ControlMessage::Resume(data) => handle_resume(server_state, connection_id, data),
+
+        ControlMessage::ClockSyncRequest(data) => {
+            let server_receive = chrono::Utc::now();
+            server_state.with_connection(connection_id, |connection| {
+                connection.send_control_message(&ControlMessage::ClockSyncResponse(
+                    aviator5g_common::ClockSyncResponseMessageData {
+                        client_transmit: data.client_transmit,
+                        server_receive,
+                        server_transmit: chrono::Utc::now(),
+                    },
+                ));
+            });
+            Ok(ControlMessageAction::None)
+        }
+
+        // Server-originated messages are never forwarded when sent by a client.
+        ControlMessage::IdentityConflict(_)
+        | ControlMessage::Error(_)
+        | ControlMessage::GoingAway(_)
+        | ControlMessage::Session(_)
+        | ControlMessage::LinkQuality(_)
+        | ControlMessage::ClockSyncResponse(_)
+        | ControlMessage::Annotations(_)
+        | ControlMessage::Alert(_) => Ok(ControlMessageAction::None),
+
+        ControlMessage::Control(data) => {
+            if !is_identified {
+                return Err(ServerError::NotIdentifiedError);
+            }
+            if client_type == Some(ClientType::Observer) {
+                return Err(ServerError::ForbiddenError(
+                    "Observers may not send control messages".into(),
+                ));
+            }
+            Ok(ControlMessageAction::ForwardToGroup {
+                target_id: data.target_id,
+                retain,
+            })
+        }
+
+        ControlMessage::StreamAdvertisement(StreamAdvertisementMessageData {
+            source_id, ..
+        })
+        | ControlMessage::Telemetry(TelemetryMessageData { source_id, .. }) => {
+            let id = server_state
+                .with_connection(connection_id, |c| c.id)
+                .expect("Unknown connection");
+
+            if !is_identified {
+                return Err(ServerError::NotIdentifiedError);
+            }
+            if client_type != Some(ClientType::Vehicle) || id != Some(source_id) {
+                return Err(ServerError::ForbiddenError(
+                    "Only vehicles may advertise their own streams and report their own telemetry"
+                        .into(),
+                ));
+            }
+            // Frames cannot carry longer stream ids, so they are not accepted here either.
+            if let ControlMessage::StreamAdvertisement(data) = control_message {
+                if let Some(stream_id) = data
+                    .stream_ids
+                    .iter()
+                    .find(|stream_id| stream_id.len() > aviator5g_common::MAX_STREAM_ID_LENGTH)
+                {
+                    return Err(ServerError::MalformedControlMessageError(format!(
+                        "Stream id of {} bytes exceeds the maximum of {} bytes",
+                        stream_id.len(),
+                        aviator5g_common::MAX_STREAM_ID_LENGTH
+                    )));
+                }
+            }
+            Ok(ControlMessageAction::ForwardToGroup {
+                target_id: None,
+                retain,
+            })
+        }
+
+        ControlMessage::Subscribe(data) => {
+            handle_subscription(server_state, connection_id, client_type, data, true)
+        }
+
+        ControlMessage::Unsubscribe(data) => {
+            handle_subscription(server_state, connection_id, client_type, data, false)
+        }
+
+        ControlMessage::LatencyRequest(data) => {
+            if !is_identified {
+                return Err(ServerError::NotIdentifiedError);
+            }
+            Ok(ControlMessageAction::ForwardToGroup {
+                target_id: data.target_id,
+                retain,
+            })
+        }
+
+        ControlMessage::LatencyResponse(e) => {
+            if !is_identified {
+                return Err(ServerError::NotIdentifiedError);
+            }
+
+            Ok(ControlMessageAction::ForwardSingle(e.initiator_id))
+        }
+    }
+}
+
+fn dispatch(
+    server_state: &Arc<ServerState>,
+    config: &ServerConfig,
+    connection_id: ConnectionId,
+    action: ControlMessageAction,
+    message: &tungstenite::Message,
+) -> Result<(), ServerError> {
+    match action {
+        ControlMessageAction::None => {}
+        ControlMessageAction::ForwardToGroup { target_id, retain } => {
+            let sender = server_state
+                .with_connection(connection_id, |c| c.group_id.zip(c.client_type))
+                .expect("Unknown connection");
+            let (group_id, client_type) = match sender {
+                Some(sender) => sender,
+                None => return Ok(()),
+            };
+
+            match target_id {
+                // Forward message to all other clients of different type within the same group.
+                None => {
+                    server_state.forward_to_group(group_id, connection_id, client_type, message)
+                }
+                Some(target_id) => {
+                    if !server_state.forward_to_member(group_id, client_type, target_id, message) {
+                        return Err(ServerError::UnknownTargetError(target_id));
+                    }
+                }
+            }
+
+            if let Some(kind) = retain {
+                server_state.retain_state(group_id, connection_id, kind, target_id, message);
+            }
+        }
+        ControlMessageAction::ForwardSingle(recipient_id) => {
+            let group_id = server_state
+                .with_connection(connection_id, |c| c.group_id)
+                .expect("Unknown connection");
+
+            if let Some(group_id) = group_id {
+                server_state.send_to_member(group_id, recipient_id, message);
+            }
+        }
+        ControlMessageAction::RelayFrame(frame) => {
+            let group_id = server_state
+                .with_connection(connection_id, |c| c.group_id)
+                .expect("Unknown connection");
+
+            if let Some(group_id) = group_id {
+                server_state.relay_frame(group_id, frame, config);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_video_frame(
+    server_state: &ServerState,
+    connection_id: ConnectionId,
+    bytes: &[u8],
+) -> Result<ControlMessageAction, ServerError> {
+    let sender = server_state
+        .with_connection(connection_id, |c| c.id.zip(c.client_type))
+        .expect("Unknown connection");
+
+    let (id, client_type) = sender.ok_or(ServerError::NotIdentifiedError)?;
+    if client_type != ClientType::Vehicle {
+        return Err(ServerError::ForbiddenError(
+            "Only vehicles may send video frames".into(),
+        ));
+    }
+
+    let mut frame = VideoFrame::decode(bytes).map_err(ServerError::MalformedVideoFrameError)?;
+    frame.source_id = id;
+
+    Ok(ControlMessageAction::RelayFrame(frame))
+}
+
+fn handle_message(
+    server_state: &ServerState,
+    config: &ServerConfig,
+    connection_id: ConnectionId,
+    message: &tungstenite::Message,
+) -> Result<ControlMessageAction, ServerError> {
+    server_state
+        .with_connection_mut(connection_id, |c| c.touch())
+        .expect("Unknown connection");
+
+    match message {
+        tungstenite::Message::Text(text) => {
+            log::debug!("Received WS Text: {}", connection_id);
+
+            // The WebSocket limit admits video frames, which may be larger than text messages.
+            if text.len() > config.max_message_size {
+                return Err(ServerError::MessageTooLargeError(config.max_message_size));
+            }
+
+            let control_message = aviator5g_common::parse_control_message(text)
+                .map_err(ServerError::MalformedControlMessageError)?;
+
+            handle_control_message(server_state, config, connection_id, control_message)
+        }
+        tungstenite::Message::Binary(bytes) => {
+            log::debug!("Received Binary Message: {}", connection_id);
+            handle_video_frame(server_state, connection_id, bytes)
+        }
+        tungstenite::Message::Ping(_) => {
+            log::debug!("Received Ping Message: {}", connection_id);
+            Ok(ControlMessageAction::None)
+        }
+        tungstenite::Message::Pong(payload) => {
+            let rtt = server_state
+                .with_connection_mut(connection_id, |c| c.link.complete(payload, Instant::now()))
+                .expect("Unknown connection");
+
+            log::debug!("Received Pong Message: {} {:?}", connection_id, rtt);
+            Ok(ControlMessageAction::None)
+        }
+        tungstenite::Message::Close(_) => {
+            log::debug!("Received Close Message: {}", connection_id);
+            Ok(ControlMessageAction::None)
+        }
+    }
+}
+
+fn report_error(server_state: &ServerState, connection_id: ConnectionId, e: &ServerError) {
+    server_state.with_connection(connection_id, |connection| {
+        if let Some(code) = e.error_code() {
+            connection.send_control_message(&ControlMessage::Error(
+                aviator5g_common::ErrorMessageData {
+                    code,
+                    message: e.to_string(),
+                    fatal: e.is_fatal(),
+                },
+            ));
+        }
+
+        if e.is_fatal() {
+            connection.close(e.close_code(), &e.to_string());
+        }
+    });
+}
+
+async fn handle_connection<S>(
+    server_state: Arc<ServerState>,
+    config: ConfigHandle,
+    stream: S,
+    connection_id: ConnectionId,
+) -> anyhow::Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (max_message_size, identification_timeout) = {
+        let config = config.current();
+        (
+            config.max_message_size.max(config.max_video_frame_size),
+            config.identification_timeout,
+        )
+    };
+    // Clients that never complete the handshake are not yet subject to the identification
+    // timeout enforced by the heartbeat.
+    let ws_stream = tokio::time::timeout(
+        identification_timeout,
+        tokio_tungstenite::accept_async_with_config(
+            stream,
+            Some(WebSocketConfig {
+                max_message_size: Some(max_message_size),
+                max_frame_size: Some(max_message_size),
+                ..Default::default()
+            }),
+        ),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("WebSocket handshake has timed out"))??;
+
+    log::info!("WebSocket connection established: {}", connection_id);
+
+    let (tx, mut rx) = unbounded();
+    let (command_tx, mut command_rx) = unbounded();
+    server_state.accept_connection(connection_id, tx, command_tx);
+    let frames = server_state
+        .with_connection(connection_id, |c| c.frames.clone())
+        .expect("Unknown connection");
+
+    let (outgoing, incoming) = ws_stream.split();
+
+    let handle_incoming = incoming.try_for_each(|message| {
+        let config = config.current();
+        let result = handle_message(&server_state, &config, connection_id, &message)
+            .and_then(|action| dispatch(&server_state, &config, connection_id, action, &message));
+
+        match result {
+            Ok(()) => {}
+            Err(e) => {
+                log::error!(
+                    "An error occurred while handling the control message: {} {:?} ::: {}",
+                    connection_id,
+                    e,
+                    message,
+                );
+
+                report_error(&server_state, connection_id, &e);
+            }
+        }
+
+        future::ok(())
+    });
+
+    let broadcast_incoming = async {
+        if let Err(tungstenite::Error::Capacity(e)) = handle_incoming.await {
+            log::error!("Message exceeds capacity: {} {}", connection_id, e);
+            report_error(
+                &server_state,
+                connection_id,
+                &ServerError::MessageTooLargeError(max_message_size),
+            );
+
+            // Keep the connection alive until the error and close frame have been flushed.
+            future::pending::<()>().await;
+        }
+    };
+
+    let session_rx = &mut rx;
+    let receive_from_others = async move {
+        let mut outgoing = outgoing;
+        loop {
+            tokio::select! {
+                biased;
+                command = command_rx.next() => match command {
+                    Some(ConnectionCommand::Adopt(adopted_rx)) => {
+                        // Flush what has been queued before the session was resumed, such as
+                        // the new resume token, ahead of the session's own queue.
+                        while let Ok(Some(message)) = session_rx.try_next() {
+                            if outgoing.feed(message).await.is_err() {
+                                break;
+                            }
+                        }
+                        let _ = outgoing.flush().await;
+                        *session_rx = adopted_rx;
+                    }
+                    Some(ConnectionCommand::Terminate) | None => break,
+                },
+                message = session_rx.next() => match message {
+                    Some(message) => {
+                        if outgoing.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => {
+                        // Wait for the peer to acknowledge the close frame, which ends the
+                        // incoming stream, rather than resetting a socket it may still write to.
+                        let _ = outgoing.close().await;
+                        tokio::time::sleep(crate::CLOSE_GRACE_PERIOD).await;
+                        break;
+                    }
+                },
+                // Frames are only sent while no other messages are waiting.
+                () = frames.ready() => {
+                    for frame in frames.take() {
+                        let frame = Arc::try_unwrap(frame).unwrap_or_else(|frame| (*frame).clone());
+                        if outgoing.send(tungstenite::Message::Binary(frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    let heartbeat = async {
+        loop {
+            let config = config.current();
+            tokio::time::sleep(config.ping_interval).await;
+
+            let alive = server_state
+                .with_connection_mut(connection_id, |connection| {
+                    let now = Instant::now();
+                    if let Some(reason) = connection.eviction_reason(&config, now) {
+                        log::info!("Evicting connection: {} {:?}", connection_id, reason);
+                        connection.close(reason.close_code(), reason.description());
+                        return false;
+                    }
+
+                    // Pings are numbered so that their pongs yield the round-trip time.
+                    let payload = connection.link.probe(now);
+                    connection
+                        .tx
+                        .unbounded_send(tungstenite::Message::Ping(payload))
+                        .is_ok()
+                })
+                .expect("Unknown connection");
+
+            if !alive {
+                // Leave the connection to the closing handshake.
+                future::pending::<()>().await;
+            }
+        }
+    };
+
+    {
+        pin_mut!(broadcast_incoming, receive_from_others, heartbeat);
+        future::select(
+            future::select(broadcast_incoming, receive_from_others),
+            heartbeat,
+        )
+        .await;
+    }
+
+    log::info!("Connection disconnected: {}", &connection_id);
+    match server_state.disconnect(connection_id, rx) {
+        Disconnection::Released {
+            abandoned_resumption,
+        } => {
+            if let Some(next) = abandoned_resumption {
+                report_error(&server_state, next, &ServerError::SessionExpiredError);
+            }
+        }
+        Disconnection::Detached => {
+            let grace_period = config.current().resume_grace_period;
+            log::info!(
+                "Keeping session of {} for {:?}",
+                connection_id,
+                grace_period
+            );
+
+            tokio::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                if server_state.expire_detached(connection_id) {
+                    log::info!("Session of {} has expired", connection_id);
+                }
+            });
+        }
+        Disconnection::HandedOver => {}
+    }
+
+    Ok(())
+}
+
+pub async fn handle_connection_wrapper<S>(
+    server_state: Arc<ServerState>,
+    config: ConfigHandle,
+    stream: S,
+    connection_id: ConnectionId,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let result = handle_connection(server_state.clone(), config, stream, connection_id).await;
+    if let Err(e) = result {
+        log::error!(
+            "Connection {} has been terminated due to an error: {:?}",
+            connection_id,
+            e
+        );
+    }
+}
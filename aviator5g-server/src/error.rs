@@ -0,0 +1,113 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use aviator5g_common::ErrorCode;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+#[derive(thiserror::Error)]
+pub enum ServerError {
+    #[error("An error occurred while handling the connection: {0}")]
+    ConnectionError(Box<tungstenite::Error>),
+
+    #[error("The control message is malformed: {0}")]
+    MalformedControlMessageError(String),
+
+    #[error("The video frame is malformed: {0}")]
+    MalformedVideoFrameError(String),
+
+    #[error("Client is not identified")]
+    NotIdentifiedError,
+
+    #[error("Client has already been identified")]
+    AlreadyIdentifiedError,
+
+    #[error("Client is not authorized: {0}")]
+    UnauthorizedError(String),
+
+    #[error("Client is not allowed to send this message: {0}")]
+    ForbiddenError(String),
+
+    #[error("Target {0} is not a member of the group")]
+    UnknownTargetError(aviator5g_common::Id),
+
+    #[error("Session has expired or does not exist")]
+    SessionExpiredError,
+
+    #[error("Client has exceeded its rate limit")]
+    RateLimitedError,
+
+    #[error("Client has repeatedly exceeded its rate limit")]
+    RateLimitExceededError,
+
+    #[error("Message exceeds the maximum size of {0} bytes")]
+    MessageTooLargeError(usize),
+
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl From<tungstenite::Error> for ServerError {
+    fn from(e: tungstenite::Error) -> Self {
+        Self::ConnectionError(Box::new(e))
+    }
+}
+
+impl ServerError {
+    /// The machine-readable code reported to the client, if the error should be reported at all.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::ConnectionError(_) => None,
+            Self::MalformedControlMessageError(_) | Self::MalformedVideoFrameError(_) => {
+                Some(ErrorCode::Malformed)
+            }
+            Self::NotIdentifiedError => Some(ErrorCode::NotIdentified),
+            Self::AlreadyIdentifiedError => Some(ErrorCode::AlreadyIdentified),
+            Self::UnauthorizedError(_) => Some(ErrorCode::Unauthorized),
+            Self::ForbiddenError(_) => Some(ErrorCode::Forbidden),
+            Self::UnknownTargetError(_) => Some(ErrorCode::UnknownTarget),
+            Self::SessionExpiredError => Some(ErrorCode::SessionExpired),
+            Self::RateLimitedError | Self::RateLimitExceededError => Some(ErrorCode::RateLimited),
+            Self::MessageTooLargeError(_) => Some(ErrorCode::MessageTooLarge),
+            Self::UnexpectedError(_) => Some(ErrorCode::Internal),
+        }
+    }
+
+    /// Whether the connection must be closed after this error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::ConnectionError(_)
+                | Self::UnauthorizedError(_)
+                | Self::RateLimitExceededError
+                | Self::MessageTooLargeError(_)
+                | Self::UnexpectedError(_)
+        )
+    }
+
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Self::ConnectionError(_) => CloseCode::Protocol,
+            Self::MalformedControlMessageError(_) | Self::MalformedVideoFrameError(_) => {
+                CloseCode::Invalid
+            }
+            Self::NotIdentifiedError
+            | Self::AlreadyIdentifiedError
+            | Self::UnknownTargetError(_)
+            | Self::SessionExpiredError => CloseCode::Protocol,
+            Self::UnauthorizedError(_)
+            | Self::ForbiddenError(_)
+            | Self::RateLimitedError
+            | Self::RateLimitExceededError => CloseCode::Policy,
+            Self::MessageTooLargeError(_) => CloseCode::Size,
+            Self::UnexpectedError(_) => CloseCode::Error,
+        }
+    }
+}
+
+impl std::fmt::Debug for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        aviator5g_common::error_chain_fmt(self, f)
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! HTTP endpoints through which the video streams of vehicles can be watched without a
+//! WebSocket connection, e.g. directly in an `<img>` element, at
+//! `/streams/{vehicle_id}/{stream_id}`, and their latest frames downloaded as JPEG images at
+//! `/snapshots/{vehicle_id}/{stream_id}`.
+//!
+//! Clients identify themselves in the query as `?group_id=<id>&id=<id>&credential=<credential>`
+//! and may watch the streams of the vehicles in the group if the group's registry admits them as
+//! pilot or observer, just like it would on the WebSocket. The id may be left out by members
+//! that are admitted by their credential alone.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+};
+
+use aviator5g_common::{
+    ClientType,
+    Id,
+    IdentificationMessageData,
+    VideoFrame,
+};
+use chrono::SecondsFormat;
+use futures_util::{
+    pin_mut,
+    stream,
+};
+use hyper::{
+    header,
+    server::conn::Http,
+    service::service_fn,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+    },
+    sync::watch,
+};
+
+use crate::{
+    acl,
+    media::LatestFrame,
+    state::ServerState,
+    ConfigHandle,
+    ConnectionId,
+    ServerConfig,
+};
+
+const MULTIPART_BOUNDARY: &str = "frame";
+
+/// Header of snapshots holding the time at which the frame has been captured.
+const CAPTURED_AT: &str = "x-captured-at";
+
+pub async fn handle_http_connection<S>(
+    server_state: Arc<ServerState>,
+    config: ConfigHandle,
+    stream: S,
+    connection_id: ConnectionId,
+    mut shutdown_rx: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = {
+        let shutdown_rx = shutdown_rx.clone();
+        service_fn(move |request| {
+            let response = route(
+                &server_state,
+                &config.current(),
+                &request,
+                shutdown_rx.clone(),
+            );
+            // The query is left out as it may hold a credential.
+            log::debug!(
+                "HTTP {} {} from {}: {}",
+                request.method(),
+                request.uri().path(),
+                connection_id,
+                response.status()
+            );
+
+            async move { Ok::<_, Infallible>(response) }
+        })
+    };
+
+    let connection = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service);
+    pin_mut!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown_rx.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(e) = result {
+        log::debug!("HTTP connection {} failed: {}", connection_id, e);
+    }
+}
+
+fn route(
+    server_state: &ServerState,
+    config: &ServerConfig,
+    request: &Request<Body>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let segments: Vec<String> = request
+        .uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| {
+            percent_encoding::percent_decode_str(segment)
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let (resource, vehicle_id, stream_id) = match segments.as_slice() {
+        [resource @ ("streams" | "snapshots"), vehicle_id, stream_id] => {
+            (*resource, *vehicle_id, *stream_id)
+        }
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+
+    let group_id = match authorize(config, request.uri().query().unwrap_or_default()) {
+        Ok(group_id) => group_id,
+        Err(code) => return status(code),
+    };
+
+    // Streams of vehicles in other groups are indistinguishable from streams that do not exist.
+    let frames = vehicle_id.parse().ok().and_then(|vehicle_id| {
+        server_state.watch_stream(group_id, &(vehicle_id, stream_id.to_string()))
+    });
+    let frames = match frames {
+        Some(frames) => frames,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+
+    match resource {
+        "streams" => multipart_response(frames, shutdown_rx),
+        _ => snapshot_response(&frames),
+    }
+}
+
+/// Checks the identification given in the query and returns the group whose streams the client
+/// may watch.
+fn authorize(config: &ServerConfig, query: &str) -> Result<Id, StatusCode> {
+    let mut group_id = None;
+    let mut id = None;
+    let mut credential = None;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "group_id" => group_id = Some(value.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+            "id" => id = Some(value.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+            "credential" => credential = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let group_id = group_id.ok_or(StatusCode::UNAUTHORIZED)?;
+    let permitted = [ClientType::Pilot, ClientType::Observer]
+        .into_iter()
+        .any(|client_type| {
+            let data = IdentificationMessageData {
+                id: id.unwrap_or_default(),
+                group_id,
+                client_type,
+                credential: credential.clone(),
+            };
+            acl::authorize(config, &data).is_ok()
+        });
+
+    if permitted {
+        Ok(group_id)
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(status.canonical_reason().unwrap_or_default()))
+        .unwrap()
+}
+
+/// Streams the frames as `multipart/x-mixed-replace`. Only the latest frame is sent whenever the
+/// client is ready to receive the next one, so slow clients skip frames instead of lagging.
+fn multipart_response(
+    frames: watch::Receiver<LatestFrame>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Response<Body> {
+    let parts = stream::unfold(
+        (frames, shutdown_rx, true),
+        |(mut frames, mut shutdown_rx, first)| async move {
+            if *shutdown_rx.borrow() {
+                return None;
+            }
+
+            if !first {
+                tokio::select! {
+                    changed = frames.changed() => changed.ok()?,
+                    _ = shutdown_rx.changed() => return None,
+                }
+            }
+
+            let frame = frames.borrow().clone()?;
+            Some((
+                Ok::<_, Infallible>(multipart_part(&frame)),
+                (frames, shutdown_rx, false),
+            ))
+        },
+    );
+
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={}", MULTIPART_BOUNDARY),
+        )
+        .header(header::CACHE_CONTROL, "no-cache, no-store")
+        .body(Body::wrap_stream(parts))
+        .unwrap()
+}
+
+/// Returns the latest frame as a single JPEG image.
+fn snapshot_response(frames: &watch::Receiver<LatestFrame>) -> Response<Body> {
+    let frame = match frames.borrow().clone() {
+        Some(frame) => frame,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "image/jpeg")
+        .header(header::CACHE_CONTROL, "no-cache, no-store")
+        .header(
+            CAPTURED_AT,
+            frame
+                .captured_at
+                .to_rfc3339_opts(SecondsFormat::Micros, true),
+        )
+        .body(Body::from(frame.jpeg.clone()))
+        .unwrap()
+}
+
+fn multipart_part(frame: &VideoFrame) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        MULTIPART_BOUNDARY,
+        frame.jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(&frame.jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
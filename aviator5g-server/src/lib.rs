@@ -0,0 +1,292 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+mod acl;
+mod analysis;
+mod config;
+mod connection;
+mod error;
+mod http;
+mod link;
+mod listener;
+mod media;
+mod motion;
+mod rate_limit;
+mod recording;
+mod state;
+
+use std::{
+    mem::Discriminant,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use aviator5g_common::{
+    ClientType,
+    ControlMessage,
+    Id,
+};
+use futures_channel::mpsc::{
+    UnboundedReceiver,
+    UnboundedSender,
+};
+use futures_util::{
+    future::{
+        self,
+        Either,
+    },
+    pin_mut,
+};
+use tokio::{
+    sync::watch,
+    time::Instant,
+};
+use tungstenite::protocol::frame::coding::CloseCode;
+
+pub use crate::{
+    acl::{
+        GroupConfig,
+        MemberConfig,
+    },
+    analysis::{
+        Alert,
+        Annotations,
+        DecodedFrame,
+        FrameProcessor,
+    },
+    config::{
+        parse_axes,
+        parse_listeners,
+        ConfigError,
+        ConfigHandle,
+        ConfigOverrides,
+        DuplicateIdPolicy,
+        ServerConfig,
+        ENV_PREFIX,
+    },
+    error::ServerError,
+    listener::{
+        ListenAddress,
+        ListenerConfig,
+        LocalAddress,
+        TlsConfig,
+    },
+    motion::MotionDetector,
+    rate_limit::RateLimit,
+};
+use crate::{
+    listener::{
+        Listener,
+        Protocol,
+    },
+    state::ServerState,
+};
+
+type Tx = UnboundedSender<tungstenite::Message>;
+type Rx = UnboundedReceiver<tungstenite::Message>;
+type ControlMessageKind = Discriminant<ControlMessage>;
+
+/// Time given to connections to flush their close frames once they have been closed forcibly.
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Identifies a connection independently of the listener it has been accepted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Stops a running [`Server`] from accepting further connections and drains existing ones.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(true);
+    }
+}
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    http_listeners: Vec<Listener>,
+    config: ConfigHandle,
+    server_state: Arc<ServerState>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Server {
+    pub async fn bind(config: ServerConfig) -> anyhow::Result<Self> {
+        let mut listeners = Vec::with_capacity(config.listeners.len());
+        for listener_config in &config.listeners {
+            listeners.push(Listener::bind(listener_config, Protocol::WebSocket).await?);
+        }
+
+        let mut http_listeners = Vec::with_capacity(config.http_listeners.len());
+        for listener_config in &config.http_listeners {
+            http_listeners.push(Listener::bind(listener_config, Protocol::Http).await?);
+        }
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        Ok(Self {
+            listeners,
+            http_listeners,
+            config: ConfigHandle::new(config),
+            server_state: Arc::new(ServerState::new()),
+            shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+        })
+    }
+
+    /// Addresses the listeners have been bound to, in the order in which they are configured.
+    pub fn local_addresses(&self) -> Vec<LocalAddress> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_address().clone())
+            .collect()
+    }
+
+    /// Addresses the HTTP listeners have been bound to, in the order in which they are configured.
+    pub fn http_local_addresses(&self) -> Vec<LocalAddress> {
+        self.http_listeners
+            .iter()
+            .map(|listener| listener.local_address().clone())
+            .collect()
+    }
+
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config.clone()
+    }
+
+    /// Runs processors created by the factory on the streams started afterwards. The factory is
+    /// given the id of the vehicle and the stream id and may return `None` to skip a stream.
+    pub fn add_frame_processor<F>(&self, factory: F)
+    where
+        F: Fn(Id, &str) -> Option<Box<dyn FrameProcessor>> + Send + Sync + 'static,
+    {
+        self.server_state.add_frame_processor(Arc::new(factory));
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tx: self.shutdown_tx.clone(),
+        }
+    }
+
+    /// Accepts connections until shut down via a [`ShutdownHandle`], then drains all connections.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let listeners = self
+            .listeners
+            .into_iter()
+            .chain(self.http_listeners)
+            .map(|listener| {
+                listener.run(
+                    self.server_state.clone(),
+                    self.config.clone(),
+                    self.shutdown_rx.clone(),
+                )
+            });
+
+        let reporter = report_link_quality(self.config.clone(), self.server_state.clone());
+        pin_mut!(reporter);
+
+        // A failing listener stops the others, so the server does not keep running degraded.
+        let result = match future::select(future::try_join_all(listeners), reporter).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((), _)) => unreachable!("Link quality reporter never returns"),
+        };
+        log::info!("Server is shutting down");
+
+        drain(&self.config.current(), &self.server_state).await;
+        log::info!("Server has shut down");
+
+        result.map(|_| ())
+    }
+}
+
+/// Periodically reports the link quality of all connections to their groups.
+async fn report_link_quality(config: ConfigHandle, server_state: Arc<ServerState>) {
+    loop {
+        let config = config.current();
+        if config.link_report_interval.is_zero() {
+            // Check again later in case reports are enabled by a reload.
+            tokio::time::sleep(config.ping_interval).await;
+            continue;
+        }
+
+        tokio::time::sleep(config.link_report_interval).await;
+        server_state.report_link_quality();
+    }
+}
+
+/// Announces the shutdown to all clients, sends the failsafe to all vehicles and waits for
+/// clients to disconnect before closing the remaining connections.
+async fn drain(config: &ServerConfig, server_state: &ServerState) {
+    // Clients cannot resume their sessions anymore once the server is shutting down.
+    server_state.end_sessions();
+
+    let deadline = Instant::now() + config.drain_timeout;
+    let going_away = ControlMessage::GoingAway(aviator5g_common::GoingAwayMessageData {
+        reason: "Server is shutting down".into(),
+        deadline: chrono::Utc::now()
+            + chrono::Duration::from_std(config.drain_timeout)
+                .unwrap_or_else(|_| chrono::Duration::zero()),
+    });
+    let failsafe = config.failsafe_axes.as_ref().map(|axes| {
+        ControlMessage::Control(aviator5g_common::ControlMessageData {
+            axes: axes.clone(),
+            target_id: None,
+        })
+    });
+
+    server_state.for_each_connection(|connection| {
+        if connection.client_type == Some(ClientType::Vehicle) {
+            if let Some(failsafe) = &failsafe {
+                connection.send_control_message(failsafe);
+            }
+        }
+        connection.send_control_message(&going_away);
+    });
+
+    log::info!(
+        "Draining {} connections for up to {:?}...",
+        server_state.connection_count(),
+        config.drain_timeout
+    );
+
+    if !server_state.wait_until_empty(deadline).await {
+        log::warn!(
+            "Closing {} connections that are still open after the drain timeout",
+            server_state.connection_count()
+        );
+
+        server_state.for_each_connection(|connection| {
+            connection.close(CloseCode::Away, "Server is shutting down");
+        });
+        server_state
+            .wait_until_empty(Instant::now() + CLOSE_GRACE_PERIOD)
+            .await;
+    }
+    server_state.finish_recordings().await;
+}
@@ -0,0 +1,110 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    collections::VecDeque,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use aviator5g_common::{
+    ClientType,
+    Id,
+    LinkQuality,
+};
+
+/// Number of most recent probes the link statistics are computed over.
+pub const LINK_WINDOW: usize = 20;
+
+/// Measures the round-trip time to a client by numbering the pings sent to it and timing the
+/// pongs echoing them.
+#[derive(Debug)]
+pub struct LinkStats {
+    /// Round-trip times of the recent probes, `None` for probes that have not been answered.
+    samples: VecDeque<Option<Duration>>,
+    pending: Option<(u64, Instant)>,
+    next_sequence: u64,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(LINK_WINDOW),
+            pending: None,
+            next_sequence: 0,
+        }
+    }
+
+    /// Starts a probe and returns the payload of the ping carrying it. A probe that is still
+    /// pending is counted as lost.
+    pub fn probe(&mut self, now: Instant) -> Vec<u8> {
+        if self.pending.take().is_some() {
+            self.record(None);
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.pending = Some((sequence, now));
+
+        sequence.to_be_bytes().to_vec()
+    }
+
+    /// Completes the pending probe if the pong echoes its payload and returns its round-trip time.
+    pub fn complete(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let sequence = u64::from_be_bytes(payload.try_into().ok()?);
+        match self.pending {
+            Some((pending, sent_at)) if pending == sequence => {
+                self.pending = None;
+
+                let rtt = now.duration_since(sent_at);
+                self.record(Some(rtt));
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, sample: Option<Duration>) {
+        if self.samples.len() == LINK_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn report(&self, id: Id, client_type: ClientType) -> LinkQuality {
+        let rtts: Vec<f64> = self
+            .samples
+            .iter()
+            .flatten()
+            .map(|rtt| rtt.as_secs_f64() * 1000.0)
+            .collect();
+
+        let lost = self
+            .samples
+            .iter()
+            .filter(|sample| sample.is_none())
+            .count();
+        let mean = |values: &[f64]| -> Option<f64> {
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        let differences: Vec<f64> = rtts.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+
+        LinkQuality {
+            id,
+            client_type,
+            rtt_ms: mean(&rtts),
+            min_rtt_ms: rtts.iter().copied().reduce(f64::min),
+            max_rtt_ms: rtts.iter().copied().reduce(f64::max),
+            jitter_ms: mean(&differences),
+            loss: if self.samples.is_empty() {
+                0.0
+            } else {
+                lost as f64 / self.samples.len() as f64
+            },
+        }
+    }
+}
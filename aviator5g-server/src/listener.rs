@@ -0,0 +1,418 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    fs::File,
+    io::{
+        self,
+        BufReader,
+    },
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+    },
+    net::{
+        TcpListener,
+        UnixListener,
+        UnixStream,
+    },
+    sync::watch,
+};
+use tokio_rustls::{
+    rustls,
+    TlsAcceptor,
+};
+
+use crate::{
+    connection::handle_connection_wrapper,
+    http::handle_http_connection,
+    state::ServerState,
+    ConfigHandle,
+    ConnectionId,
+};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Time to wait before accepting connections again after having run out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where a listener accepts connections: `host:port` (IPv6 literals in brackets, e.g. `[::]:9000`)
+/// or `unix:<path>` for a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(format!("Missing socket path in listen address '{}'", s));
+            }
+
+            Ok(Self::Unix(path.into()))
+        } else if !matches!(s.rsplit_once(':'), Some((host, _)) if !host.is_empty()) {
+            Err(format!(
+                "Invalid listen address '{}', expected 'host:port' or '{}<path>'",
+                s, UNIX_PREFIX
+            ))
+        } else {
+            Ok(Self::Tcp(s.into()))
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl ListenAddress {
+    /// The address given by the separate `--host` and `--port` options of earlier versions, which
+    /// default to localhost and port 9000.
+    pub fn from_host_and_port(host: Option<&str>, port: Option<u16>) -> Self {
+        let host = host.unwrap_or("localhost");
+        let port = port.unwrap_or(9000);
+
+        if host.contains(':') && !host.starts_with('[') {
+            Self::Tcp(format!("[{}]:{}", host, port))
+        } else {
+            Self::Tcp(format!("{}:{}", host, port))
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain.
+    pub certificate: PathBuf,
+    /// PEM file containing the private key.
+    pub private_key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl From<ListenAddress> for ListenerConfig {
+    fn from(address: ListenAddress) -> Self {
+        Self { address, tls: None }
+    }
+}
+
+/// Address a listener has actually been bound to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for LocalAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// What a listener serves on the connections it accepts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// The control protocol, spoken over WebSocket.
+    WebSocket,
+    /// Video streams of vehicles.
+    Http,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WebSocket => write!(f, "WebSocket"),
+            Self::Http => write!(f, "HTTP"),
+        }
+    }
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub struct Listener {
+    protocol: Protocol,
+    socket: Socket,
+    tls_acceptor: Option<TlsAcceptor>,
+    local_address: LocalAddress,
+}
+
+impl Listener {
+    pub async fn bind(config: &ListenerConfig, protocol: Protocol) -> anyhow::Result<Self> {
+        let tls_acceptor = config.tls.as_ref().map(load_tls_acceptor).transpose()?;
+
+        let (socket, local_address) = match &config.address {
+            ListenAddress::Tcp(address) => {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Could not listen on {}", address))?;
+                let local_address = LocalAddress::Tcp(listener.local_addr()?);
+
+                (Socket::Tcp(listener), local_address)
+            }
+            ListenAddress::Unix(path) => {
+                // A socket left behind by a server that has not shut down cleanly refuses
+                // connections, whereas one of a running server must not be taken over.
+                if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    match UnixStream::connect(path).await {
+                        Ok(_) => anyhow::bail!(
+                            "Could not listen on {}: another server is listening",
+                            config.address
+                        ),
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            log::warn!("Removing stale Unix socket {}", path.display());
+                            std::fs::remove_file(path)?;
+                        }
+                        Err(_) => {}
+                    }
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Could not listen on {}", config.address))?;
+
+                (Socket::Unix(listener), LocalAddress::Unix(path.clone()))
+            }
+        };
+
+        Ok(Self {
+            protocol,
+            socket,
+            tls_acceptor,
+            local_address,
+        })
+    }
+
+    pub fn local_address(&self) -> &LocalAddress {
+        &self.local_address
+    }
+
+    /// Accepts connections until the shutdown signal has been received.
+    pub async fn run(
+        self,
+        server_state: Arc<ServerState>,
+        config: ConfigHandle,
+        mut shutdown_rx: watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        log::info!(
+            "Listening for {} connections at {}{}...",
+            self.protocol,
+            self.local_address,
+            if self.tls_acceptor.is_some() {
+                " (TLS)"
+            } else {
+                ""
+            }
+        );
+
+        // Handed to connections that outlive a single request, such as HTTP video streams.
+        let connection_shutdown_rx = shutdown_rx.clone();
+        loop {
+            tokio::select! {
+                accepted = self.accept(&server_state, &config, &connection_shutdown_rx) => {
+                    if let Err(e) = accepted {
+                        log::error!("Could not accept a connection at {}: {}", self.local_address, e);
+
+                        // Running out of file descriptors persists until connections have closed.
+                        if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+                            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        }
+                    }
+                }
+                _ = shutdown_rx.changed() => break,
+            }
+        }
+
+        log::info!("Stopped listening at {}", self.local_address);
+        Ok(())
+    }
+
+    async fn accept(
+        &self,
+        server_state: &Arc<ServerState>,
+        config: &ConfigHandle,
+        shutdown_rx: &watch::Receiver<bool>,
+    ) -> io::Result<()> {
+        let connection_id = ConnectionId::next();
+
+        match &self.socket {
+            Socket::Tcp(listener) => {
+                let (stream, peer_address) = listener.accept().await?;
+                log::info!(
+                    "Incoming connection {} from {} at {}",
+                    connection_id,
+                    peer_address,
+                    self.local_address
+                );
+                self.spawn_connection(server_state, config, shutdown_rx, stream, connection_id);
+            }
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                log::info!(
+                    "Incoming connection {} at {}",
+                    connection_id,
+                    self.local_address
+                );
+                self.spawn_connection(server_state, config, shutdown_rx, stream, connection_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn spawn_connection<S>(
+        &self,
+        server_state: &Arc<ServerState>,
+        config: &ConfigHandle,
+        shutdown_rx: &watch::Receiver<bool>,
+        stream: S,
+        connection_id: ConnectionId,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let protocol = self.protocol;
+        let server_state = server_state.clone();
+        let config = config.clone();
+        let shutdown_rx = shutdown_rx.clone();
+
+        match self.tls_acceptor.clone() {
+            Some(tls_acceptor) => {
+                tokio::spawn(async move {
+                    let handshake_timeout = config.current().identification_timeout;
+                    match tokio::time::timeout(handshake_timeout, tls_acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            serve(
+                                protocol,
+                                server_state,
+                                config,
+                                shutdown_rx,
+                                stream,
+                                connection_id,
+                            )
+                            .await
+                        }
+                        Ok(Err(e)) => {
+                            log::error!("TLS handshake with {} failed: {}", connection_id, e)
+                        }
+                        Err(_) => log::error!("TLS handshake with {} timed out", connection_id),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(serve(
+                    protocol,
+                    server_state,
+                    config,
+                    shutdown_rx,
+                    stream,
+                    connection_id,
+                ));
+            }
+        }
+    }
+}
+
+async fn serve<S>(
+    protocol: Protocol,
+    server_state: Arc<ServerState>,
+    config: ConfigHandle,
+    shutdown_rx: watch::Receiver<bool>,
+    stream: S,
+    connection_id: ConnectionId,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match protocol {
+        Protocol::WebSocket => {
+            handle_connection_wrapper(server_state, config, stream, connection_id).await
+        }
+        Protocol::Http => {
+            handle_http_connection(server_state, config, stream, connection_id, shutdown_rx).await
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let LocalAddress::Unix(path) = &self.local_address {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn load_tls_acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let open = |path: &PathBuf| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Could not open {}", path.display()))
+    };
+
+    let certificates = rustls_pemfile::certs(&mut open(&config.certificate)?)
+        .with_context(|| format!("Invalid certificate {}", config.certificate.display()))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let mut key_reader = open(&config.private_key)?;
+    let private_key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break rustls::PrivateKey(key),
+            Some(_) => {}
+            None => anyhow::bail!("No private key found in {}", config.private_key.display()),
+        }
+    };
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
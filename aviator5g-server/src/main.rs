@@ -3,351 +3,284 @@
  * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
  */
 
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{
-        Arc,
-        Mutex,
-    },
-};
+use std::path::PathBuf;
 
 use argh::FromArgs;
-use aviator5g_common::ControlMessage;
-use futures_channel::mpsc::{
-    unbounded,
-    UnboundedSender,
-};
-use futures_util::{
-    future,
-    pin_mut,
-    stream::TryStreamExt,
-    StreamExt,
+use aviator5g_server::{
+    parse_axes,
+    ConfigError,
+    ConfigHandle,
+    ConfigOverrides,
+    DuplicateIdPolicy,
+    ListenAddress,
+    ListenerConfig,
+    Server,
+    ServerConfig,
+    ShutdownHandle,
 };
-use tokio::net::{
-    TcpListener,
-    TcpStream,
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
 };
 
-type Tx = UnboundedSender<tungstenite::Message>;
-
-struct ConnectionState {
-    tx: Tx,
-    group_id: Option<aviator5g_common::Id>,
-    id: Option<aviator5g_common::Id>,
-    client_type: Option<aviator5g_common::ClientType>,
+/// Aviator5G Server.
+///
+/// Settings are taken from the defaults, the configuration file, environment variables prefixed
+/// with AVIATOR5G_ (e.g. AVIATOR5G_PING_INTERVAL) and the options below, in increasing order of
+/// precedence. Send SIGHUP to reload settings that can change at runtime.
+#[derive(Debug, Clone, FromArgs)]
+struct Args {
+    /// path to a TOML configuration file.
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// address on which the server listens, either 'host:port' with IPv6 hosts in brackets or
+    /// 'unix:<path>'; may be repeated and replaces all configured listeners
+    /// (default: localhost:9000).
+    #[argh(option)]
+    listen: Vec<ListenAddress>,
+
+    /// address on which the server serves video streams over HTTP, in the same format as
+    /// --listen; may be repeated and replaces all configured HTTP listeners
+    /// (default: localhost:8554).
+    #[argh(option)]
+    http_listen: Vec<ListenAddress>,
+
+    /// interval in seconds at which the server pings each connection (default: 5).
+    #[argh(option)]
+    ping_interval: Option<u64>,
+
+    /// time in seconds after which a connection that has not sent anything is closed with
+    /// code 1001 (default: 15).
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+
+    /// time in seconds within which a new connection must identify itself or is closed
+    /// with code 1008 (default: 10).
+    #[argh(option)]
+    identification_timeout: Option<u64>,
+
+    /// how to handle a client identifying with an id that is already connected:
+    /// 'reject' closes the new connection, 'supersede' closes the old one (default: reject).
+    #[argh(option)]
+    duplicate_id_policy: Option<DuplicateIdPolicy>,
+
+    /// messages per second each connection may send per message type (default: 100).
+    #[argh(option)]
+    rate_limit: Option<f64>,
+
+    /// burst of messages each connection may send per message type (default: 50).
+    #[argh(option)]
+    rate_limit_burst: Option<f64>,
+
+    /// messages per second all connections of a group may send together per message type
+    /// (default: 500).
+    #[argh(option)]
+    group_rate_limit: Option<f64>,
+
+    /// burst of messages all connections of a group may send together per message type
+    /// (default: 200).
+    #[argh(option)]
+    group_rate_limit_burst: Option<f64>,
+
+    /// number of rate-limited messages within 10 seconds after which a connection is closed
+    /// (default: 100).
+    #[argh(option)]
+    max_rate_limit_violations: Option<u32>,
+
+    /// maximum size of a single WebSocket text message in bytes (default: 65536).
+    #[argh(option)]
+    max_message_size: Option<usize>,
+
+    /// maximum size of a binary WebSocket message carrying a video frame in bytes
+    /// (default: 1048576).
+    #[argh(option)]
+    max_video_frame_size: Option<usize>,
+
+    /// directory in which the video streams of vehicles are recorded as MJPEG AVI files with
+    /// the capture time of each frame in an accompanying CSV file (default: disabled).
+    #[argh(option)]
+    recording_directory: Option<PathBuf>,
+
+    /// share of a video frame from 0 to 1 that must change between frames for motion to be
+    /// reported to the group (default: disabled).
+    #[argh(option)]
+    motion_detection_threshold: Option<f64>,
+
+    /// time in seconds clients are given to disconnect after a shutdown has been announced
+    /// (default: 10).
+    #[argh(option)]
+    drain_timeout: Option<u64>,
+
+    /// comma-separated axes sent to all vehicles on shutdown, e.g. '0,0,0,0'.
+    #[argh(option, from_str_fn(parse_axes))]
+    failsafe_axes: Option<Vec<f64>>,
+
+    /// time in seconds for which a disconnected client can resume its session, 0 disables
+    /// resumption (default: 30).
+    #[argh(option)]
+    resume_grace_period: Option<u64>,
+
+    /// interval in seconds at which the round-trip times measured to each client are reported
+    /// to its group, 0 disables the reports (default: 5).
+    #[argh(option)]
+    link_report_interval: Option<u64>,
+
+    /// whether clients may join groups that are not listed in the configuration file, e.g.
+    /// for local development (default: false).
+    #[argh(option)]
+    allow_unknown_groups: Option<bool>,
 }
 
-impl ConnectionState {
-    fn new(tx: Tx) -> Self {
-        Self {
-            tx,
-            group_id: None,
-            id: None,
-            client_type: None,
+impl Args {
+    fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            listeners: (!self.listen.is_empty()).then(|| {
+                self.listen
+                    .iter()
+                    .cloned()
+                    .map(ListenerConfig::from)
+                    .collect()
+            }),
+            http_listeners: (!self.http_listen.is_empty()).then(|| {
+                self.http_listen
+                    .iter()
+                    .cloned()
+                    .map(ListenerConfig::from)
+                    .collect()
+            }),
+            ping_interval: self.ping_interval,
+            idle_timeout: self.idle_timeout,
+            identification_timeout: self.identification_timeout,
+            duplicate_id_policy: self.duplicate_id_policy,
+            rate_limit: self.rate_limit,
+            rate_limit_burst: self.rate_limit_burst,
+            group_rate_limit: self.group_rate_limit,
+            group_rate_limit_burst: self.group_rate_limit_burst,
+            max_rate_limit_violations: self.max_rate_limit_violations,
+            max_message_size: self.max_message_size,
+            max_video_frame_size: self.max_video_frame_size,
+            recording_directory: self.recording_directory.clone(),
+            motion_detection_threshold: self.motion_detection_threshold,
+            drain_timeout: self.drain_timeout,
+            failsafe_axes: self.failsafe_axes.clone(),
+            resume_grace_period: self.resume_grace_period,
+            link_report_interval: self.link_report_interval,
+            allow_unknown_groups: self.allow_unknown_groups,
+            groups: None,
         }
     }
 
-    fn is_identified(&self) -> bool {
-        self.group_id.is_some() && self.id.is_some() && self.client_type.is_some()
-    }
-
-    fn identify(
-        &mut self,
-        group_id: aviator5g_common::Id,
-        id: aviator5g_common::Id,
-        client_type: aviator5g_common::ClientType,
-    ) {
-        self.group_id = Some(group_id);
-        self.id = Some(id);
-        self.client_type = Some(client_type);
+    fn load_config(&self) -> Result<ServerConfig, ConfigError> {
+        ServerConfig::load(self.config.as_deref(), &self.overrides())
     }
 }
 
-struct ServerState {
-    connections: HashMap<SocketAddr, ConnectionState>,
-}
-
-impl ServerState {
-    fn new() -> Self {
-        Self {
-            connections: HashMap::new(),
-        }
-    }
+async fn reload_on_signal(args: Args, config_handle: ConfigHandle) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
 
-    fn accept_connection(&mut self, address: SocketAddr, tx: Tx) {
-        self.connections.insert(address, ConnectionState::new(tx));
-    }
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading configuration...");
 
-    fn release_connection(&mut self, address: &SocketAddr) {
-        self.connections.remove(address);
-    }
-
-    fn connection_from_socket_address(&self, address: SocketAddr) -> Option<&ConnectionState> {
-        self.connections.get(&address)
-    }
-
-    fn connection_from_socket_address_mut(
-        &mut self,
-        address: SocketAddr,
-    ) -> Option<&mut ConnectionState> {
-        self.connections.get_mut(&address)
-    }
-
-    fn connection_from_id(&self, id: aviator5g_common::Id) -> Option<&ConnectionState> {
-        self.connections.values().into_iter().find(|v| v.id == Some(id))
+        if let Err(e) = args
+            .load_config()
+            .and_then(|config| config_handle.reload(config))
+        {
+            log::error!("Keeping the current configuration: {:?}", e);
+        }
     }
-}
-
-#[derive(thiserror::Error)]
-pub enum ServerError {
-    #[error("An error occurred while handling the connection: {0}")]
-    ConnectionError(tungstenite::Error),
-
-    #[error("The control message is malformed: {0}")]
-    MalformedControlMessageError(String),
 
-    #[error("Client is not identified")]
-    NotIdentifiedError,
-
-    #[error("Client has already been identified")]
-    AlreadyIdentifiedError,
-
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
+    Ok(())
 }
 
-impl From<tungstenite::Error> for ServerError {
-    fn from(e: tungstenite::Error) -> Self {
-        Self::ConnectionError(e)
-    }
-}
+async fn shutdown_on_signal(shutdown_handle: ShutdownHandle) -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
 
-impl std::fmt::Debug for ServerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        aviator5g_common::error_chain_fmt(self, f)
+    tokio::select! {
+        _ = terminate.recv() => log::info!("Received SIGTERM"),
+        _ = interrupt.recv() => log::info!("Received SIGINT"),
     }
-}
-
-/// Aviator5G Server.
-#[derive(Debug, Clone, FromArgs)]
-struct Args {
-    /// the hostname on which the server listens.
-    #[argh(option, default = r#""localhost".into()"#)]
-    host: String,
-
-    /// the server's port.
-    #[argh(option, default = "9000")]
-    port: u16,
-}
 
-pub enum ControlMessageAction {
-    None,
-    ForwardAll,
-    ForwardSingle(aviator5g_common::Id),
+    shutdown_handle.shutdown();
+    Ok(())
 }
 
-fn handle_control_message(
-    server_state: Arc<Mutex<ServerState>>,
-    socket_address: SocketAddr,
-    control_message: ControlMessage,
-) -> Result<ControlMessageAction, ServerError> {
-    log::debug!(
-        "Handling control message: {} {:?}",
-        socket_address,
-        control_message
-    );
-
-    let mut server_state = server_state.lock().unwrap();
-    let connection = server_state
-        .connection_from_socket_address_mut(socket_address)
-        .expect("Unknown connection");
-
-    match control_message {
-        ControlMessage::Identification(e) => {
-            if connection.is_identified() {
-                return Err(ServerError::AlreadyIdentifiedError);
+/// Translates the `--host` and `--port` options of earlier versions into a single `--listen`
+/// option. They keep working but are left out of the help.
+fn translate_legacy_args(args: Vec<String>) -> Result<Vec<String>, String> {
+    let mut host = None;
+    let mut port = None;
+    let mut translated = Vec::with_capacity(args.len());
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--host" => host = Some(args.next().ok_or("Missing value for option '--host'")?),
+            "--port" => {
+                let value = args.next().ok_or("Missing value for option '--port'")?;
+                port = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid port '{}'", value))?,
+                );
             }
-
-            connection.identify(e.group_id, e.id, e.client_type);
-            Ok(ControlMessageAction::None)
+            _ => translated.push(arg),
         }
+    }
 
-        ControlMessage::Control(_) | ControlMessage::LatencyRequest(_) => {
-            if !connection.is_identified() {
-                return Err(ServerError::NotIdentifiedError);
-            }
-            Ok(ControlMessageAction::ForwardAll)
+    if host.is_some() || port.is_some() {
+        if translated.iter().any(|arg| arg == "--listen") {
+            return Err("--host and --port cannot be combined with --listen".into());
         }
 
-        ControlMessage::LatencyResponse(e) => {
-            if !connection.is_identified() {
-                return Err(ServerError::NotIdentifiedError);
-            }
-
-            Ok(ControlMessageAction::ForwardSingle(e.initiator_id))
-        }
+        translated.push("--listen".into());
+        translated.push(ListenAddress::from_host_and_port(host.as_deref(), port).to_string());
     }
-}
 
-fn handle_message(
-    server_state: Arc<Mutex<ServerState>>,
-    socket_address: SocketAddr,
-    message: tungstenite::Message,
-) -> Result<ControlMessageAction, ServerError> {
-    match &message {
-        tungstenite::Message::Text(text) => {
-            log::debug!("Received WS Text: {}", socket_address);
-
-            let control_message = aviator5g_common::parse_control_message(&text)
-                .map_err(|e| ServerError::MalformedControlMessageError(e))?;
-
-            handle_control_message(server_state, socket_address, control_message)
-        }
-        tungstenite::Message::Binary(_) => {
-            log::debug!("Received Binary Message: {}", socket_address);
-            Ok(ControlMessageAction::None)
-        }
-        tungstenite::Message::Ping(_) => {
-            log::debug!("Received Ping Message: {}", socket_address);
-            Ok(ControlMessageAction::None)
-        }
-        tungstenite::Message::Pong(_) => {
-            log::debug!("Received Pong Message: {}", socket_address);
-            Ok(ControlMessageAction::None)
-        }
-        tungstenite::Message::Close(_) => {
-            log::debug!("Received Close Message: {}", socket_address);
-            Ok(ControlMessageAction::None)
-        }
-    }
+    Ok(translated)
 }
 
-async fn handle_connection(
-    server_state: Arc<Mutex<ServerState>>,
-    tcp_stream: TcpStream,
-    socket_address: SocketAddr,
-) -> anyhow::Result<(), ServerError> {
-    log::info!("Incoming TCP connection: {}", socket_address);
-
-    let ws_stream = tokio_tungstenite::accept_async(tcp_stream).await?;
-
-    log::info!("WebSocket connection established: {}", socket_address);
-
-    let (tx, rx) = unbounded();
-    server_state
-        .lock()
-        .unwrap()
-        .accept_connection(socket_address, tx);
-
-    let (outgoing, incoming) = ws_stream.split();
-
-    let (abort_connection_tx, abort_connection_rx) = futures::channel::oneshot::channel::<()>();
-    let abort_connection = Mutex::new(Some(abort_connection_tx));
-
-    let broadcast_incoming = incoming.try_for_each(|message| {
-        match handle_message(server_state.clone(), socket_address, message.clone()) {
-            Ok(action) => match action {
-                ControlMessageAction::None => {}
-                ControlMessageAction::ForwardAll => {
-                    let server_state = server_state.lock().unwrap();
-                    let current_connection = &server_state
-                        .connection_from_socket_address(socket_address)
-                        .expect("Unknown connection");
-
-                    let current_group_id = &current_connection.group_id;
-                    let client_type = &current_connection.client_type;
-
-                    // Forward message to all other clients of different type within the same group.
-                    server_state
-                        .connections
-                        .iter()
-                        .filter(|(sa, state)| {
-                            sa != &&socket_address
-                                && state.group_id == *current_group_id
-                                && state.client_type != *client_type
-                        })
-                        .map(|(_, state)| &state.tx)
-                        .for_each(|tx| tx.unbounded_send(message.clone()).unwrap());
-                }
-                ControlMessageAction::ForwardSingle(recipient_id) => {
-                    let server_state = server_state.lock().unwrap();
-                    if let Some(connection) = server_state.connection_from_id(recipient_id) {
-                        connection.tx.unbounded_send(message.clone()).unwrap();
-                    }
-                }
-            },
-            Err(e) => {
-                log::error!(
-                    "An error occurred while handling the control message: {} {:?} ::: {}",
-                    socket_address,
-                    e,
-                    message,
-                );
-
-                abort_connection
-                    .lock()
-                    .unwrap()
-                    .take()
-                    .unwrap()
-                    .send(())
-                    .unwrap();
-            }
-        }
-
-        future::ok(())
+/// Parses the command line as `argh::from_env` does, after translating legacy options.
+fn args_from_env() -> Args {
+    let mut strings = std::env::args();
+    let command = strings.next().unwrap_or_default();
+    let strings = translate_legacy_args(strings.collect()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
     });
 
-    let receive_from_others = rx.map(Ok).forward(outgoing);
-
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(
-        future::select(broadcast_incoming, receive_from_others),
-        abort_connection_rx,
-    )
-    .await;
-
-    log::info!("Connection disconnected: {}", &socket_address);
-    server_state
-        .lock()
-        .unwrap()
-        .release_connection(&socket_address);
-
-    Ok(())
-}
-
-async fn handle_connection_wrapper(
-    server_state: Arc<Mutex<ServerState>>,
-    tcp_stream: TcpStream,
-    socket_address: SocketAddr,
-) {
-    let result = handle_connection(server_state.clone(), tcp_stream, socket_address).await;
-    if let Err(e) = result {
-        log::error!(
-            "Connection {} has been terminated due to an error: {:?}",
-            socket_address,
-            e
-        );
-    }
+    let name = std::path::Path::new(&command)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&command);
+    let strs: Vec<&str> = strings.iter().map(String::as_str).collect();
+
+    Args::from_args(&[name], &strs).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!("{}", early_exit.output);
+                1
+            }
+        })
+    })
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let args: Args = argh::from_env();
-    let server_state = Arc::new(Mutex::new(ServerState::new()));
-
-    log::info!("Starting server at {}:{}...", args.host, args.port);
-    let server = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
+    let args = args_from_env();
+    let config = args.load_config()?;
 
-    while let Ok((tcp_stream, socket_address)) = server.accept().await {
-        tokio::spawn(handle_connection_wrapper(
-            server_state.clone(),
-            tcp_stream,
-            socket_address,
-        ));
-    }
+    log::info!("Starting server...");
+    let server = Server::bind(config).await?;
 
-    Ok(())
+    tokio::spawn(shutdown_on_signal(server.shutdown_handle()));
+    tokio::spawn(reload_on_signal(args, server.config_handle()));
+    server.run().await
 }
@@ -0,0 +1,62 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+use aviator5g_common::{
+    Id,
+    VideoFrame,
+};
+use tokio::sync::Notify;
+
+/// Identifies a video stream by the vehicle sending it and the stream id chosen by the vehicle.
+pub type StreamKey = (Id, String);
+
+/// Video frames waiting to be sent to a connection.
+///
+/// Only the latest frame of each stream is kept, so that a connection that cannot keep up skips
+/// frames instead of falling further and further behind.
+#[derive(Debug, Default)]
+pub struct FrameQueue {
+    frames: Mutex<Vec<(StreamKey, Arc<Vec<u8>>)>>,
+    notify: Notify,
+}
+
+impl FrameQueue {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Queues the encoded frame, replacing an undelivered frame of the same stream.
+    pub fn push(&self, key: &StreamKey, frame: Arc<Vec<u8>>) {
+        let mut frames = self.frames.lock().unwrap();
+        match frames.iter_mut().find(|(queued, _)| queued == key) {
+            Some((_, queued)) => *queued = frame,
+            None => frames.push((key.clone(), frame)),
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Waits until at least one frame has been queued.
+    pub async fn ready(&self) {
+        self.notify.notified().await
+    }
+
+    pub fn take(&self) -> Vec<Arc<Vec<u8>>> {
+        self.frames
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(_, frame)| frame)
+            .collect()
+    }
+}
+
+/// The latest frame of a stream, shared by all HTTP clients watching it.
+pub type LatestFrame = Option<Arc<VideoFrame>>;
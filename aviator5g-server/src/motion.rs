@@ -0,0 +1,140 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use aviator5g_common::{
+    AlertSeverity,
+    BoundingBox,
+};
+
+use crate::analysis::{
+    Alert,
+    Annotations,
+    DecodedFrame,
+    FrameProcessor,
+};
+
+/// Size in pixels of the square blocks whose mean brightness is compared between frames.
+const BLOCK_SIZE: usize = 8;
+
+/// Change in mean brightness, from 0 to 255, beyond which a block is considered to have changed.
+/// Absorbs sensor noise and compression artifacts.
+const BLOCK_THRESHOLD: u32 = 16;
+
+/// Reports motion in a video stream by comparing each frame to the previous one, without
+/// requiring any hardware acceleration.
+///
+/// A bounding box encloses all parts of the frame that have changed, and an alert is raised
+/// whenever motion starts.
+#[derive(Debug)]
+pub struct MotionDetector {
+    threshold: f64,
+    previous: Option<Blocks>,
+    moving: bool,
+}
+
+impl MotionDetector {
+    /// Creates a detector that reports motion once the given share of the frame, from 0 to 1, has
+    /// changed.
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: None,
+            moving: false,
+        }
+    }
+}
+
+impl FrameProcessor for MotionDetector {
+    fn name(&self) -> &str {
+        "motion"
+    }
+
+    fn process(&mut self, frame: &DecodedFrame) -> Annotations {
+        let blocks = Blocks::of(frame);
+        let previous = match self.previous.replace(blocks) {
+            Some(previous) if previous.dimensions == (frame.width, frame.height) => previous,
+            // The first frame and a change of resolution only establish the reference.
+            _ => {
+                self.moving = false;
+                return Annotations::default();
+            }
+        };
+        let current = self.previous.as_ref().unwrap();
+
+        let mut changed = 0;
+        let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+        for (index, (a, b)) in previous.means.iter().zip(&current.means).enumerate() {
+            if a.abs_diff(*b) > BLOCK_THRESHOLD {
+                let (column, row) = (index % current.columns, index / current.columns);
+                left = left.min(column);
+                top = top.min(row);
+                right = right.max(column + 1);
+                bottom = bottom.max(row + 1);
+                changed += 1;
+            }
+        }
+
+        if (changed as f64) < self.threshold * current.means.len() as f64 || changed == 0 {
+            self.moving = false;
+            return Annotations::default();
+        }
+
+        let (width, height) = (f64::from(frame.width), f64::from(frame.height));
+        let x = (left * BLOCK_SIZE) as f64 / width;
+        let y = (top * BLOCK_SIZE) as f64 / height;
+        let mut annotations = Annotations {
+            boxes: vec![BoundingBox {
+                x,
+                y,
+                width: ((right * BLOCK_SIZE) as f64 / width).min(1.0) - x,
+                height: ((bottom * BLOCK_SIZE) as f64 / height).min(1.0) - y,
+                label: "motion".into(),
+                confidence: None,
+            }],
+            ..Default::default()
+        };
+
+        if !self.moving {
+            annotations.alerts.push(Alert {
+                severity: AlertSeverity::Warning,
+                message: "Motion detected".into(),
+            });
+        }
+        self.moving = true;
+
+        annotations
+    }
+}
+
+/// Mean brightness of each block of a frame, row by row.
+#[derive(Debug)]
+struct Blocks {
+    dimensions: (u16, u16),
+    columns: usize,
+    means: Vec<u32>,
+}
+
+impl Blocks {
+    fn of(frame: &DecodedFrame) -> Self {
+        let (width, height) = (usize::from(frame.width), usize::from(frame.height));
+        let columns = width.div_ceil(BLOCK_SIZE);
+        let rows = height.div_ceil(BLOCK_SIZE);
+
+        let mut sums = vec![(0, 0); columns * rows];
+        for y in 0..height {
+            for x in 0..width {
+                let (sum, count) = &mut sums[(y / BLOCK_SIZE) * columns + x / BLOCK_SIZE];
+                *sum += u32::from(frame.luma(x, y));
+                *count += 1;
+            }
+        }
+
+        Self {
+            dimensions: (frame.width, frame.height),
+            columns,
+            means: sums.into_iter().map(|(sum, count)| sum / count).collect(),
+        }
+    }
+}
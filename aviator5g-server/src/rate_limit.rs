@@ -0,0 +1,149 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+/// Window within which rate limit violations are counted before escalating to a disconnect.
+pub const VIOLATION_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Number of messages replenished per second.
+    pub rate: f64,
+    /// Maximum number of messages that can be sent in a single burst.
+    pub burst: f64,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            refilled_at: now,
+        }
+    }
+
+    pub fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitVerdict {
+    Allow,
+    /// The message is dropped silently because the client has already been warned.
+    Drop,
+    /// The message is dropped and the client is told why.
+    Reject,
+    /// The client kept exceeding its limit and is disconnected.
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub struct ViolationCounter {
+    count: u32,
+    window_started_at: Instant,
+}
+
+impl ViolationCounter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            count: 0,
+            window_started_at: now,
+        }
+    }
+
+    pub fn record(&mut self, max_violations: u32, now: Instant) -> RateLimitVerdict {
+        if now.duration_since(self.window_started_at) > VIOLATION_WINDOW {
+            self.count = 0;
+            self.window_started_at = now;
+        }
+
+        self.count += 1;
+
+        if self.count > max_violations {
+            RateLimitVerdict::Disconnect
+        } else if self.count == 1 {
+            RateLimitVerdict::Reject
+        } else {
+            RateLimitVerdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        rate: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn buckets_start_full_and_allow_a_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&LIMIT, now);
+
+        assert!((0..3).all(|_| bucket.try_take(&LIMIT, now)));
+        assert!(!bucket.try_take(&LIMIT, now));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&LIMIT, now);
+        (0..3).for_each(|_| {
+            bucket.try_take(&LIMIT, now);
+        });
+
+        assert!(!bucket.try_take(&LIMIT, now + Duration::from_millis(250)));
+        assert!(bucket.try_take(&LIMIT, now + Duration::from_millis(500)));
+        assert!(!bucket.try_take(&LIMIT, now + Duration::from_millis(500)));
+
+        let later = now + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.try_take(&LIMIT, later)));
+        assert!(!bucket.try_take(&LIMIT, later));
+    }
+
+    #[test]
+    fn violations_escalate_from_reject_to_drop_to_disconnect() {
+        let now = Instant::now();
+        let mut violations = ViolationCounter::new(now);
+
+        assert_eq!(violations.record(3, now), RateLimitVerdict::Reject);
+        assert_eq!(violations.record(3, now), RateLimitVerdict::Drop);
+        assert_eq!(violations.record(3, now), RateLimitVerdict::Drop);
+        assert_eq!(violations.record(3, now), RateLimitVerdict::Disconnect);
+    }
+
+    #[test]
+    fn violations_are_forgotten_after_the_window() {
+        let now = Instant::now();
+        let mut violations = ViolationCounter::new(now);
+        violations.record(1, now);
+
+        let later = now + VIOLATION_WINDOW + Duration::from_secs(1);
+        assert_eq!(violations.record(1, later), RateLimitVerdict::Reject);
+        assert_eq!(violations.record(1, later), RateLimitVerdict::Disconnect);
+    }
+}
@@ -0,0 +1,371 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Records video streams to disk as Motion JPEG in AVI files, each accompanied by a CSV file with
+//! the capture time of every frame.
+//!
+//! The headers are updated after every frame, so that a file remains playable even if the server
+//! stops without finishing it. Only the index, which players do not require, is written last.
+
+use std::{
+    fs::File,
+    io::{
+        self,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use aviator5g_common::{
+    DateTime,
+    VideoFrame,
+};
+use chrono::SecondsFormat;
+use tokio::sync::watch;
+
+use crate::media::{
+    LatestFrame,
+    StreamKey,
+};
+
+/// Files are continued in a new file beyond this size, well below the limits of AVI 1.0.
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Frame duration written until the duration can be derived from the frames' capture times.
+const DEFAULT_FRAME_MICROS: u32 = 1_000_000 / 15;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// Offsets of the fields that are updated while recording.
+const RIFF_SIZE: u64 = 4;
+const AVIH_MICROS_PER_FRAME: u64 = 32;
+const AVIH_MAX_BYTES_PER_SEC: u64 = 36;
+const AVIH_FLAGS: u64 = 44;
+const AVIH_TOTAL_FRAMES: u64 = 48;
+const AVIH_SUGGESTED_BUFFER_SIZE: u64 = 60;
+const STRH_SCALE: u64 = 128;
+const STRH_LENGTH: u64 = 140;
+const STRH_SUGGESTED_BUFFER_SIZE: u64 = 144;
+const MOVI_SIZE: u64 = 216;
+const MOVI: u64 = 220;
+const HEADER_LENGTH: u64 = 224;
+
+/// Records the frames of a stream until it ends, skipping frames while the disk cannot keep up.
+pub async fn record(directory: PathBuf, key: StreamKey, mut frames: watch::Receiver<LatestFrame>) {
+    let (vehicle_id, stream_id) = key;
+    let mut recorder = Recorder::new(directory.join(vehicle_id.to_string()), &stream_id);
+
+    loop {
+        let frame = frames.borrow_and_update().clone();
+        if let Some(frame) = frame {
+            let result = tokio::task::spawn_blocking(move || {
+                let result = recorder.write(&frame);
+                (recorder, result)
+            })
+            .await;
+
+            match result {
+                Ok((next, Ok(()))) => recorder = next,
+                Ok((mut next, Err(e))) => {
+                    log::error!(
+                        "Stopped recording stream '{}' of {}: {}",
+                        stream_id,
+                        vehicle_id,
+                        e
+                    );
+                    let _ = tokio::task::spawn_blocking(move || next.finish()).await;
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+
+        if frames.changed().await.is_err() {
+            break;
+        }
+    }
+
+    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || recorder.finish()).await {
+        log::error!("Could not finish recording: {}", e);
+    }
+}
+
+/// Writes the frames of one stream to a sequence of files, starting a new file whenever the
+/// resolution changes or the file grows too large.
+struct Recorder {
+    directory: PathBuf,
+    name: String,
+    file: Option<AviFile>,
+}
+
+impl Recorder {
+    fn new(directory: PathBuf, stream_id: &str) -> Self {
+        // Stream ids are chosen by vehicles and must not escape the directory.
+        let name = stream_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+
+        Self {
+            directory,
+            name,
+            file: None,
+        }
+    }
+
+    fn write(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        let dimensions = match jpeg_dimensions(&frame.jpeg) {
+            Some(dimensions) => dimensions,
+            None => {
+                log::warn!("Skipping recording of a frame that is not a JPEG image");
+                return Ok(());
+            }
+        };
+
+        if let Some(file) = &self.file {
+            if file.dimensions != dimensions || file.len >= MAX_FILE_SIZE {
+                self.finish()?;
+            }
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                std::fs::create_dir_all(&self.directory)?;
+                let path = self.directory.join(format!(
+                    "{}-{}.avi",
+                    self.name,
+                    chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+                ));
+
+                log::info!("Recording to {}", path.display());
+                self.file.insert(AviFile::create(&path, dimensions)?)
+            }
+        };
+
+        file.write_frame(&frame.jpeg, frame.captured_at)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.file.take() {
+            Some(file) => file.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+struct AviFile {
+    avi: File,
+    timestamps: File,
+    dimensions: (u16, u16),
+    len: u64,
+    /// Offset and size of every frame, for the index written when the file is finished.
+    index: Vec<(u32, u32)>,
+    first_captured_at: Option<DateTime>,
+    max_frame_size: u32,
+}
+
+impl AviFile {
+    fn create(path: &Path, dimensions: (u16, u16)) -> io::Result<Self> {
+        let (width, height) = (u32::from(dimensions.0), u32::from(dimensions.1));
+
+        let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+        let mut chunk = |fourcc: &[u8; 4], values: &[u32]| {
+            header.extend_from_slice(fourcc);
+            values
+                .iter()
+                .for_each(|value| header.extend_from_slice(&value.to_le_bytes()));
+        };
+
+        chunk(b"RIFF", &[HEADER_LENGTH as u32 - 8]);
+        chunk(b"AVI ", &[]);
+        chunk(b"LIST", &[192]);
+        chunk(b"hdrl", &[]);
+        chunk(
+            b"avih",
+            &[
+                56,
+                DEFAULT_FRAME_MICROS,
+                0,
+                0,
+                0,
+                0,
+                0,
+                1,
+                0,
+                width,
+                height,
+                0,
+                0,
+                0,
+                0,
+            ],
+        );
+        chunk(b"LIST", &[116]);
+        chunk(b"strl", &[]);
+        chunk(b"strh", &[56]);
+        chunk(b"vids", &[]);
+        chunk(
+            b"MJPG",
+            &[
+                0,
+                0,
+                0,
+                DEFAULT_FRAME_MICROS,
+                1_000_000,
+                0,
+                0,
+                0,
+                u32::MAX,
+                0,
+            ],
+        );
+        chunk(b"\0\0\0\0", &[width | (height << 16)]);
+        chunk(b"strf", &[40, 40, width, height, 1 | (24 << 16)]);
+        chunk(b"MJPG", &[width * height * 3, 0, 0, 0, 0]);
+        chunk(b"LIST", &[4]);
+        chunk(b"movi", &[]);
+        debug_assert_eq!(header.len() as u64, HEADER_LENGTH);
+
+        let mut avi = File::create(path)?;
+        avi.write_all(&header)?;
+
+        let mut timestamps = File::create(path.with_extension("csv"))?;
+        writeln!(timestamps, "frame,captured_at")?;
+
+        Ok(Self {
+            avi,
+            timestamps,
+            dimensions,
+            len: HEADER_LENGTH,
+            index: Vec::new(),
+            first_captured_at: None,
+            max_frame_size: 0,
+        })
+    }
+
+    fn write_frame(&mut self, jpeg: &[u8], captured_at: DateTime) -> io::Result<()> {
+        let size = jpeg.len() as u32;
+        let padding = jpeg.len() % 2;
+
+        self.avi.seek(SeekFrom::Start(self.len))?;
+        self.avi.write_all(b"00dc")?;
+        self.avi.write_all(&size.to_le_bytes())?;
+        self.avi.write_all(jpeg)?;
+        self.avi.write_all(&[0; 1][..padding])?;
+
+        self.index.push(((self.len - MOVI) as u32, size));
+        self.len += 8 + jpeg.len() as u64 + padding as u64;
+        self.max_frame_size = self.max_frame_size.max(size);
+
+        writeln!(
+            self.timestamps,
+            "{},{}",
+            self.index.len() - 1,
+            captured_at.to_rfc3339_opts(SecondsFormat::Micros, true)
+        )?;
+
+        let first_captured_at = *self.first_captured_at.get_or_insert(captured_at);
+        let frames = self.index.len() as u32;
+        let frame_micros = match frames {
+            1 => DEFAULT_FRAME_MICROS,
+            _ => ((captured_at - first_captured_at)
+                .num_microseconds()
+                .unwrap_or(0)
+                / i64::from(frames - 1))
+            .clamp(1, i64::from(u32::MAX)) as u32,
+        };
+        let bytes_per_sec =
+            u32::try_from(u64::from(self.max_frame_size) * 1_000_000 / u64::from(frame_micros))
+                .unwrap_or(u32::MAX);
+
+        self.patch(&[
+            (RIFF_SIZE, (self.len - 8) as u32),
+            (MOVI_SIZE, (self.len - MOVI) as u32),
+            (AVIH_MICROS_PER_FRAME, frame_micros),
+            (AVIH_MAX_BYTES_PER_SEC, bytes_per_sec),
+            (AVIH_TOTAL_FRAMES, frames),
+            (AVIH_SUGGESTED_BUFFER_SIZE, self.max_frame_size),
+            (STRH_SCALE, frame_micros),
+            (STRH_LENGTH, frames),
+            (STRH_SUGGESTED_BUFFER_SIZE, self.max_frame_size),
+        ])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let mut index = Vec::with_capacity(8 + self.index.len() * 16);
+        index.extend_from_slice(b"idx1");
+        index.extend_from_slice(&(self.index.len() as u32 * 16).to_le_bytes());
+        for (offset, size) in &self.index {
+            index.extend_from_slice(b"00dc");
+            index.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&size.to_le_bytes());
+        }
+
+        self.avi.seek(SeekFrom::Start(self.len))?;
+        self.avi.write_all(&index)?;
+        self.len += index.len() as u64;
+
+        self.patch(&[
+            (RIFF_SIZE, (self.len - 8) as u32),
+            (AVIH_FLAGS, AVIF_HASINDEX),
+        ])?;
+
+        self.avi.sync_all()?;
+        self.timestamps.sync_all()
+    }
+
+    fn patch(&mut self, fields: &[(u64, u32)]) -> io::Result<()> {
+        for (offset, value) in fields {
+            self.avi.seek(SeekFrom::Start(*offset))?;
+            self.avi.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the width and height from the frame header of a JPEG image.
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut position = 2;
+    loop {
+        let marker = *jpeg.get(position + 1)?;
+        if jpeg[position] != 0xff {
+            return None;
+        }
+
+        match marker {
+            // Fill bytes may precede any marker.
+            0xff => position += 1,
+            // Markers without a length.
+            0x01 | 0xd0..=0xd7 => position += 2,
+            // Start of frame, except for DHT, JPG and DAC which share the range.
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let header = jpeg.get(position + 5..position + 9)?;
+                let height = u16::from_be_bytes([header[0], header[1]]);
+                let width = u16::from_be_bytes([header[2], header[3]]);
+                return (width > 0 && height > 0).then_some((width, height));
+            }
+            _ => {
+                let length = jpeg.get(position + 2..position + 4)?;
+                position += 2 + usize::from(u16::from_be_bytes([length[0], length[1]]));
+            }
+        }
+    }
+}
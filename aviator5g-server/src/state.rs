@@ -0,0 +1,952 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Broker state shared between all connection tasks.
+//!
+//! All maps are sharded concurrent maps so that connections only contend when they touch the same
+//! shard. To rule out deadlocks, a guard into one map is never held while accessing another one.
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Instant,
+};
+
+use aviator5g_common::{
+    ClientType,
+    ControlMessage,
+    Id,
+    VideoFrame,
+};
+use dashmap::{
+    mapref::entry::Entry,
+    DashMap,
+};
+use futures_channel::mpsc::UnboundedSender;
+use tokio::sync::{
+    mpsc,
+    watch,
+    Notify,
+};
+use tungstenite::protocol::{
+    frame::coding::CloseCode,
+    CloseFrame,
+};
+
+use crate::{
+    analysis::{
+        FrameProcessor,
+        FrameProcessorFactory,
+    },
+    link::LinkStats,
+    media::{
+        FrameQueue,
+        LatestFrame,
+        StreamKey,
+    },
+    motion::MotionDetector,
+    rate_limit::{
+        RateLimitVerdict,
+        TokenBucket,
+        ViolationCounter,
+    },
+    ConnectionId,
+    ControlMessageKind,
+    Rx,
+    ServerConfig,
+    Tx,
+};
+
+/// Close frames carry at most 125 bytes of payload, two of which are the close code.
+const MAX_CLOSE_REASON_LENGTH: usize = 123;
+
+/// Instructs the task serving a connection's socket.
+pub enum ConnectionCommand {
+    /// Continue with the outbound queue of a resumed session.
+    Adopt(Rx),
+    /// Drop the socket, e.g. because it is half-open and its session has been resumed elsewhere.
+    Terminate,
+}
+
+type CommandTx = UnboundedSender<ConnectionCommand>;
+
+#[derive(Debug)]
+pub enum EvictionReason {
+    IdleTimeout,
+    IdentificationTimeout,
+}
+
+impl EvictionReason {
+    /// Code of the close frame with which the connection is evicted.
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Self::IdleTimeout => CloseCode::Away,
+            Self::IdentificationTimeout => CloseCode::Policy,
+        }
+    }
+
+    /// Reason given in the close frame.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::IdleTimeout => "Idle timeout",
+            Self::IdentificationTimeout => "Identification timeout",
+        }
+    }
+}
+
+pub struct ConnectionState {
+    pub tx: Tx,
+    pub group_id: Option<Id>,
+    pub id: Option<Id>,
+    pub client_type: Option<ClientType>,
+    commands: CommandTx,
+    /// Token with which the session of this connection can be resumed.
+    session: Option<String>,
+    /// Outbound queue kept while the client is disconnected and may still resume the session.
+    detached: Option<Rx>,
+    /// Connection that resumes this session once the task serving this one has ended.
+    handover_to: Option<ConnectionId>,
+    connected_at: Instant,
+    last_seen_at: Instant,
+    rate_limits: HashMap<ControlMessageKind, TokenBucket>,
+    rate_limit_violations: ViolationCounter,
+    pub link: LinkStats,
+    /// Video frames relayed to this connection, sent alongside the messages queued on `tx`.
+    pub frames: Arc<FrameQueue>,
+}
+
+impl ConnectionState {
+    fn new(tx: Tx, commands: CommandTx) -> Self {
+        let now = Instant::now();
+        Self {
+            tx,
+            group_id: None,
+            id: None,
+            client_type: None,
+            commands,
+            session: None,
+            detached: None,
+            handover_to: None,
+            connected_at: now,
+            last_seen_at: now,
+            rate_limits: HashMap::new(),
+            rate_limit_violations: ViolationCounter::new(now),
+            link: LinkStats::new(),
+            frames: FrameQueue::new(),
+        }
+    }
+
+    pub fn touch(&mut self) {
+        self.last_seen_at = Instant::now();
+    }
+
+    pub fn eviction_reason(&self, config: &ServerConfig, now: Instant) -> Option<EvictionReason> {
+        if now.duration_since(self.last_seen_at) > config.idle_timeout {
+            Some(EvictionReason::IdleTimeout)
+        } else if !self.is_identified()
+            && now.duration_since(self.connected_at) > config.identification_timeout
+        {
+            Some(EvictionReason::IdentificationTimeout)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_identified(&self) -> bool {
+        self.group_id.is_some() && self.id.is_some() && self.client_type.is_some()
+    }
+
+    fn identify(&mut self, group_id: Id, id: Id, client_type: ClientType) {
+        self.group_id = Some(group_id);
+        self.id = Some(id);
+        self.client_type = Some(client_type);
+    }
+
+    fn revoke_identity(&mut self) -> Option<String> {
+        self.group_id = None;
+        self.id = None;
+        self.client_type = None;
+        self.session.take()
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached.is_some()
+    }
+
+    pub fn send_control_message(&self, control_message: &ControlMessage) {
+        let _ = self.tx.unbounded_send(tungstenite::Message::Text(
+            aviator5g_common::build_control_message(control_message),
+        ));
+    }
+
+    /// Closes the connection gracefully once all queued messages have been sent.
+    pub fn close(&self, code: CloseCode, reason: &str) {
+        let mut end = reason.len().min(MAX_CLOSE_REASON_LENGTH);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let _ = self
+            .tx
+            .unbounded_send(tungstenite::Message::Close(Some(CloseFrame {
+                code,
+                reason: reason[..end].to_owned().into(),
+            })));
+        self.tx.close_channel();
+    }
+}
+
+struct GroupMember {
+    tx: Tx,
+    frames: Arc<FrameQueue>,
+    /// Video streams relayed to the member.
+    subscriptions: HashSet<StreamKey>,
+    id: Id,
+    client_type: ClientType,
+}
+
+/// Identifies a retained state message by its sender and its kind. A message replaces the
+/// previous one of its kind regardless of their targets, as it supersedes that state.
+type RetainedKey = (Id, ControlMessageKind);
+
+struct RetainedMessage {
+    sender_client_type: ClientType,
+    /// Member the message is addressed to, or `None` if it is addressed to the whole group.
+    target_id: Option<Id>,
+    message: tungstenite::Message,
+}
+
+#[derive(Default)]
+struct GroupState {
+    members: HashMap<ConnectionId, GroupMember>,
+    rate_limits: HashMap<ControlMessageKind, TokenBucket>,
+    /// Most recent state messages of the current members, delivered to members joining later.
+    retained: HashMap<RetainedKey, RetainedMessage>,
+}
+
+/// What happened to a connection whose socket has been closed.
+pub enum Disconnection {
+    Released {
+        /// Connection that waited to resume the released connection's session in vain.
+        abandoned_resumption: Option<ConnectionId>,
+    },
+    /// The session is kept so that the client can resume it.
+    Detached,
+    /// The session has been resumed by another connection.
+    HandedOver,
+}
+
+/// Outcome of claiming an id for a connection.
+pub enum IdClaim {
+    Claimed,
+    /// The id is held by another connection that keeps it.
+    Rejected(ConnectionId),
+    /// The id has been taken over from another connection, which has lost its identity.
+    Superseded(ConnectionId),
+}
+
+pub struct ServerState {
+    connections: DashMap<ConnectionId, ConnectionState>,
+    ids: DashMap<Id, ConnectionId>,
+    groups: DashMap<Id, GroupState>,
+    sessions: DashMap<String, ConnectionId>,
+    /// Latest frame of each stream and the group of the vehicle sending it.
+    streams: DashMap<StreamKey, (Id, watch::Sender<LatestFrame>)>,
+    /// Cloned into every recording, so that the receiver learns when all of them have finished.
+    recording_tx: Mutex<Option<mpsc::Sender<()>>>,
+    recording_rx: tokio::sync::Mutex<mpsc::Receiver<()>>,
+    frame_processors: Mutex<Vec<FrameProcessorFactory>>,
+    released: Notify,
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        let (recording_tx, recording_rx) = mpsc::channel(1);
+
+        Self {
+            connections: DashMap::new(),
+            ids: DashMap::new(),
+            groups: DashMap::new(),
+            sessions: DashMap::new(),
+            streams: DashMap::new(),
+            recording_tx: Mutex::new(Some(recording_tx)),
+            recording_rx: tokio::sync::Mutex::new(recording_rx),
+            frame_processors: Mutex::new(Vec::new()),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Waits until all connections have been released or the deadline has passed and returns
+    /// whether all connections have been released.
+    pub async fn wait_until_empty(&self, deadline: tokio::time::Instant) -> bool {
+        loop {
+            let released = self.released.notified();
+            if self.connections.is_empty() {
+                return true;
+            }
+
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return self.connections.is_empty();
+            }
+        }
+    }
+
+    pub fn for_each_connection(&self, mut f: impl FnMut(&ConnectionState)) {
+        self.connections.iter().for_each(|c| f(&c));
+    }
+
+    pub fn accept_connection(&self, connection_id: ConnectionId, tx: Tx, commands: CommandTx) {
+        self.connections
+            .insert(connection_id, ConnectionState::new(tx, commands));
+    }
+
+    pub fn release_connection(&self, connection_id: &ConnectionId) {
+        if let Some((_, connection)) = self.connections.remove(connection_id) {
+            if let Some(id) = connection.id {
+                if self.ids.remove_if(&id, |_, c| c == connection_id).is_some() {
+                    self.streams.retain(|(source_id, _), _| *source_id != id);
+                }
+            }
+
+            if let Some(session) = connection.session {
+                self.sessions.remove_if(&session, |_, c| c == connection_id);
+            }
+
+            if let Some(group_id) = connection.group_id {
+                self.leave_group(group_id, connection_id);
+            }
+
+            self.released.notify_waiters();
+        }
+    }
+
+    pub fn with_connection<R>(
+        &self,
+        connection_id: ConnectionId,
+        f: impl FnOnce(&ConnectionState) -> R,
+    ) -> Option<R> {
+        self.connections.get(&connection_id).map(|c| f(&c))
+    }
+
+    pub fn with_connection_mut<R>(
+        &self,
+        connection_id: ConnectionId,
+        f: impl FnOnce(&mut ConnectionState) -> R,
+    ) -> Option<R> {
+        self.connections
+            .get_mut(&connection_id)
+            .map(|mut c| f(&mut c))
+    }
+
+    /// Claims `id` for the connection at `connection_id`. When the id is already in use, `supersede`
+    /// decides whether the existing connection keeps it or loses it to the new one.
+    pub fn claim_id(&self, connection_id: ConnectionId, id: Id, supersede: bool) -> IdClaim {
+        let superseded = match self.ids.entry(id) {
+            Entry::Vacant(entry) => {
+                entry.insert(connection_id);
+                return IdClaim::Claimed;
+            }
+            Entry::Occupied(mut entry) => {
+                let existing = *entry.get();
+                if !supersede {
+                    return IdClaim::Rejected(existing);
+                }
+
+                entry.insert(connection_id);
+                existing
+            }
+        };
+
+        let revoked = self.with_connection_mut(superseded, |c| {
+            let group_id = c.group_id;
+            (group_id, c.revoke_identity())
+        });
+
+        if let Some((group_id, session)) = revoked {
+            if let Some(group_id) = group_id {
+                self.leave_group(group_id, &superseded);
+            }
+            if let Some(session) = session {
+                self.sessions.remove(&session);
+            }
+        }
+
+        IdClaim::Superseded(superseded)
+    }
+
+    /// Completes the identification of a connection whose id has been claimed and delivers the
+    /// state retained by the other members of its group.
+    pub fn identify(
+        &self,
+        connection_id: ConnectionId,
+        group_id: Id,
+        id: Id,
+        client_type: ClientType,
+    ) {
+        let (tx, frames) = self
+            .with_connection_mut(connection_id, |c| {
+                c.identify(group_id, id, client_type);
+                (c.tx.clone(), c.frames.clone())
+            })
+            .expect("Unknown connection");
+
+        let mut group = self.groups.entry(group_id).or_default();
+        group
+            .retained
+            .iter()
+            .filter(|((sender_id, _), retained)| {
+                *sender_id != id
+                    && retained.sender_client_type != client_type
+                    && retained.target_id.is_none_or(|target_id| target_id == id)
+            })
+            .for_each(|(_, retained)| {
+                let _ = tx.unbounded_send(retained.message.clone());
+            });
+
+        group.members.insert(
+            connection_id,
+            GroupMember {
+                tx,
+                frames,
+                subscriptions: HashSet::new(),
+                id,
+                client_type,
+            },
+        );
+    }
+
+    /// Releases the connection holding the id if its client has disconnected, so that a fresh
+    /// identification may claim the id regardless of the duplicate id policy.
+    pub fn release_detached_holder(&self, id: Id) {
+        let connection_id = match self.ids.get(&id) {
+            Some(connection_id) => *connection_id,
+            None => return,
+        };
+
+        if self.expire_detached(connection_id) {
+            log::info!("Released detached session of {} for {}", connection_id, id);
+        }
+    }
+
+    /// Issues a new resume token for an identified connection.
+    pub fn open_session(&self, connection_id: ConnectionId) -> String {
+        let token = Id::new_v4().to_simple().to_string();
+        let previous = self
+            .with_connection_mut(connection_id, |c| c.session.replace(token.clone()))
+            .expect("Unknown connection");
+
+        if let Some(previous) = previous {
+            self.sessions.remove(&previous);
+        }
+
+        self.sessions.insert(token.clone(), connection_id);
+        token
+    }
+
+    /// Resumes the session with the given token on the connection, which adopts the session's
+    /// identity and outbound queue and receives a new token. If the session's previous
+    /// connection is still open, it is terminated first and the resumption completes once it
+    /// has ended. Returns whether the session exists.
+    pub fn resume(&self, connection_id: ConnectionId, token: &str) -> bool {
+        let previous = match self.sessions.get(token) {
+            Some(previous) => *previous,
+            None => return false,
+        };
+
+        if previous == connection_id {
+            return false;
+        }
+
+        let detached = self.with_connection_mut(previous, |c| match c.detached.take() {
+            Some(rx) => Some(rx),
+            None => {
+                c.handover_to = Some(connection_id);
+                let _ = c.commands.unbounded_send(ConnectionCommand::Terminate);
+                None
+            }
+        });
+
+        match detached {
+            Some(Some(rx)) => self.hand_over(previous, connection_id, rx).is_ok(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Called once the socket of a connection has been closed, with the outbound queue of its
+    /// session. Identified connections that have not been closed by the server are detached so
+    /// that their clients can resume them.
+    pub fn disconnect(&self, connection_id: ConnectionId, rx: Rx) -> Disconnection {
+        let (resumable, handover_to) = match self.with_connection_mut(connection_id, |c| {
+            (
+                c.session.is_some() && !c.tx.is_closed(),
+                c.handover_to.take(),
+            )
+        }) {
+            Some(outcome) => outcome,
+            None => {
+                return Disconnection::Released {
+                    abandoned_resumption: None,
+                }
+            }
+        };
+
+        if !resumable {
+            self.release_connection(&connection_id);
+            return Disconnection::Released {
+                abandoned_resumption: handover_to,
+            };
+        }
+
+        let rx = match handover_to {
+            Some(next) => match self.hand_over(connection_id, next, rx) {
+                Ok(()) => return Disconnection::HandedOver,
+                Err(rx) => rx,
+            },
+            None => rx,
+        };
+
+        self.with_connection_mut(connection_id, |c| c.detached = Some(rx));
+        Disconnection::Detached
+    }
+
+    /// Releases the connection if its client has not resumed the session in the meantime.
+    pub fn expire_detached(&self, connection_id: ConnectionId) -> bool {
+        let detached = self
+            .with_connection(connection_id, |c| c.is_detached())
+            .unwrap_or(false);
+
+        if detached {
+            self.release_connection(&connection_id);
+        }
+
+        detached
+    }
+
+    /// Invalidates all resume tokens and releases connections waiting to be resumed, so that
+    /// connections closing from now on are released right away.
+    pub fn end_sessions(&self) {
+        self.sessions.clear();
+
+        let mut detached = Vec::new();
+        for mut connection in self.connections.iter_mut() {
+            connection.session = None;
+            if connection.is_detached() {
+                detached.push(*connection.key());
+            }
+        }
+
+        for connection_id in detached {
+            self.release_connection(&connection_id);
+        }
+    }
+
+    /// Moves the identity and outbound queue of `previous` to `next`. Senders keep using the
+    /// same queue throughout, so no message is lost or reordered.
+    fn hand_over(&self, previous: ConnectionId, next: ConnectionId, rx: Rx) -> Result<(), Rx> {
+        let previous_state = match self.connections.remove(&previous) {
+            Some((_, state)) => state,
+            None => return Err(rx),
+        };
+
+        let token = Id::new_v4().to_simple().to_string();
+        let mut handed_over = Some((previous_state, rx));
+        let identity = self.with_connection_mut(next, |c| {
+            let (previous_state, rx) = handed_over.take().unwrap();
+
+            c.send_control_message(&ControlMessage::Session(
+                aviator5g_common::SessionMessageData {
+                    resume_token: token.clone(),
+                    resumed: true,
+                },
+            ));
+            let _ = c.commands.unbounded_send(ConnectionCommand::Adopt(rx));
+
+            c.tx = previous_state.tx;
+            c.group_id = previous_state.group_id;
+            c.id = previous_state.id;
+            c.client_type = previous_state.client_type;
+            c.session = Some(token.clone());
+            c.rate_limits = previous_state.rate_limits;
+            c.rate_limit_violations = previous_state.rate_limit_violations;
+
+            (c.group_id, c.id, previous_state.session, c.frames.clone())
+        });
+
+        let (group_id, id, previous_session, frames) = match identity {
+            Some(identity) => identity,
+            None => {
+                let (previous_state, rx) = handed_over.take().unwrap();
+                self.connections.insert(previous, previous_state);
+                return Err(rx);
+            }
+        };
+
+        if let Some(id) = id {
+            if let Some(mut holder) = self.ids.get_mut(&id) {
+                if *holder == previous {
+                    *holder = next;
+                }
+            }
+        }
+
+        if let Some(group_id) = group_id {
+            if let Some(mut group) = self.groups.get_mut(&group_id) {
+                if let Some(mut member) = group.members.remove(&previous) {
+                    // Frames are sent by the task serving the new connection's socket.
+                    member.frames = frames;
+                    group.members.insert(next, member);
+                }
+            }
+        }
+
+        if let Some(previous_session) = previous_session {
+            self.sessions.remove(&previous_session);
+        }
+        self.sessions.insert(token, next);
+
+        log::info!("Session of {} has been resumed by {}", previous, next);
+        self.released.notify_waiters();
+        Ok(())
+    }
+
+    fn leave_group(&self, group_id: Id, connection_id: &ConnectionId) {
+        if let Some(mut group) = self.groups.get_mut(&group_id) {
+            if let Some(member) = group.members.remove(connection_id) {
+                group
+                    .retained
+                    .retain(|(sender_id, _), _| *sender_id != member.id);
+            }
+        }
+
+        self.groups
+            .remove_if(&group_id, |_, g| g.members.is_empty());
+    }
+
+    /// Starts or stops relaying the video stream to the connection, as long as it stays in its
+    /// group.
+    pub fn subscribe(&self, connection_id: ConnectionId, key: StreamKey, subscribe: bool) {
+        let group_id = match self.with_connection(connection_id, |c| c.group_id) {
+            Some(Some(group_id)) => group_id,
+            _ => return,
+        };
+
+        if let Some(mut group) = self.groups.get_mut(&group_id) {
+            if let Some(member) = group.members.get_mut(&connection_id) {
+                if subscribe {
+                    member.subscriptions.insert(key);
+                } else {
+                    member.subscriptions.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Relays a video frame to the members of the group subscribed to its stream and publishes it
+    /// to the HTTP clients watching the stream. New streams are analysed by the frame processors
+    /// and recorded if a directory is configured.
+    pub fn relay_frame(self: &Arc<Self>, group_id: Id, frame: VideoFrame, config: &ServerConfig) {
+        let key = (frame.source_id, frame.stream_id.clone());
+
+        if let Some(group) = self.groups.get(&group_id) {
+            let mut encoded = None;
+            group
+                .members
+                .values()
+                .filter(|member| member.subscriptions.contains(&key))
+                .for_each(|member| {
+                    let encoded = encoded.get_or_insert_with(|| {
+                        Arc::new(frame.encode().expect("Relayed frames have been decoded"))
+                    });
+                    member.frames.push(&key, encoded.clone());
+                });
+        }
+
+        let frame = Some(Arc::new(frame));
+        match self.streams.entry(key) {
+            Entry::Occupied(entry) => {
+                let _ = entry.get().1.send(frame);
+            }
+            Entry::Vacant(entry) => {
+                let (tx, rx) = watch::channel(frame);
+                let key = entry.key().clone();
+                entry.insert((group_id, tx));
+
+                let processors = self.frame_processors(&key, config);
+                if !processors.is_empty() {
+                    let server_state = self.clone();
+                    tokio::spawn(crate::analysis::analyze(
+                        rx.clone(),
+                        processors,
+                        move |message| server_state.send_to_group(group_id, message),
+                    ));
+                }
+
+                let recording_tx = self.recording_tx.lock().unwrap().clone();
+                if let Some((directory, recording_tx)) =
+                    config.recording_directory.clone().zip(recording_tx)
+                {
+                    tokio::spawn(async move {
+                        crate::recording::record(directory, key, rx).await;
+                        drop(recording_tx);
+                    });
+                }
+            }
+        }
+    }
+
+    /// Registers a factory that creates frame processors for streams started afterwards.
+    pub fn add_frame_processor(&self, factory: FrameProcessorFactory) {
+        self.frame_processors.lock().unwrap().push(factory);
+    }
+
+    fn frame_processors(
+        &self,
+        key: &StreamKey,
+        config: &ServerConfig,
+    ) -> Vec<Box<dyn FrameProcessor>> {
+        let mut processors = Vec::new();
+        if let Some(threshold) = config.motion_detection_threshold {
+            processors.push(Box::new(MotionDetector::new(threshold)) as Box<dyn FrameProcessor>);
+        }
+
+        let (vehicle_id, stream_id) = key;
+        processors.extend(
+            self.frame_processors
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|factory| factory(*vehicle_id, stream_id)),
+        );
+        processors
+    }
+
+    /// Ends all streams and waits for their recordings to be finished. No new recordings are
+    /// started afterwards.
+    pub async fn finish_recordings(&self) {
+        self.recording_tx.lock().unwrap().take();
+        self.streams.clear();
+
+        // Resolves once every recording has dropped its sender.
+        self.recording_rx.lock().await.recv().await;
+    }
+
+    /// Subscribes to the frames of a stream, if its vehicle has sent any and is in the group.
+    pub fn watch_stream(
+        &self,
+        group_id: Id,
+        key: &StreamKey,
+    ) -> Option<watch::Receiver<LatestFrame>> {
+        self.streams
+            .get(key)
+            .filter(|stream| stream.0 == group_id)
+            .map(|stream| stream.1.subscribe())
+    }
+
+    /// Sends the link quality of every connected member of each group to the group, so that
+    /// clients can tell which leg of the route through the server is slow.
+    pub fn report_link_quality(&self) {
+        let groups: Vec<Vec<(ConnectionId, Tx)>> = self
+            .groups
+            .iter()
+            .map(|group| {
+                group
+                    .members
+                    .iter()
+                    .map(|(connection_id, member)| (*connection_id, member.tx.clone()))
+                    .collect()
+            })
+            .collect();
+
+        for members in groups {
+            let mut links = Vec::with_capacity(members.len());
+            let mut recipients = Vec::with_capacity(members.len());
+            for (connection_id, tx) in members {
+                let report = self.with_connection(connection_id, |c| {
+                    let identity = c.id.zip(c.client_type).filter(|_| !c.is_detached());
+                    identity.map(|(id, client_type)| c.link.report(id, client_type))
+                });
+
+                if let Some(Some(report)) = report {
+                    links.push(report);
+                    recipients.push(tx);
+                }
+            }
+
+            if links.is_empty() {
+                continue;
+            }
+
+            let message = tungstenite::Message::Text(aviator5g_common::build_control_message(
+                &ControlMessage::LinkQuality(aviator5g_common::LinkQualityMessageData { links }),
+            ));
+            for tx in recipients {
+                let _ = tx.unbounded_send(message.clone());
+            }
+        }
+    }
+
+    /// Sends a message originating from the server to all members of the group.
+    pub fn send_to_group(&self, group_id: Id, control_message: &ControlMessage) {
+        let message =
+            tungstenite::Message::Text(aviator5g_common::build_control_message(control_message));
+
+        if let Some(group) = self.groups.get(&group_id) {
+            group.members.values().for_each(|member| {
+                let _ = member.tx.unbounded_send(message.clone());
+            });
+        }
+    }
+
+    /// Sends the message to all members of the group whose client type differs from the sender's.
+    pub fn forward_to_group(
+        &self,
+        group_id: Id,
+        sender_connection_id: ConnectionId,
+        sender_client_type: ClientType,
+        message: &tungstenite::Message,
+    ) {
+        if let Some(group) = self.groups.get(&group_id) {
+            group
+                .members
+                .iter()
+                .filter(|(connection_id, member)| {
+                    **connection_id != sender_connection_id
+                        && member.client_type != sender_client_type
+                })
+                .for_each(|(_, member)| {
+                    let _ = member.tx.unbounded_send(message.clone());
+                });
+        }
+    }
+
+    /// Replaces the state message of the given kind most recently sent by the member, unless the
+    /// member has left the group in the meantime.
+    pub fn retain_state(
+        &self,
+        group_id: Id,
+        sender_connection_id: ConnectionId,
+        kind: ControlMessageKind,
+        target_id: Option<Id>,
+        message: &tungstenite::Message,
+    ) {
+        if let Some(mut group) = self.groups.get_mut(&group_id) {
+            let sender = group
+                .members
+                .get(&sender_connection_id)
+                .map(|member| (member.id, member.client_type));
+
+            if let Some((sender_id, sender_client_type)) = sender {
+                group.retained.insert(
+                    (sender_id, kind),
+                    RetainedMessage {
+                        sender_client_type,
+                        target_id,
+                        message: message.clone(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Sends the message to the member of the group with the given id, if there is one.
+    pub fn send_to_member(&self, group_id: Id, target_id: Id, message: &tungstenite::Message) {
+        let target_connection_id = match self.ids.get(&target_id) {
+            Some(connection_id) => *connection_id,
+            None => return,
+        };
+
+        if let Some(group) = self.groups.get(&group_id) {
+            if let Some(member) = group.members.get(&target_connection_id) {
+                let _ = member.tx.unbounded_send(message.clone());
+            }
+        }
+    }
+
+    /// Sends the message to the member of the group with the given id if its client type differs
+    /// from the sender's and returns whether there is such a member.
+    pub fn forward_to_member(
+        &self,
+        group_id: Id,
+        sender_client_type: ClientType,
+        target_id: Id,
+        message: &tungstenite::Message,
+    ) -> bool {
+        let target_connection_id = match self.ids.get(&target_id) {
+            Some(connection_id) => *connection_id,
+            None => return false,
+        };
+
+        self.groups
+            .get(&group_id)
+            .and_then(|group| {
+                group
+                    .members
+                    .get(&target_connection_id)
+                    .filter(|member| member.client_type != sender_client_type)
+                    .map(|member| {
+                        let _ = member.tx.unbounded_send(message.clone());
+                    })
+            })
+            .is_some()
+    }
+
+    /// Takes a token from the connection's bucket and, once identified, from its group's bucket
+    /// for the given kind of message. Repeated violations of the connection's own limit escalate
+    /// to a disconnect, while an exhausted group bucket only rejects the message, as it may have
+    /// been emptied by another member.
+    pub fn check_rate_limit(
+        &self,
+        connection_id: ConnectionId,
+        kind: ControlMessageKind,
+        config: &ServerConfig,
+        now: Instant,
+    ) -> RateLimitVerdict {
+        let (allowed, group_id) = self
+            .with_connection_mut(connection_id, |c| {
+                let allowed = c
+                    .rate_limits
+                    .entry(kind)
+                    .or_insert_with(|| TokenBucket::new(&config.connection_rate_limit, now))
+                    .try_take(&config.connection_rate_limit, now);
+
+                (allowed, c.group_id)
+            })
+            .expect("Unknown connection");
+
+        if allowed {
+            let group_allowed = match group_id.and_then(|id| self.groups.get_mut(&id)) {
+                Some(mut group) => group
+                    .rate_limits
+                    .entry(kind)
+                    .or_insert_with(|| TokenBucket::new(&config.group_rate_limit, now))
+                    .try_take(&config.group_rate_limit, now),
+                None => true,
+            };
+
+            return if group_allowed {
+                RateLimitVerdict::Allow
+            } else {
+                RateLimitVerdict::Reject
+            };
+        }
+
+        self.with_connection_mut(connection_id, |c| {
+            c.rate_limit_violations
+                .record(config.max_rate_limit_violations, now)
+        })
+        .expect("Unknown connection")
+    }
+}
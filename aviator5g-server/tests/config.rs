@@ -0,0 +1,208 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+
+use aviator5g_server::{
+    ConfigError,
+    ConfigOverrides,
+    DuplicateIdPolicy,
+    ListenAddress,
+    RateLimit,
+    ServerConfig,
+};
+
+#[test]
+fn example_configuration_is_valid() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("aviator5g-server.example.toml");
+    let mut config = ServerConfig::default();
+
+    ConfigOverrides::from_file(&path)
+        .unwrap()
+        .apply(&mut config);
+
+    assert_eq!(config.listeners.len(), 4);
+    assert_eq!(
+        config.listeners[2].address,
+        ListenAddress::Unix("/run/aviator5g/server.sock".into())
+    );
+    assert!(config.listeners[3].tls.is_some());
+    assert_eq!(
+        config.http_listeners[0].address,
+        ListenAddress::Tcp("0.0.0.0:8554".into())
+    );
+    assert!(!config.allow_unknown_groups);
+    assert_eq!(config.groups.len(), 1);
+    assert_eq!(config.groups[0].members.len(), 2);
+    assert_eq!(config.failsafe_axes, Some(vec![0.0, 0.0, 0.0, 0.0]));
+    config.validate().unwrap();
+}
+
+#[test]
+fn unknown_settings_are_rejected() {
+    let e = toml::from_str::<ConfigOverrides>("prot = 9000").unwrap_err();
+
+    assert!(e.to_string().contains("unknown field `prot`"));
+}
+
+#[test]
+fn environment_overrides_settings() {
+    let env = HashMap::from([
+        (
+            "AVIATOR5G_LISTENERS",
+            "[::1]:9001, unix:/tmp/aviator5g.sock",
+        ),
+        ("AVIATOR5G_IDLE_TIMEOUT", "30"),
+        ("AVIATOR5G_DUPLICATE_ID_POLICY", "supersede"),
+        ("AVIATOR5G_FAILSAFE_AXES", "0, 0, 0, -1"),
+        ("AVIATOR5G_RECORDING_DIRECTORY", "/tmp/recordings"),
+        ("AVIATOR5G_MOTION_DETECTION_THRESHOLD", "0.1"),
+    ]);
+    let mut config = ServerConfig::default();
+
+    ConfigOverrides::from_env(|name| env.get(name).map(|v| v.to_string()))
+        .unwrap()
+        .apply(&mut config);
+
+    assert_eq!(
+        config
+            .listeners
+            .iter()
+            .map(|listener| listener.address.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            ListenAddress::Tcp("[::1]:9001".into()),
+            ListenAddress::Unix("/tmp/aviator5g.sock".into()),
+        ]
+    );
+    assert_eq!(config.idle_timeout, Duration::from_secs(30));
+    assert_eq!(config.duplicate_id_policy, DuplicateIdPolicy::Supersede);
+    assert_eq!(config.failsafe_axes, Some(vec![0.0, 0.0, 0.0, -1.0]));
+    assert_eq!(
+        config.recording_directory,
+        Some(PathBuf::from("/tmp/recordings"))
+    );
+    assert_eq!(config.motion_detection_threshold, Some(0.1));
+}
+
+#[test]
+fn invalid_environment_value_names_the_variable() {
+    let e = ConfigOverrides::from_env(|name| {
+        (name == "AVIATOR5G_IDLE_TIMEOUT").then(|| "ninety".to_string())
+    })
+    .unwrap_err();
+
+    assert!(matches!(
+        e,
+        ConfigError::EnvironmentError(ref name, _, _) if name == "AVIATOR5G_IDLE_TIMEOUT"
+    ));
+}
+
+#[test]
+fn listen_address_requires_host_and_port_or_socket_path() {
+    assert!("localhost".parse::<ListenAddress>().is_err());
+    assert!(":9000".parse::<ListenAddress>().is_err());
+    assert!("unix:".parse::<ListenAddress>().is_err());
+    assert_eq!(
+        "[::]:9000".parse::<ListenAddress>(),
+        Ok(ListenAddress::Tcp("[::]:9000".into()))
+    );
+}
+
+#[test]
+fn legacy_host_and_port_map_to_a_single_listen_address() {
+    assert_eq!(
+        ListenAddress::from_host_and_port(None, None),
+        ListenAddress::Tcp("localhost:9000".into())
+    );
+    assert_eq!(
+        ListenAddress::from_host_and_port(Some("0.0.0.0"), None),
+        ListenAddress::Tcp("0.0.0.0:9000".into())
+    );
+    assert_eq!(
+        ListenAddress::from_host_and_port(None, Some(9001)),
+        ListenAddress::Tcp("localhost:9001".into())
+    );
+    assert_eq!(
+        ListenAddress::from_host_and_port(Some("::"), Some(9001)),
+        ListenAddress::Tcp("[::]:9001".into())
+    );
+}
+
+#[test]
+fn group_members_must_be_identifiable() {
+    let config = toml::from_str::<ConfigOverrides>(
+        r#"
+        [[groups]]
+        id = "14ed4af8-5256-4e74-a5d6-545dfc0b004c"
+
+        [[groups.members]]
+        roles = ["pilot"]
+        "#,
+    )
+    .unwrap();
+    let mut server_config = ServerConfig::default();
+    config.apply(&mut server_config);
+
+    assert!(matches!(
+        server_config.validate(),
+        Err(ConfigError::ValidationError(_))
+    ));
+}
+
+#[test]
+fn idle_timeout_must_exceed_ping_interval() {
+    let config = ServerConfig {
+        ping_interval: Duration::from_secs(10),
+        idle_timeout: Duration::from_secs(5),
+        ..Default::default()
+    };
+
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::ValidationError(_))
+    ));
+}
+
+#[test]
+fn rate_limits_must_be_finite_and_positive() {
+    for (rate, burst) in [
+        (0.0, 10.0),
+        (f64::NAN, 10.0),
+        (f64::INFINITY, 10.0),
+        (10.0, 0.5),
+        (10.0, f64::NAN),
+        (10.0, f64::INFINITY),
+    ] {
+        let config = ServerConfig {
+            group_rate_limit: RateLimit { rate, burst },
+            ..Default::default()
+        };
+
+        assert!(
+            matches!(config.validate(), Err(ConfigError::ValidationError(_))),
+            "rate {} and burst {} were accepted",
+            rate,
+            burst
+        );
+    }
+
+    ServerConfig {
+        connection_rate_limit: RateLimit {
+            rate: 0.5,
+            burst: 1.0,
+        },
+        ..Default::default()
+    }
+    .validate()
+    .unwrap();
+}
@@ -0,0 +1,1872 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use aviator5g_common::{
+    AlertSeverity,
+    ClientType,
+    ClockSample,
+    ClockSync,
+    ClockSyncRequestMessageData,
+    ControlMessage,
+    ControlMessageData,
+    ErrorCode,
+    Id,
+    IdentificationMessageData,
+    IdentityConflictOutcome,
+    LatencyRequestMessageData,
+    LatencyResponseMessageData,
+    ResumeMessageData,
+    SessionMessageData,
+    StreamAdvertisementMessageData,
+    StreamSubscriptionMessageData,
+    TelemetryMessageData,
+    VideoFrame,
+};
+use aviator5g_server::{
+    Annotations,
+    DecodedFrame,
+    DuplicateIdPolicy,
+    FrameProcessor,
+    GroupConfig,
+    ListenAddress,
+    LocalAddress,
+    MemberConfig,
+    RateLimit,
+    Server,
+    ServerConfig,
+    ShutdownHandle,
+};
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+    },
+    net::{
+        TcpStream,
+        UnixStream,
+    },
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    MaybeTlsStream,
+    WebSocketStream,
+};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+const SILENCE_TIMEOUT: Duration = Duration::from_millis(200);
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        listeners: vec![ListenAddress::Tcp("127.0.0.1:0".into()).into()],
+        http_listeners: vec![ListenAddress::Tcp("127.0.0.1:0".into()).into()],
+        allow_unknown_groups: true,
+        resume_grace_period: Duration::ZERO,
+        link_report_interval: Duration::ZERO,
+        ..Default::default()
+    }
+}
+
+fn unix_socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!("aviator5g-test-{}.sock", Id::new_v4()))
+}
+
+struct TestServer {
+    address: SocketAddr,
+    http_address: Option<SocketAddr>,
+    local_addresses: Vec<LocalAddress>,
+    shutdown_handle: ShutdownHandle,
+    run_handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        Self::start_with_config(test_config()).await
+    }
+
+    async fn start_with_config(config: ServerConfig) -> Self {
+        Self::start_with_server(Server::bind(config).await.unwrap())
+    }
+
+    fn start_with_server(server: Server) -> Self {
+        let local_addresses = server.local_addresses();
+        let address = local_addresses
+            .iter()
+            .find_map(|address| match address {
+                LocalAddress::Tcp(address) => Some(*address),
+                LocalAddress::Unix(_) => None,
+            })
+            .expect("Test server requires a TCP listener");
+        let http_address = server
+            .http_local_addresses()
+            .into_iter()
+            .find_map(|address| match address {
+                LocalAddress::Tcp(address) => Some(address),
+                LocalAddress::Unix(_) => None,
+            });
+        let shutdown_handle = server.shutdown_handle();
+        let run_handle = tokio::spawn(server.run());
+
+        Self {
+            address,
+            http_address,
+            local_addresses,
+            shutdown_handle,
+            run_handle,
+        }
+    }
+
+    async fn connect(&self) -> TestClient {
+        let (ws_stream, _) = tokio_tungstenite::connect_async(format!("ws://{}", self.address))
+            .await
+            .unwrap();
+
+        TestClient { ws_stream }
+    }
+
+    async fn identified(&self, group_id: Id, client_type: ClientType) -> (Id, TestClient) {
+        let id = Id::new_v4();
+        let mut client = self.connect().await;
+        client.identify(id, group_id, client_type).await;
+
+        (id, client)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown_handle.shutdown();
+    }
+}
+
+struct TestClient<S = MaybeTlsStream<TcpStream>> {
+    ws_stream: WebSocketStream<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    async fn send(&mut self, control_message: &ControlMessage) {
+        self.send_text(aviator5g_common::build_control_message(control_message))
+            .await;
+    }
+
+    async fn send_text(&mut self, text: String) {
+        self.ws_stream
+            .send(tungstenite::Message::Text(text))
+            .await
+            .unwrap();
+    }
+
+    async fn identify(&mut self, id: Id, group_id: Id, client_type: ClientType) {
+        self.identify_with(IdentificationMessageData {
+            id,
+            group_id,
+            client_type,
+            credential: None,
+        })
+        .await;
+    }
+
+    async fn identify_with(&mut self, data: IdentificationMessageData) {
+        self.send(&ControlMessage::Identification(data)).await;
+
+        // Identification is not acknowledged, so give the server a moment to process it.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    async fn subscribe(&mut self, source_id: Id, stream_id: &str) {
+        self.send(&ControlMessage::Subscribe(StreamSubscriptionMessageData {
+            source_id,
+            stream_id: stream_id.into(),
+        }))
+        .await;
+
+        // Subscriptions are not acknowledged either.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    async fn receive_within(&mut self, timeout: Duration) -> Option<tungstenite::Message> {
+        // Pings do not extend the wait, as they may well arrive more often than the timeout.
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match tokio::time::timeout_at(deadline, self.ws_stream.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_)))) => {}
+                Ok(Some(Ok(message))) => return Some(message),
+                Ok(Some(Err(_)) | None) | Err(_) => return None,
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> ControlMessage {
+        match self.receive_within(RECEIVE_TIMEOUT).await {
+            Some(tungstenite::Message::Text(text)) => {
+                aviator5g_common::parse_control_message(&text).unwrap()
+            }
+            other => panic!("Expected control message, got {:?}", other),
+        }
+    }
+
+    async fn expect_silence(&mut self) {
+        if let Some(message) = self.receive_within(SILENCE_TIMEOUT).await {
+            panic!("Expected no message, got {:?}", message);
+        }
+    }
+
+    async fn send_frame(&mut self, frame: &VideoFrame) {
+        self.ws_stream
+            .send(tungstenite::Message::Binary(frame.encode().unwrap()))
+            .await
+            .unwrap();
+    }
+
+    async fn receive_frame(&mut self) -> VideoFrame {
+        match self.receive_within(RECEIVE_TIMEOUT).await {
+            Some(tungstenite::Message::Binary(bytes)) => VideoFrame::decode(&bytes).unwrap(),
+            other => panic!("Expected video frame, got {:?}", other),
+        }
+    }
+
+    async fn receive_session(&mut self) -> SessionMessageData {
+        match self.receive().await {
+            ControlMessage::Session(data) => data,
+            other => panic!("Expected session, got {:?}", other),
+        }
+    }
+
+    async fn resume(&mut self, resume_token: String) {
+        self.send(&ControlMessage::Resume(ResumeMessageData { resume_token }))
+            .await;
+    }
+
+    async fn expect_close(&mut self) -> Option<tungstenite::protocol::CloseFrame<'static>> {
+        match self.receive_within(RECEIVE_TIMEOUT).await {
+            Some(tungstenite::Message::Close(frame)) => frame,
+            other => panic!("Expected close frame, got {:?}", other),
+        }
+    }
+}
+
+fn video_frame(stream_id: &str, jpeg: &[u8]) -> VideoFrame {
+    VideoFrame {
+        source_id: Id::nil(),
+        stream_id: stream_id.into(),
+        captured_at: chrono::Utc::now(),
+        jpeg: jpeg.to_vec(),
+    }
+}
+
+/// Sends a GET request over a new connection and returns the response read so far once it
+/// contains `expected`, or everything until the connection has been closed.
+async fn http_get(address: SocketAddr, path: &str, expected: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 4096];
+    while !response
+        .windows(expected.len())
+        .any(|window| window == expected)
+    {
+        match tokio::time::timeout(RECEIVE_TIMEOUT, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(n)) => response.extend_from_slice(&buffer[..n]),
+        }
+    }
+
+    response
+}
+
+/// Encodes a grey image of the given size with a white square at the given position, if any.
+fn jpeg_image(width: u16, height: u16, square: Option<(usize, usize)>) -> Vec<u8> {
+    let (width_, height_) = (usize::from(width), usize::from(height));
+    let mut pixels = vec![128; width_ * height_];
+    if let Some((left, top)) = square {
+        for y in top..(top + 16).min(height_) {
+            pixels[y * width_ + left..y * width_ + (left + 16).min(width_)].fill(255);
+        }
+    }
+
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, 90)
+        .encode(&pixels, width, height, jpeg_encoder::ColorType::Luma)
+        .unwrap();
+    jpeg
+}
+
+fn control(axes: Vec<f64>) -> ControlMessage {
+    ControlMessage::Control(ControlMessageData {
+        axes,
+        target_id: None,
+    })
+}
+
+#[tokio::test]
+async fn server_binds_to_ephemeral_port() {
+    let server = TestServer::start().await;
+
+    assert_ne!(server.address.port(), 0);
+}
+
+#[tokio::test]
+async fn unix_socket_of_a_running_server_is_not_taken_over() {
+    let socket_path = unix_socket_path();
+    let config = ServerConfig {
+        listeners: vec![
+            ListenAddress::Tcp("127.0.0.1:0".into()).into(),
+            ListenAddress::Unix(socket_path.clone()).into(),
+        ],
+        ..test_config()
+    };
+    let _server = TestServer::start_with_config(config.clone()).await;
+
+    assert!(Server::bind(config).await.is_err());
+    assert!(UnixStream::connect(&socket_path).await.is_ok());
+}
+
+#[tokio::test]
+async fn stale_unix_socket_is_replaced() {
+    let socket_path = unix_socket_path();
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+
+    let _server = TestServer::start_with_config(ServerConfig {
+        listeners: vec![
+            ListenAddress::Tcp("127.0.0.1:0".into()).into(),
+            ListenAddress::Unix(socket_path.clone()).into(),
+        ],
+        ..test_config()
+    })
+    .await;
+
+    assert!(UnixStream::connect(&socket_path).await.is_ok());
+}
+
+#[tokio::test]
+async fn clients_on_different_listeners_share_groups() {
+    let socket_path = unix_socket_path();
+    let mut server = TestServer::start_with_config(ServerConfig {
+        listeners: vec![
+            ListenAddress::Tcp("127.0.0.1:0".into()).into(),
+            ListenAddress::Tcp("[::1]:0".into()).into(),
+            ListenAddress::Unix(socket_path.clone()).into(),
+        ],
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+
+    assert_eq!(server.local_addresses.len(), 3);
+    assert!(matches!(server.local_addresses[1], LocalAddress::Tcp(address) if address.is_ipv6()));
+    assert_eq!(
+        server.local_addresses[2],
+        LocalAddress::Unix(socket_path.clone())
+    );
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    let (ws_stream, _) =
+        tokio_tungstenite::connect_async(format!("ws://{}", server.local_addresses[1]))
+            .await
+            .unwrap();
+    let mut ipv6_vehicle = TestClient { ws_stream };
+    ipv6_vehicle
+        .identify(Id::new_v4(), group_id, ClientType::Vehicle)
+        .await;
+
+    let (ws_stream, _) = tokio_tungstenite::client_async(
+        "ws://localhost",
+        UnixStream::connect(&socket_path).await.unwrap(),
+    )
+    .await
+    .unwrap();
+    let mut unix_vehicle = TestClient { ws_stream };
+    unix_vehicle
+        .identify(Id::new_v4(), group_id, ClientType::Vehicle)
+        .await;
+
+    pilot.send(&control(vec![0.5, 0.0, 0.0, 0.0])).await;
+
+    match ipv6_vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.5, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+    match unix_vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.5, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+
+    drop((pilot, ipv6_vehicle, unix_vehicle));
+    server.shutdown_handle.shutdown();
+    tokio::time::timeout(RECEIVE_TIMEOUT, &mut server.run_handle)
+        .await
+        .expect("Server did not shut down in time")
+        .unwrap()
+        .unwrap();
+
+    assert!(!socket_path.exists());
+}
+
+#[tokio::test]
+async fn control_is_forwarded_to_other_client_types_in_group() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut foreign_vehicle) = server.identified(Id::new_v4(), ClientType::Vehicle).await;
+
+    pilot.send(&control(vec![0.1, 0.2, 0.3, 0.4])).await;
+
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.1, 0.2, 0.3, 0.4]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+
+    other_pilot.expect_silence().await;
+    foreign_vehicle.expect_silence().await;
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn targeted_control_is_routed_to_a_single_vehicle_in_group() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (target_id, mut target) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut other_vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (foreign_id, mut foreign_vehicle) =
+        server.identified(Id::new_v4(), ClientType::Vehicle).await;
+
+    pilot
+        .send(&ControlMessage::Control(ControlMessageData {
+            axes: vec![0.0, 0.0, 1.0, 0.0],
+            target_id: Some(target_id),
+        }))
+        .await;
+
+    match target.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.0, 0.0, 1.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+    other_vehicle.expect_silence().await;
+
+    for unknown_id in [foreign_id, Id::new_v4()] {
+        pilot
+            .send(&ControlMessage::Control(ControlMessageData {
+                axes: vec![0.0, 0.0, 0.0, 0.0],
+                target_id: Some(unknown_id),
+            }))
+            .await;
+
+        match pilot.receive().await {
+            ControlMessage::Error(data) => {
+                assert_eq!(data.code, ErrorCode::UnknownTarget);
+                assert!(!data.fatal);
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+    foreign_vehicle.expect_silence().await;
+}
+
+#[tokio::test]
+async fn late_joiners_receive_the_latest_state_of_present_members() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    pilot.send(&control(vec![0.1, 0.0, 0.0, 0.0])).await;
+    pilot.send(&control(vec![0.2, 0.0, 0.0, 0.0])).await;
+    pilot
+        .send(&ControlMessage::Control(ControlMessageData {
+            axes: vec![0.3, 0.0, 0.0, 0.0],
+            target_id: Some(Id::new_v4()),
+        }))
+        .await;
+    pilot.receive().await;
+
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.2, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+    vehicle.expect_silence().await;
+
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+    other_pilot.expect_silence().await;
+
+    drop(pilot);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (_, mut late_vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    late_vehicle.expect_silence().await;
+}
+
+#[tokio::test]
+async fn reconnecting_vehicle_receives_only_the_latest_control() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    pilot
+        .send(&ControlMessage::Control(ControlMessageData {
+            axes: vec![0.1, 0.0, 0.0, 0.0],
+            target_id: Some(vehicle_id),
+        }))
+        .await;
+    vehicle.receive().await;
+    pilot.send(&control(vec![0.2, 0.0, 0.0, 0.0])).await;
+    vehicle.receive().await;
+
+    drop(vehicle);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut vehicle = server.connect().await;
+    vehicle
+        .identify(vehicle_id, group_id, ClientType::Vehicle)
+        .await;
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.2, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+    vehicle.expect_silence().await;
+}
+
+#[tokio::test]
+async fn latency_round_trip_returns_to_initiator_only() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (pilot_id, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    let timestamp = chrono::Utc::now();
+    pilot
+        .send(&ControlMessage::LatencyRequest(LatencyRequestMessageData {
+            initiator_id: pilot_id,
+            timestamp,
+            target_id: None,
+            sent_at: Some(timestamp),
+        }))
+        .await;
+
+    let request = match vehicle.receive().await {
+        ControlMessage::LatencyRequest(data) => data,
+        other => panic!("Expected latency request, got {:?}", other),
+    };
+    assert_eq!(request.initiator_id, pilot_id);
+
+    vehicle
+        .send(&ControlMessage::LatencyResponse(
+            LatencyResponseMessageData {
+                initiator_id: request.initiator_id,
+                responder_id: vehicle_id,
+                timestamp: request.timestamp,
+                request_sent_at: request.sent_at,
+                request_received_at: Some(timestamp + chrono::Duration::milliseconds(20)),
+                sent_at: Some(timestamp + chrono::Duration::milliseconds(25)),
+            },
+        ))
+        .await;
+
+    match pilot.receive().await {
+        ControlMessage::LatencyResponse(data) => {
+            assert_eq!(data.responder_id, vehicle_id);
+            assert_eq!(data.timestamp, timestamp);
+            assert_eq!(
+                data.request_latency(),
+                Some(chrono::Duration::milliseconds(20))
+            );
+            assert_eq!(
+                data.response_latency(timestamp + chrono::Duration::milliseconds(40)),
+                Some(chrono::Duration::milliseconds(15))
+            );
+        }
+        other => panic!("Expected latency response, got {:?}", other),
+    }
+
+    other_pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn latency_responses_are_not_delivered_to_other_groups() {
+    let server = TestServer::start().await;
+
+    let (pilot_id, mut pilot) = server.identified(Id::new_v4(), ClientType::Pilot).await;
+    let (vehicle_id, mut vehicle) = server.identified(Id::new_v4(), ClientType::Vehicle).await;
+
+    let timestamp = chrono::Utc::now();
+    vehicle
+        .send(&ControlMessage::LatencyResponse(
+            LatencyResponseMessageData {
+                initiator_id: pilot_id,
+                responder_id: vehicle_id,
+                timestamp,
+                request_sent_at: None,
+                request_received_at: None,
+                sent_at: Some(timestamp),
+            },
+        ))
+        .await;
+
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn clock_sync_response_yields_offset_to_server_clock() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    let mut clock_sync = ClockSync::new();
+
+    for _ in 0..3 {
+        client
+            .send(&ControlMessage::ClockSyncRequest(
+                ClockSyncRequestMessageData {
+                    client_transmit: chrono::Utc::now(),
+                },
+            ))
+            .await;
+
+        let response = match client.receive().await {
+            ControlMessage::ClockSyncResponse(data) => data,
+            other => panic!("Expected clock sync response, got {:?}", other),
+        };
+        assert!(response.server_receive <= response.server_transmit);
+
+        let sample = ClockSample::from_response(&response, chrono::Utc::now());
+        assert!(sample.delay >= chrono::Duration::zero());
+        clock_sync.add(sample);
+    }
+
+    // Client and server share the same clock.
+    let offset = clock_sync.offset().unwrap();
+    assert!(offset.num_milliseconds().abs() < 100);
+}
+
+#[tokio::test]
+async fn video_frames_are_relayed_to_subscribed_pilots_and_observers_with_source_id() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut other_vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut observer) = server.identified(group_id, ClientType::Observer).await;
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+    pilot.subscribe(vehicle_id, "fpv").await;
+    observer.subscribe(vehicle_id, "fpv").await;
+    other_pilot.subscribe(vehicle_id, "survey").await;
+
+    let frame = video_frame("fpv", b"\xff\xd8jpeg\xff\xd9");
+    vehicle.send_frame(&frame).await;
+
+    for client in [&mut pilot, &mut observer] {
+        let received = client.receive_frame().await;
+        assert_eq!(received.source_id, vehicle_id);
+        assert_eq!(received.stream_id, "fpv");
+        assert_eq!(received.jpeg, frame.jpeg);
+    }
+
+    other_vehicle.expect_silence().await;
+    other_pilot.expect_silence().await;
+
+    pilot
+        .send(&ControlMessage::Unsubscribe(
+            StreamSubscriptionMessageData {
+                source_id: vehicle_id,
+                stream_id: "fpv".into(),
+            },
+        ))
+        .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    vehicle.send_frame(&frame).await;
+
+    observer.receive_frame().await;
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn stream_advertisements_are_forwarded_and_retained_for_late_joiners() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    let advertisement = |stream_ids: &[&str]| {
+        ControlMessage::StreamAdvertisement(StreamAdvertisementMessageData {
+            source_id: vehicle_id,
+            stream_ids: stream_ids.iter().map(|id| id.to_string()).collect(),
+        })
+    };
+    vehicle.send(&advertisement(&["fpv"])).await;
+    vehicle.send(&advertisement(&["fpv", "survey"])).await;
+
+    for expected in [vec!["fpv"], vec!["fpv", "survey"]] {
+        match pilot.receive().await {
+            ControlMessage::StreamAdvertisement(data) => {
+                assert_eq!(data.source_id, vehicle_id);
+                assert_eq!(data.stream_ids, expected);
+            }
+            other => panic!("Expected stream advertisement, got {:?}", other),
+        }
+    }
+
+    let (_, mut observer) = server.identified(group_id, ClientType::Observer).await;
+    match observer.receive().await {
+        ControlMessage::StreamAdvertisement(data) => {
+            assert_eq!(data.stream_ids, vec!["fpv", "survey"])
+        }
+        other => panic!("Expected stream advertisement, got {:?}", other),
+    }
+    observer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn streams_may_only_be_advertised_by_their_vehicle_and_watched_by_others() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut other_vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    let advertisement = ControlMessage::StreamAdvertisement(StreamAdvertisementMessageData {
+        source_id: vehicle_id,
+        stream_ids: vec!["fpv".into()],
+    });
+    for client in [&mut other_vehicle, &mut pilot] {
+        client.send(&advertisement).await;
+        match client.receive().await {
+            ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Forbidden),
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+
+    other_vehicle.subscribe(vehicle_id, "fpv").await;
+    match other_vehicle.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Forbidden),
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    vehicle.send_frame(&video_frame("fpv", b"jpeg")).await;
+    other_vehicle.expect_silence().await;
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn stream_advertisements_with_overlong_stream_ids_are_rejected() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    vehicle
+        .send(&ControlMessage::StreamAdvertisement(
+            StreamAdvertisementMessageData {
+                source_id: vehicle_id,
+                stream_ids: vec!["fpv".into(), "x".repeat(256)],
+            },
+        ))
+        .await;
+
+    match vehicle.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::Malformed);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn telemetry_of_a_vehicle_is_forwarded_and_retained_for_late_joiners() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    let telemetry = TelemetryMessageData {
+        source_id: vehicle_id,
+        armed: Some(true),
+        battery_voltage: Some(12.6),
+        latitude: Some(-35.363262),
+        longitude: Some(149.165237),
+        ..Default::default()
+    };
+    vehicle
+        .send(&ControlMessage::Telemetry(telemetry.clone()))
+        .await;
+
+    match pilot.receive().await {
+        ControlMessage::Telemetry(data) => assert_eq!(data, telemetry),
+        other => panic!("Expected telemetry, got {:?}", other),
+    }
+
+    pilot
+        .send(&ControlMessage::Telemetry(telemetry.clone()))
+        .await;
+    match pilot.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Forbidden),
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    let (_, mut observer) = server.identified(group_id, ClientType::Observer).await;
+    match observer.receive().await {
+        ControlMessage::Telemetry(data) => assert_eq!(data, telemetry),
+        other => panic!("Expected telemetry, got {:?}", other),
+    }
+    observer.expect_silence().await;
+    vehicle.expect_silence().await;
+}
+
+#[tokio::test]
+async fn video_frames_are_only_accepted_from_vehicles() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    pilot.send_frame(&video_frame("fpv", b"jpeg")).await;
+
+    match pilot.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Forbidden),
+        other => panic!("Expected error, got {:?}", other),
+    }
+    other_pilot.expect_silence().await;
+
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    vehicle
+        .ws_stream
+        .send(tungstenite::Message::Binary(vec![42; 8]))
+        .await
+        .unwrap();
+
+    match vehicle.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::Malformed);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn video_streams_are_served_as_multipart_over_http() {
+    let server = TestServer::start().await;
+    let http_address = server.http_address.unwrap();
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let path = format!("/streams/{}/fpv?group_id={}", vehicle_id, group_id);
+
+    let response = http_get(http_address, &path, b"\r\n\r\n").await;
+    assert!(response.starts_with(b"HTTP/1.1 404"));
+
+    vehicle.send_frame(&video_frame("fpv", b"first")).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let response = http_get(http_address, &path, b"first").await;
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("multipart/x-mixed-replace; boundary=frame"));
+    assert!(
+        response.contains("--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 5\r\n\r\nfirst")
+    );
+}
+
+#[tokio::test]
+async fn video_snapshots_are_served_over_http() {
+    let server = TestServer::start().await;
+    let http_address = server.http_address.unwrap();
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let path = format!("/snapshots/{}/fpv?group_id={}", vehicle_id, group_id);
+
+    let response = http_get(http_address, &path, b"\r\n\r\n").await;
+    assert!(response.starts_with(b"HTTP/1.1 404"));
+
+    let frame = video_frame("fpv", b"first");
+    vehicle.send_frame(&frame).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let response = http_get(http_address, &path, b"first").await;
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("content-type: image/jpeg"));
+    assert!(response.contains(&format!(
+        "x-captured-at: {}",
+        frame
+            .captured_at
+            .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+            .to_lowercase()
+    )));
+    assert!(response.ends_with("\r\n\r\nfirst"));
+
+    let path = format!("/snapshots/unknown/fpv?group_id={}", group_id);
+    let response = http_get(http_address, &path, b"\r\n\r\n").await;
+    assert!(response.starts_with(b"HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn video_streams_over_http_are_only_served_to_members_of_the_group() {
+    let group_id = Id::new_v4();
+    let vehicle_id = Id::new_v4();
+    let server = TestServer::start_with_config(ServerConfig {
+        allow_unknown_groups: false,
+        groups: vec![GroupConfig {
+            id: group_id,
+            name: None,
+            members: vec![
+                MemberConfig {
+                    id: Some(vehicle_id),
+                    credential: None,
+                    roles: vec![ClientType::Vehicle],
+                },
+                MemberConfig {
+                    id: None,
+                    credential: Some("secret".into()),
+                    roles: vec![ClientType::Observer],
+                },
+            ],
+        }],
+        ..test_config()
+    })
+    .await;
+    let http_address = server.http_address.unwrap();
+
+    let mut vehicle = server.connect().await;
+    vehicle
+        .identify(vehicle_id, group_id, ClientType::Vehicle)
+        .await;
+    vehicle.send_frame(&video_frame("fpv", b"first")).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    for (query, expected) in [
+        (String::new(), "401"),
+        (format!("?group_id={}", group_id), "403"),
+        (format!("?group_id={}&credential=wrong", group_id), "403"),
+        (
+            format!("?group_id={}&credential=secret", Id::new_v4()),
+            "403",
+        ),
+        ("?group_id=unknown".into(), "400"),
+    ] {
+        let path = format!("/snapshots/{}/fpv{}", vehicle_id, query);
+        let response = http_get(http_address, &path, b"\r\n\r\n").await;
+        assert!(
+            response.starts_with(format!("HTTP/1.1 {}", expected).as_bytes()),
+            "Expected {} for {}",
+            expected,
+            query
+        );
+    }
+
+    let path = format!(
+        "/snapshots/{}/fpv?group_id={}&credential=secret",
+        vehicle_id, group_id
+    );
+    let response = http_get(http_address, &path, b"first").await;
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+}
+
+#[tokio::test]
+async fn video_streams_over_http_are_not_served_to_other_groups() {
+    let server = TestServer::start().await;
+    let http_address = server.http_address.unwrap();
+    let (vehicle_id, mut vehicle) = server.identified(Id::new_v4(), ClientType::Vehicle).await;
+    vehicle.send_frame(&video_frame("fpv", b"first")).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    for resource in ["streams", "snapshots"] {
+        let path = format!("/{}/{}/fpv?group_id={}", resource, vehicle_id, Id::new_v4());
+        let response = http_get(http_address, &path, b"\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+    }
+}
+
+#[tokio::test]
+async fn video_streams_are_recorded_until_shutdown() {
+    let recording_directory = std::env::temp_dir().join(format!("aviator5g-test-{}", Id::new_v4()));
+    let mut server = TestServer::start_with_config(ServerConfig {
+        recording_directory: Some(recording_directory.clone()),
+        ..test_config()
+    })
+    .await;
+    let (vehicle_id, mut vehicle) = server.identified(Id::new_v4(), ClientType::Vehicle).await;
+
+    // Start of image followed by a baseline frame header of 32x16 pixels.
+    let jpeg = b"\xff\xd8\xff\xc0\x00\x0b\x08\x00\x10\x00\x20\x01\x01\x11\x00\xff\xd9";
+    for _ in 0..2 {
+        vehicle.send_frame(&video_frame("fpv/0", jpeg)).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    drop(vehicle);
+    server.shutdown_handle.shutdown();
+    tokio::time::timeout(RECEIVE_TIMEOUT, &mut server.run_handle)
+        .await
+        .expect("Server did not shut down in time")
+        .unwrap()
+        .unwrap();
+
+    let files = std::fs::read_dir(recording_directory.join(vehicle_id.to_string()))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    let avi_path = files
+        .iter()
+        .find(|path| path.extension().unwrap() == "avi")
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    assert!(avi_path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("fpv_0-"));
+
+    let avi = std::fs::read(avi_path).unwrap();
+    assert_eq!(&avi[0..4], b"RIFF");
+    assert_eq!(&avi[8..12], b"AVI ");
+    assert_eq!(
+        u32::from_le_bytes(avi[4..8].try_into().unwrap()) as usize,
+        avi.len() - 8
+    );
+    assert_eq!(u32::from_le_bytes(avi[48..52].try_into().unwrap()), 2);
+    assert_eq!(
+        avi.windows(jpeg.len())
+            .filter(|window| window == jpeg)
+            .count(),
+        2
+    );
+    assert_eq!(&avi[avi.len() - 40..avi.len() - 36], b"idx1");
+
+    let timestamps = std::fs::read_to_string(avi_path.with_extension("csv")).unwrap();
+    let lines = timestamps.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "frame,captured_at");
+    assert!(lines[1].starts_with("0,"));
+    assert!(lines[2].starts_with("1,"));
+
+    std::fs::remove_dir_all(recording_directory).unwrap();
+}
+
+#[tokio::test]
+async fn motion_is_reported_to_the_group() {
+    let server = TestServer::start_with_config(ServerConfig {
+        motion_detection_threshold: Some(0.05),
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    let still = jpeg_image(64, 64, None);
+    let moved = jpeg_image(64, 64, Some((16, 32)));
+    for jpeg in [&still, &still] {
+        vehicle.send_frame(&video_frame("fpv", jpeg)).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    vehicle.expect_silence().await;
+
+    let frame = video_frame("fpv", &moved);
+    vehicle.send_frame(&frame).await;
+
+    match vehicle.receive().await {
+        ControlMessage::Alert(data) => {
+            assert_eq!(data.source_id, vehicle_id);
+            assert_eq!(data.stream_id, "fpv");
+            assert_eq!(data.processor, "motion");
+            assert_eq!(data.severity, AlertSeverity::Warning);
+        }
+        other => panic!("Expected alert, got {:?}", other),
+    }
+    match vehicle.receive().await {
+        ControlMessage::Annotations(data) => {
+            // Frames carry their capture time with microsecond precision.
+            let captured_at = VideoFrame::decode(&frame.encode().unwrap())
+                .unwrap()
+                .captured_at;
+            assert_eq!(data.captured_at, captured_at);
+            assert_eq!(data.boxes.len(), 1);
+            let motion = &data.boxes[0];
+            assert_eq!(motion.label, "motion");
+            assert!((motion.x - 0.25).abs() < 0.2 && (motion.y - 0.5).abs() < 0.2);
+            assert!(motion.width <= 0.5 && motion.height <= 0.5);
+        }
+        other => panic!("Expected annotations, got {:?}", other),
+    }
+
+    // Once the frames stop changing, the previous annotations are cleared exactly once.
+    for _ in 0..2 {
+        vehicle.send_frame(&video_frame("fpv", &moved)).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    match vehicle.receive().await {
+        ControlMessage::Annotations(data) => assert!(data.boxes.is_empty()),
+        other => panic!("Expected annotations, got {:?}", other),
+    }
+    vehicle.expect_silence().await;
+}
+
+struct SizeLabeler;
+
+impl FrameProcessor for SizeLabeler {
+    fn name(&self) -> &str {
+        "size"
+    }
+
+    fn process(&mut self, frame: &DecodedFrame) -> Annotations {
+        Annotations {
+            labels: vec![aviator5g_common::Label {
+                text: format!("{}x{}", frame.width, frame.height),
+                confidence: Some(1.0),
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+#[tokio::test]
+async fn frame_processors_can_be_added_for_selected_streams() {
+    let server = Server::bind(test_config()).await.unwrap();
+    server.add_frame_processor(|_, stream_id| {
+        (stream_id == "analysed").then(|| Box::new(SizeLabeler) as Box<dyn FrameProcessor>)
+    });
+    let server = TestServer::start_with_server(server);
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    pilot.subscribe(vehicle_id, "analysed").await;
+
+    let jpeg = jpeg_image(48, 32, None);
+    vehicle.send_frame(&video_frame("ignored", &jpeg)).await;
+    vehicle.send_frame(&video_frame("analysed", &jpeg)).await;
+
+    assert_eq!(pilot.receive_frame().await.stream_id, "analysed");
+    match pilot.receive().await {
+        ControlMessage::Annotations(data) => {
+            assert_eq!(data.stream_id, "analysed");
+            assert_eq!(data.processor, "size");
+            assert_eq!(data.labels[0].text, "48x32");
+        }
+        other => panic!("Expected annotations, got {:?}", other),
+    }
+    pilot.expect_silence().await;
+}
+
+#[tokio::test]
+async fn unidentified_control_is_rejected_without_disconnect() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send(&control(vec![0.0, 0.0, 0.0, 0.0])).await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::NotIdentified);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    // The connection is still usable after a non-fatal error.
+    client
+        .identify(Id::new_v4(), Id::new_v4(), ClientType::Pilot)
+        .await;
+    client.expect_silence().await;
+}
+
+#[tokio::test]
+async fn malformed_message_is_reported() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send_text("{\"type\": \"nonsense\"}".into()).await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Malformed),
+        other => panic!("Expected error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn repeated_identification_is_rejected() {
+    let server = TestServer::start().await;
+    let (id, mut client) = server.identified(Id::new_v4(), ClientType::Vehicle).await;
+
+    client.identify(id, Id::new_v4(), ClientType::Vehicle).await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::AlreadyIdentified),
+        other => panic!("Expected error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn duplicate_identity_is_rejected() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+    let (id, mut existing) = server.identified(group_id, ClientType::Vehicle).await;
+
+    let mut newcomer = server.connect().await;
+    newcomer.identify(id, group_id, ClientType::Vehicle).await;
+
+    for client in [&mut existing, &mut newcomer] {
+        match client.receive().await {
+            ControlMessage::IdentityConflict(data) => {
+                assert_eq!(data.id, id);
+                assert_eq!(data.outcome, IdentityConflictOutcome::Rejected);
+            }
+            other => panic!("Expected identity conflict, got {:?}", other),
+        }
+    }
+
+    newcomer.expect_close().await;
+    existing.expect_silence().await;
+}
+
+#[tokio::test]
+async fn duplicate_identity_supersedes_existing_connection() {
+    let server = TestServer::start_with_config(ServerConfig {
+        duplicate_id_policy: DuplicateIdPolicy::Supersede,
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (pilot_id, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (id, mut existing) = server.identified(group_id, ClientType::Vehicle).await;
+
+    let mut newcomer = server.connect().await;
+    newcomer.identify(id, group_id, ClientType::Vehicle).await;
+
+    for client in [&mut existing, &mut newcomer] {
+        match client.receive().await {
+            ControlMessage::IdentityConflict(data) => {
+                assert_eq!(data.id, id);
+                assert_eq!(data.outcome, IdentityConflictOutcome::Superseded);
+            }
+            other => panic!("Expected identity conflict, got {:?}", other),
+        }
+    }
+
+    let frame = existing.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Policy
+    );
+
+    // Both messages to the group and messages to the id now reach the new connection.
+    pilot.send(&control(vec![0.25, 0.0, 0.0, 0.0])).await;
+    match newcomer.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.25, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control, got {:?}", other),
+    }
+
+    let timestamp = chrono::Utc::now();
+    pilot
+        .send(&ControlMessage::LatencyResponse(
+            LatencyResponseMessageData {
+                initiator_id: id,
+                responder_id: pilot_id,
+                timestamp,
+                request_sent_at: None,
+                request_received_at: None,
+                sent_at: Some(timestamp),
+            },
+        ))
+        .await;
+    match newcomer.receive().await {
+        ControlMessage::LatencyResponse(data) => assert_eq!(data.responder_id, pilot_id),
+        other => panic!("Expected latency response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn link_quality_of_each_member_is_reported_to_group() {
+    let server = TestServer::start_with_config(ServerConfig {
+        ping_interval: Duration::from_millis(50),
+        link_report_interval: Duration::from_millis(200),
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (vehicle_id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (pilot_id, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    // Both clients have to keep reading so that their pongs are sent.
+    for _ in 0..10 {
+        let (_, report) = tokio::join!(vehicle.receive(), pilot.receive());
+        let links = match report {
+            ControlMessage::LinkQuality(data) => data.links,
+            other => panic!("Expected link quality, got {:?}", other),
+        };
+
+        if links.len() == 2 && links.iter().all(|link| link.rtt_ms.is_some()) {
+            for (id, client_type) in [
+                (vehicle_id, ClientType::Vehicle),
+                (pilot_id, ClientType::Pilot),
+            ] {
+                let link = links.iter().find(|link| link.id == id).unwrap();
+                assert_eq!(link.client_type, client_type);
+                assert!(link.min_rtt_ms <= link.rtt_ms && link.rtt_ms <= link.max_rtt_ms);
+                assert!((0.0..=1.0).contains(&link.loss));
+            }
+            return;
+        }
+    }
+
+    panic!("No report contained the round-trip times of both members");
+}
+
+fn resumable_config(resume_grace_period: Duration) -> ServerConfig {
+    ServerConfig {
+        resume_grace_period,
+        ..test_config()
+    }
+}
+
+#[tokio::test]
+async fn resumed_session_reclaims_identity_and_queued_messages() {
+    let server = TestServer::start_with_config(resumable_config(Duration::from_secs(5))).await;
+    let group_id = Id::new_v4();
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+
+    let session = vehicle.receive_session().await;
+    assert!(!session.resumed);
+    pilot.receive_session().await;
+
+    drop(vehicle);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    pilot.send(&control(vec![0.25])).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut vehicle = server.connect().await;
+    vehicle.resume(session.resume_token.clone()).await;
+
+    let resumed = vehicle.receive_session().await;
+    assert!(resumed.resumed);
+    assert_ne!(resumed.resume_token, session.resume_token);
+
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.25]),
+        other => panic!("Expected queued control, got {:?}", other),
+    }
+
+    pilot.send(&control(vec![0.5])).await;
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.5]),
+        other => panic!("Expected control, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn resuming_closes_half_open_connection() {
+    let server = TestServer::start_with_config(resumable_config(Duration::from_secs(5))).await;
+    let group_id = Id::new_v4();
+    let (_, mut stale) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let session = stale.receive_session().await;
+    pilot.receive_session().await;
+
+    let mut vehicle = server.connect().await;
+    vehicle.resume(session.resume_token).await;
+
+    assert!(vehicle.receive_session().await.resumed);
+    assert!(stale.receive_within(RECEIVE_TIMEOUT).await.is_none());
+
+    pilot.send(&control(vec![1.0])).await;
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![1.0]),
+        other => panic!("Expected control, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn unknown_resume_token_is_rejected_without_disconnect() {
+    let server = TestServer::start_with_config(resumable_config(Duration::from_secs(5))).await;
+    let mut client = server.connect().await;
+
+    client.resume("unknown".into()).await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::SessionExpired);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    client
+        .identify(Id::new_v4(), Id::new_v4(), ClientType::Pilot)
+        .await;
+    assert!(!client.receive_session().await.resumed);
+}
+
+#[tokio::test]
+async fn session_expires_after_grace_period() {
+    let server = TestServer::start_with_config(resumable_config(Duration::from_millis(100))).await;
+    let group_id = Id::new_v4();
+    let (id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let session = vehicle.receive_session().await;
+
+    drop(vehicle);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let mut vehicle = server.connect().await;
+    vehicle.resume(session.resume_token).await;
+    match vehicle.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::SessionExpired),
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    vehicle.identify(id, group_id, ClientType::Vehicle).await;
+    assert!(!vehicle.receive_session().await.resumed);
+}
+
+#[tokio::test]
+async fn fresh_identification_replaces_detached_session() {
+    let server = TestServer::start_with_config(resumable_config(Duration::from_secs(5))).await;
+    let group_id = Id::new_v4();
+    let (id, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let session = vehicle.receive_session().await;
+
+    drop(vehicle);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut vehicle = server.connect().await;
+    vehicle.identify(id, group_id, ClientType::Vehicle).await;
+    assert!(!vehicle.receive_session().await.resumed);
+
+    let mut other = server.connect().await;
+    other.resume(session.resume_token).await;
+    match other.receive().await {
+        ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::SessionExpired),
+        other => panic!("Expected error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn unknown_group_is_rejected_by_default() {
+    let server = TestServer::start_with_config(ServerConfig {
+        allow_unknown_groups: false,
+        ..test_config()
+    })
+    .await;
+    let mut client = server.connect().await;
+
+    client
+        .identify(Id::new_v4(), Id::new_v4(), ClientType::Pilot)
+        .await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::Unauthorized);
+            assert!(data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    let frame = client.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Policy
+    );
+}
+
+#[tokio::test]
+async fn registered_group_admits_listed_members_in_their_roles_only() {
+    let group_id = Id::new_v4();
+    let vehicle_id = Id::new_v4();
+    let server = TestServer::start_with_config(ServerConfig {
+        allow_unknown_groups: false,
+        groups: vec![GroupConfig {
+            id: group_id,
+            name: Some("Test".into()),
+            members: vec![
+                MemberConfig {
+                    id: Some(vehicle_id),
+                    credential: None,
+                    roles: vec![ClientType::Vehicle],
+                },
+                MemberConfig {
+                    id: None,
+                    credential: Some("secret".into()),
+                    roles: vec![ClientType::Pilot, ClientType::Observer],
+                },
+            ],
+        }],
+        ..test_config()
+    })
+    .await;
+
+    let mut vehicle = server.connect().await;
+    vehicle
+        .identify(vehicle_id, group_id, ClientType::Vehicle)
+        .await;
+
+    let mut pilot = server.connect().await;
+    pilot
+        .identify_with(IdentificationMessageData {
+            id: Id::new_v4(),
+            group_id,
+            client_type: ClientType::Pilot,
+            credential: Some("secret".into()),
+        })
+        .await;
+
+    pilot.send(&control(vec![1.0, 0.0, 0.0, 0.0])).await;
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![1.0, 0.0, 0.0, 0.0]),
+        other => panic!("Expected control message, got {:?}", other),
+    }
+
+    for (client_type, credential) in [
+        (ClientType::Pilot, Some("wrong")),
+        (ClientType::Pilot, None),
+        (ClientType::Vehicle, Some("secret")),
+    ] {
+        let mut client = server.connect().await;
+        client
+            .identify_with(IdentificationMessageData {
+                id: Id::new_v4(),
+                group_id,
+                client_type,
+                credential: credential.map(Into::into),
+            })
+            .await;
+
+        match client.receive().await {
+            ControlMessage::Error(data) => assert_eq!(data.code, ErrorCode::Unauthorized),
+            other => panic!("Expected error, got {:?}", other),
+        }
+        client.expect_close().await;
+    }
+}
+
+#[tokio::test]
+async fn observer_receives_control_but_may_not_send_it() {
+    let server = TestServer::start().await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut observer) = server.identified(group_id, ClientType::Observer).await;
+
+    pilot.send(&control(vec![0.0, 1.0, 0.0, 0.0])).await;
+    for client in [&mut vehicle, &mut observer] {
+        match client.receive().await {
+            ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.0, 1.0, 0.0, 0.0]),
+            other => panic!("Expected control message, got {:?}", other),
+        }
+    }
+
+    observer.send(&control(vec![0.0, 0.0, 0.0, 0.0])).await;
+    match observer.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::Forbidden);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    vehicle.expect_silence().await;
+}
+
+#[tokio::test]
+async fn oversized_message_closes_connection() {
+    let server = TestServer::start_with_config(ServerConfig {
+        max_message_size: 1024,
+        ..test_config()
+    })
+    .await;
+    let mut client = server.connect().await;
+
+    client.send_text("x".repeat(2048)).await;
+
+    match client.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::MessageTooLarge);
+            assert!(data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    let frame = client.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Size
+    );
+}
+
+#[tokio::test]
+async fn unidentified_connection_is_closed_after_identification_timeout() {
+    let server = TestServer::start_with_config(ServerConfig {
+        ping_interval: Duration::from_millis(50),
+        identification_timeout: Duration::from_millis(200),
+        ..test_config()
+    })
+    .await;
+    let mut unidentified = server.connect().await;
+    let (_, mut identified) = server.identified(Id::new_v4(), ClientType::Pilot).await;
+
+    let frame = unidentified.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Policy
+    );
+
+    identified.expect_silence().await;
+}
+
+#[tokio::test]
+async fn connection_without_handshake_is_closed_after_identification_timeout() {
+    let server = TestServer::start_with_config(ServerConfig {
+        identification_timeout: Duration::from_millis(200),
+        ..test_config()
+    })
+    .await;
+    let mut stream = TcpStream::connect(server.address).await.unwrap();
+
+    let mut buffer = [0; 64];
+    let read = tokio::time::timeout(RECEIVE_TIMEOUT, stream.read(&mut buffer))
+        .await
+        .expect("Connection has not been closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
+
+#[tokio::test]
+async fn silent_connection_is_closed_after_idle_timeout() {
+    let server = TestServer::start_with_config(ServerConfig {
+        ping_interval: Duration::from_millis(50),
+        idle_timeout: Duration::from_millis(300),
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (_, mut silent) = server.identified(group_id, ClientType::Vehicle).await;
+    let (_, mut responsive) = server.identified(group_id, ClientType::Pilot).await;
+
+    // Pings are only answered while the socket is being read.
+    for _ in 0..3 {
+        responsive.expect_silence().await;
+    }
+
+    let frame = silent.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Away
+    );
+
+    responsive.send(&control(vec![0.0; 4])).await;
+    responsive.expect_silence().await;
+}
+
+#[tokio::test]
+async fn flooding_is_rejected_then_dropped_then_disconnected() {
+    let server = TestServer::start_with_config(ServerConfig {
+        connection_rate_limit: RateLimit {
+            rate: 0.001,
+            burst: 2.0,
+        },
+        max_rate_limit_violations: 3,
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    for _ in 0..2 {
+        pilot.send(&control(vec![0.0; 4])).await;
+        assert!(matches!(
+            vehicle.receive().await,
+            ControlMessage::Control(_)
+        ));
+    }
+
+    // The first violation is reported, further ones are dropped silently.
+    pilot.send(&control(vec![0.0; 4])).await;
+    match pilot.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::RateLimited);
+            assert!(!data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    for _ in 0..2 {
+        pilot.send(&control(vec![0.0; 4])).await;
+    }
+    pilot.expect_silence().await;
+    vehicle.expect_silence().await;
+
+    pilot.send(&control(vec![0.0; 4])).await;
+    match pilot.receive().await {
+        ControlMessage::Error(data) => {
+            assert_eq!(data.code, ErrorCode::RateLimited);
+            assert!(data.fatal);
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    let frame = pilot.expect_close().await.unwrap();
+    assert_eq!(
+        frame.code,
+        tungstenite::protocol::frame::coding::CloseCode::Policy
+    );
+}
+
+#[tokio::test]
+async fn rate_limits_apply_to_each_kind_of_message_separately() {
+    let server = TestServer::start_with_config(ServerConfig {
+        connection_rate_limit: RateLimit {
+            rate: 0.001,
+            burst: 1.0,
+        },
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (pilot_id, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    pilot.send(&control(vec![0.0; 4])).await;
+    assert!(matches!(
+        vehicle.receive().await,
+        ControlMessage::Control(_)
+    ));
+
+    let timestamp = chrono::Utc::now();
+    pilot
+        .send(&ControlMessage::LatencyRequest(LatencyRequestMessageData {
+            initiator_id: pilot_id,
+            timestamp,
+            target_id: None,
+            sent_at: Some(timestamp),
+        }))
+        .await;
+    assert!(matches!(
+        vehicle.receive().await,
+        ControlMessage::LatencyRequest(_)
+    ));
+}
+
+#[tokio::test]
+async fn group_rate_limit_is_shared_by_all_members() {
+    let server = TestServer::start_with_config(ServerConfig {
+        group_rate_limit: RateLimit {
+            rate: 0.001,
+            burst: 2.0,
+        },
+        max_rate_limit_violations: 3,
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut observer) = server.identified(group_id, ClientType::Observer).await;
+    let (_, mut other_pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let other_group_id = Id::new_v4();
+    let (_, mut other_group_pilot) = server.identified(other_group_id, ClientType::Pilot).await;
+    let (_, mut other_group_observer) = server
+        .identified(other_group_id, ClientType::Observer)
+        .await;
+
+    for client in [&mut pilot, &mut other_pilot] {
+        client.send(&control(vec![0.0; 4])).await;
+        assert!(matches!(
+            observer.receive().await,
+            ControlMessage::Control(_)
+        ));
+    }
+
+    // The pilot's own bucket is still full, but the group's is empty. As another member may have
+    // emptied it, the pilot is not disconnected however often it is refused.
+    for _ in 0..5 {
+        pilot.send(&control(vec![0.0; 4])).await;
+        match pilot.receive().await {
+            ControlMessage::Error(data) => {
+                assert_eq!(data.code, ErrorCode::RateLimited);
+                assert!(!data.fatal);
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+    observer.expect_silence().await;
+
+    pilot
+        .send(&ControlMessage::ClockSyncRequest(
+            ClockSyncRequestMessageData {
+                client_transmit: chrono::Utc::now(),
+            },
+        ))
+        .await;
+    assert!(matches!(
+        pilot.receive().await,
+        ControlMessage::ClockSyncResponse(_)
+    ));
+
+    other_group_pilot.send(&control(vec![0.0; 4])).await;
+    assert!(matches!(
+        other_group_observer.receive().await,
+        ControlMessage::Control(_)
+    ));
+}
+
+#[tokio::test]
+async fn shutdown_announces_failsafe_and_closes_remaining_connections() {
+    let mut server = TestServer::start_with_config(ServerConfig {
+        drain_timeout: Duration::from_millis(300),
+        failsafe_axes: Some(vec![0.0, 0.0, 0.0, -1.0]),
+        ..test_config()
+    })
+    .await;
+    let group_id = Id::new_v4();
+
+    let (_, mut pilot) = server.identified(group_id, ClientType::Pilot).await;
+    let (_, mut vehicle) = server.identified(group_id, ClientType::Vehicle).await;
+
+    server.shutdown_handle.shutdown();
+
+    match vehicle.receive().await {
+        ControlMessage::Control(data) => assert_eq!(data.axes, vec![0.0, 0.0, 0.0, -1.0]),
+        other => panic!("Expected failsafe control message, got {:?}", other),
+    }
+
+    for client in [&mut pilot, &mut vehicle] {
+        match client.receive().await {
+            ControlMessage::GoingAway(_) => {}
+            other => panic!("Expected going away message, got {:?}", other),
+        }
+
+        let frame = client.expect_close().await.unwrap();
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Away
+        );
+    }
+
+    tokio::time::timeout(RECEIVE_TIMEOUT, &mut server.run_handle)
+        .await
+        .expect("Server did not shut down in time")
+        .unwrap()
+        .unwrap();
+}
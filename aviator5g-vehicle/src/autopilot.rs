@@ -0,0 +1,698 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Flies the vehicle through an ArduPilot flight controller: control messages are translated into
+//! MAVLink RC channel overrides or manual control, and the flight controller's telemetry is
+//! relayed to the group.
+
+use std::{
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use aviator5g_common::{
+    ControlMessage,
+    ControlMessageData,
+    Id,
+    TelemetryMessageData,
+};
+use futures_channel::mpsc::UnboundedSender;
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::{
+        TcpStream,
+        UdpSocket,
+    },
+    sync::{
+        mpsc,
+        watch,
+    },
+    time::Instant,
+};
+
+use crate::{
+    channels,
+    mavlink::{
+        Frame,
+        Heartbeat,
+        ManualControl,
+        Message,
+        Parser,
+        RcChannelsOverride,
+        RequestDataStream,
+        MAV_AUTOPILOT_INVALID,
+        MAV_DATA_STREAM_ALL,
+        MAV_MODE_FLAG_SAFETY_ARMED,
+        MAV_TYPE_GCS,
+    },
+    serial,
+};
+
+/// System id under which the vehicle talks to the flight controller, the one conventionally used
+/// by ground control stations.
+const SYSTEM_ID: u8 = 255;
+const COMPONENT_ID: u8 = 190;
+
+/// Rate at which the flight controller is asked to send telemetry.
+const TELEMETRY_RATE_HZ: u16 = 4;
+
+/// Interval at which the latest telemetry is sent to the group, if it has changed.
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Interval at which heartbeats are sent, without which the flight controller considers the
+/// ground control station lost.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval at which the latest control is repeated, well within the time after which ArduPilot
+/// drops RC overrides.
+const CONTROL_INTERVAL: Duration = Duration::from_millis(200);
+
+const DEFAULT_BAUD_RATE: u32 = 57600;
+
+/// Where the flight controller is reached: `udp:<address>` to listen for it on a local address,
+/// `tcp:<address>` to connect to it, or `<path>[:<baud rate>]` for a serial port.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Udp(String),
+    Tcp(String),
+    Serial { path: PathBuf, baud_rate: u32 },
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("udp:") {
+            Ok(Self::Udp(address.into()))
+        } else if let Some(address) = s.strip_prefix("tcp:") {
+            Ok(Self::Tcp(address.into()))
+        } else if s.is_empty() {
+            Err("Missing MAVLink endpoint".into())
+        } else {
+            match s.rsplit_once(':') {
+                Some((path, baud_rate)) => Ok(Self::Serial {
+                    path: path.into(),
+                    baud_rate: baud_rate
+                        .parse()
+                        .map_err(|_| format!("Invalid baud rate '{}'", baud_rate))?,
+                }),
+                None => Ok(Self::Serial {
+                    path: s.into(),
+                    baud_rate: DEFAULT_BAUD_RATE,
+                }),
+            }
+        }
+    }
+}
+
+/// How control messages are sent to the flight controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMode {
+    /// Overrides the RC channels, as if the pilot were using an RC transmitter.
+    RcOverride,
+    /// Sends stick positions, as if the pilot were using a joystick with a ground control
+    /// station.
+    ManualControl,
+}
+
+impl FromStr for ControlMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rc-override" => Ok(Self::RcOverride),
+            "manual-control" => Ok(Self::ManualControl),
+            _ => Err(format!(
+                "Invalid control mode '{}', expected 'rc-override' or 'manual-control'",
+                s
+            )),
+        }
+    }
+}
+
+/// Axes of the latest control message, or `None` once control has been released.
+type Axes = Option<channels::Axes>;
+
+/// Connection to a flight controller, which is driven by background tasks until the vehicle
+/// exits.
+pub struct Autopilot {
+    axes: watch::Sender<Axes>,
+}
+
+impl std::fmt::Debug for Autopilot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Autopilot")
+            .field("axes", &*self.axes.borrow())
+            .finish()
+    }
+}
+
+impl Autopilot {
+    /// Opens the endpoint and starts talking to the first flight controller that sends a
+    /// heartbeat. Its telemetry is sent to the server through `outgoing`.
+    pub async fn connect(
+        endpoint: &Endpoint,
+        mode: ControlMode,
+        source_id: Id,
+        outgoing: UnboundedSender<tungstenite::Message>,
+    ) -> anyhow::Result<Self> {
+        let (write_tx, read_rx) = open(endpoint)
+            .await
+            .with_context(|| format!("Could not open MAVLink endpoint {:?}", endpoint))?;
+        let (axes, axes_rx) = watch::channel(None);
+
+        let link = Link {
+            write_tx,
+            sequence: 0,
+            target: None,
+        };
+        tokio::spawn(run(link, read_rx, axes_rx, mode, source_id, outgoing));
+
+        Ok(Self { axes })
+    }
+
+    pub fn update_from_control_message_data(&self, data: ControlMessageData) {
+        if let Some(axes) = channels::axes(data) {
+            let _ = self.axes.send(Some(axes));
+        }
+    }
+
+    /// Hands control back to the flight controller and its RC receiver.
+    pub fn release(&self) {
+        let _ = self.axes.send(None);
+    }
+}
+
+struct Link {
+    write_tx: mpsc::UnboundedSender<Vec<u8>>,
+    sequence: u8,
+    /// System and component id of the flight controller, once it has sent a heartbeat.
+    target: Option<(u8, u8)>,
+}
+
+impl Link {
+    fn send(&mut self, message: Message) {
+        let frame = Frame {
+            system_id: SYSTEM_ID,
+            component_id: COMPONENT_ID,
+            message,
+        };
+        let _ = self.write_tx.send(frame.encode(self.sequence));
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+
+    fn send_heartbeat(&mut self) {
+        self.send(Message::Heartbeat(Heartbeat {
+            custom_mode: 0,
+            mav_type: MAV_TYPE_GCS,
+            autopilot: MAV_AUTOPILOT_INVALID,
+            base_mode: 0,
+            system_status: 0,
+        }));
+    }
+
+    fn send_control(&mut self, mode: ControlMode, axes: Axes) {
+        let (target_system, target_component) = match self.target {
+            Some(target) => target,
+            None => return,
+        };
+
+        let message = match mode {
+            ControlMode::RcOverride => {
+                // Released channels are handed back to the RC receiver.
+                let mut overrides = [0; 8];
+                if let Some(axes) = axes {
+                    overrides[..4].copy_from_slice(&channels::pulses(axes));
+                }
+
+                Message::RcChannelsOverride(RcChannelsOverride {
+                    target_system,
+                    target_component,
+                    channels: overrides,
+                })
+            }
+            ControlMode::ManualControl => {
+                let [ailerons, elevator, rudder, throttle] = axes.unwrap_or([0.0; 4]);
+                let scale = |amount: f64| (amount.clamp(-1.0, 1.0) * 1000.0).round() as i16;
+
+                Message::ManualControl(ManualControl {
+                    target: target_system,
+                    x: scale(elevator),
+                    y: scale(ailerons),
+                    z: scale(throttle.max(0.0)),
+                    r: scale(rudder),
+                })
+            }
+        };
+
+        self.send(message);
+    }
+}
+
+async fn run(
+    mut link: Link,
+    mut read_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut axes: watch::Receiver<Axes>,
+    mode: ControlMode,
+    source_id: Id,
+    outgoing: UnboundedSender<tungstenite::Message>,
+) {
+    let mut parser = Parser::default();
+    let mut telemetry = TelemetryMessageData {
+        source_id,
+        ..Default::default()
+    };
+    let mut telemetry_changed = false;
+    let mut control_updated_at = Instant::now();
+
+    let mut heartbeat_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut control_interval = tokio::time::interval(CONTROL_INTERVAL);
+    let mut telemetry_interval = tokio::time::interval(TELEMETRY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            bytes = read_rx.recv() => {
+                let bytes = match bytes {
+                    Some(bytes) => bytes,
+                    None => {
+                        log::error!("Lost connection to the flight controller");
+                        break;
+                    }
+                };
+
+                parser.push(&bytes);
+                while let Some(frame) = parser.next_frame() {
+                    if link.target.is_none() {
+                        if let Message::Heartbeat(heartbeat) = frame.message {
+                            if heartbeat.autopilot != MAV_AUTOPILOT_INVALID {
+                                connect_target(&mut link, &frame);
+                            }
+                        }
+                    }
+
+                    if link.target == Some((frame.system_id, frame.component_id)) {
+                        telemetry_changed |= update_telemetry(&mut telemetry, frame.message);
+                    }
+                }
+            }
+            result = axes.changed() => {
+                if result.is_err() {
+                    break;
+                }
+                control_updated_at = Instant::now();
+                let axes = *axes.borrow();
+                link.send_control(mode, axes);
+            }
+            _ = control_interval.tick() => {
+                // Released control has been handed back once and is not repeated. Neither is stale
+                // control, so that ArduPilot's RC override timeout and failsafe take over.
+                let axes = *axes.borrow();
+                if axes.is_some() && control_updated_at.elapsed() < channels::CONTROL_TIMEOUT {
+                    link.send_control(mode, axes);
+                }
+            }
+            _ = heartbeat_interval.tick() => link.send_heartbeat(),
+            _ = telemetry_interval.tick(), if telemetry_changed => {
+                telemetry_changed = false;
+                let message = ControlMessage::Telemetry(telemetry.clone());
+                let result = outgoing.unbounded_send(tungstenite::Message::Text(
+                    aviator5g_common::build_control_message(&message),
+                ));
+
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn connect_target(link: &mut Link, frame: &Frame) {
+    log::info!(
+        "Connected to flight controller {}/{}",
+        frame.system_id,
+        frame.component_id
+    );
+
+    link.target = Some((frame.system_id, frame.component_id));
+    link.send(Message::RequestDataStream(RequestDataStream {
+        target_system: frame.system_id,
+        target_component: frame.component_id,
+        stream_id: MAV_DATA_STREAM_ALL,
+        rate: TELEMETRY_RATE_HZ,
+        start: true,
+    }));
+}
+
+/// Updates the telemetry from a message of the flight controller and returns whether it has
+/// changed.
+fn update_telemetry(telemetry: &mut TelemetryMessageData, message: Message) -> bool {
+    let previous = telemetry.clone();
+
+    match message {
+        Message::Heartbeat(m) => {
+            telemetry.armed = Some(m.base_mode & MAV_MODE_FLAG_SAFETY_ARMED != 0);
+            telemetry.mode = Some(m.custom_mode);
+        }
+        Message::SysStatus(m) => {
+            telemetry.battery_voltage =
+                (m.voltage_battery != u16::MAX).then(|| f64::from(m.voltage_battery) / 1000.0);
+            telemetry.battery_remaining =
+                (m.battery_remaining >= 0).then(|| f64::from(m.battery_remaining) / 100.0);
+        }
+        Message::Attitude(m) => {
+            telemetry.roll = Some(f64::from(m.roll));
+            telemetry.pitch = Some(f64::from(m.pitch));
+            telemetry.yaw = Some(f64::from(m.yaw));
+        }
+        Message::GlobalPositionInt(m) => {
+            telemetry.latitude = Some(f64::from(m.lat) / 1e7);
+            telemetry.longitude = Some(f64::from(m.lon) / 1e7);
+            telemetry.altitude = Some(f64::from(m.alt) / 1000.0);
+            telemetry.relative_altitude = Some(f64::from(m.relative_alt) / 1000.0);
+            telemetry.heading = (m.hdg != u16::MAX).then(|| f64::from(m.hdg) / 100.0);
+        }
+        Message::VfrHud(m) => {
+            telemetry.airspeed = Some(f64::from(m.airspeed));
+            telemetry.groundspeed = Some(f64::from(m.groundspeed));
+            telemetry.climb_rate = Some(f64::from(m.climb));
+        }
+        _ => {}
+    }
+
+    *telemetry != previous
+}
+
+/// Opens the endpoint and returns a channel for bytes to write to it and a channel of bytes read
+/// from it.
+async fn open(
+    endpoint: &Endpoint,
+) -> io::Result<(
+    mpsc::UnboundedSender<Vec<u8>>,
+    mpsc::UnboundedReceiver<Vec<u8>>,
+)> {
+    let (write_tx, mut write_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (read_tx, read_rx) = mpsc::unbounded_channel();
+
+    match endpoint {
+        Endpoint::Udp(address) => {
+            let socket = Arc::new(UdpSocket::bind(address).await?);
+            // Replies go to wherever the flight controller has last sent from.
+            let peer = Arc::new(Mutex::new(None::<SocketAddr>));
+
+            tokio::spawn({
+                let (socket, peer) = (socket.clone(), peer.clone());
+                async move {
+                    let mut buffer = vec![0; 65536];
+                    while let Ok((length, address)) = socket.recv_from(&mut buffer).await {
+                        *peer.lock().unwrap() = Some(address);
+                        if read_tx.send(buffer[..length].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+
+            tokio::spawn(async move {
+                while let Some(bytes) = write_rx.recv().await {
+                    let peer = *peer.lock().unwrap();
+                    if let Some(peer) = peer {
+                        if let Err(e) = socket.send_to(&bytes, peer).await {
+                            log::warn!("Could not send to the flight controller: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+        Endpoint::Tcp(address) => {
+            let (mut reader, mut writer) = TcpStream::connect(address).await?.into_split();
+
+            tokio::spawn(async move {
+                let mut buffer = vec![0; 4096];
+                while let Ok(length @ 1..) = reader.read(&mut buffer).await {
+                    if read_tx.send(buffer[..length].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            tokio::spawn(async move {
+                while let Some(bytes) = write_rx.recv().await {
+                    if writer.write_all(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Endpoint::Serial { path, baud_rate } => {
+            let mut reader = serial::open(path, *baud_rate, serial::Framing::Standard)?;
+            let mut writer = reader.try_clone()?;
+
+            std::thread::Builder::new()
+                .name("mavlink-read".into())
+                .spawn(move || {
+                    let mut buffer = vec![0; 4096];
+                    while let Ok(length @ 1..) = reader.read(&mut buffer) {
+                        if read_tx.send(buffer[..length].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+
+            std::thread::Builder::new()
+                .name("mavlink-write".into())
+                .spawn(move || {
+                    while let Some(bytes) = write_rx.blocking_recv() {
+                        if writer.write_all(&bytes).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+        }
+    }
+
+    Ok((write_tx, read_rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use aviator5g_common::ControlMessageData;
+
+    use super::*;
+    use crate::mavlink::{
+        GlobalPositionInt,
+        SysStatus,
+    };
+
+    fn link() -> (Link, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (write_tx, write_rx) = mpsc::unbounded_channel();
+        let link = Link {
+            write_tx,
+            sequence: 0,
+            target: Some((1, 1)),
+        };
+
+        (link, write_rx)
+    }
+
+    fn axes(axes: Vec<f64>) -> Axes {
+        channels::axes(ControlMessageData {
+            axes,
+            target_id: None,
+        })
+    }
+
+    fn sent(write_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<Message> {
+        let mut parser = Parser::default();
+        while let Ok(bytes) = write_rx.try_recv() {
+            parser.push(&bytes);
+        }
+
+        std::iter::from_fn(|| parser.next_frame())
+            .map(|frame| frame.message)
+            .collect()
+    }
+
+    #[test]
+    fn control_is_sent_as_aetr_rc_channel_overrides() {
+        let (mut link, mut write_rx) = link();
+
+        link.send_control(ControlMode::RcOverride, axes(vec![-1.0, 1.0, 0.5, 0.75]));
+        link.send_control(ControlMode::RcOverride, None);
+
+        assert_eq!(
+            sent(&mut write_rx),
+            [
+                Message::RcChannelsOverride(RcChannelsOverride {
+                    target_system: 1,
+                    target_component: 1,
+                    channels: [1000, 2000, 1750, 1750, 0, 0, 0, 0],
+                }),
+                Message::RcChannelsOverride(RcChannelsOverride {
+                    target_system: 1,
+                    target_component: 1,
+                    channels: [0; 8],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn control_is_sent_as_manual_control() {
+        let (mut link, mut write_rx) = link();
+
+        link.send_control(
+            ControlMode::ManualControl,
+            axes(vec![0.5, -1.0, 0.25, -0.5]),
+        );
+        link.send_control(ControlMode::ManualControl, None);
+
+        assert_eq!(
+            sent(&mut write_rx),
+            [
+                Message::ManualControl(ManualControl {
+                    target: 1,
+                    x: -1000,
+                    y: 500,
+                    z: 0,
+                    r: 250,
+                }),
+                Message::ManualControl(ManualControl {
+                    target: 1,
+                    x: 0,
+                    y: 0,
+                    z: 0,
+                    r: 0,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn control_waits_for_the_flight_controller() {
+        let (mut link, mut write_rx) = link();
+        link.target = None;
+
+        link.send_control(ControlMode::RcOverride, axes(vec![0.0; 4]));
+
+        assert!(sent(&mut write_rx).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn control_is_no_longer_repeated_once_it_is_stale() {
+        let (link, mut write_rx) = link();
+        let (_read_tx, read_rx) = mpsc::unbounded_channel();
+        let (axes_tx, axes_rx) = watch::channel(None);
+        let (outgoing, _outgoing_rx) = futures_channel::mpsc::unbounded();
+        tokio::spawn(run(
+            link,
+            read_rx,
+            axes_rx,
+            ControlMode::RcOverride,
+            Id::nil(),
+            outgoing,
+        ));
+
+        let overrides = |write_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>| {
+            sent(write_rx)
+                .into_iter()
+                .filter(|message| matches!(message, Message::RcChannelsOverride(_)))
+                .count()
+        };
+
+        axes_tx.send(axes(vec![0.0; 4])).unwrap();
+        tokio::time::sleep(channels::CONTROL_TIMEOUT / 2).await;
+        assert!(overrides(&mut write_rx) >= 2);
+
+        tokio::time::sleep(channels::CONTROL_TIMEOUT).await;
+        overrides(&mut write_rx);
+        tokio::time::sleep(channels::CONTROL_TIMEOUT).await;
+        assert_eq!(overrides(&mut write_rx), 0);
+
+        axes_tx.send(axes(vec![0.0; 4])).unwrap();
+        tokio::time::sleep(channels::CONTROL_TIMEOUT / 2).await;
+        assert!(overrides(&mut write_rx) >= 2);
+    }
+
+    #[test]
+    fn telemetry_is_converted_to_si_units() {
+        let mut telemetry = TelemetryMessageData::default();
+
+        assert!(update_telemetry(
+            &mut telemetry,
+            Message::SysStatus(SysStatus {
+                voltage_battery: 12600,
+                battery_remaining: -1,
+            })
+        ));
+        assert!(update_telemetry(
+            &mut telemetry,
+            Message::GlobalPositionInt(GlobalPositionInt {
+                lat: -350_000_000,
+                lon: 1_490_000_000,
+                alt: 584_000,
+                relative_alt: 1_500,
+                hdg: 9_000,
+            })
+        ));
+        assert!(!update_telemetry(
+            &mut telemetry,
+            Message::SysStatus(SysStatus {
+                voltage_battery: 12600,
+                battery_remaining: -1,
+            })
+        ));
+
+        assert_eq!(telemetry.battery_voltage, Some(12.6));
+        assert_eq!(telemetry.battery_remaining, None);
+        assert_eq!(telemetry.latitude, Some(-35.0));
+        assert_eq!(telemetry.longitude, Some(149.0));
+        assert_eq!(telemetry.altitude, Some(584.0));
+        assert_eq!(telemetry.relative_altitude, Some(1.5));
+        assert_eq!(telemetry.heading, Some(90.0));
+    }
+
+    #[test]
+    fn endpoints_are_parsed() {
+        assert_eq!(
+            "udp:0.0.0.0:14550".parse(),
+            Ok(Endpoint::Udp("0.0.0.0:14550".into()))
+        );
+        assert_eq!(
+            "tcp:127.0.0.1:5760".parse(),
+            Ok(Endpoint::Tcp("127.0.0.1:5760".into()))
+        );
+        assert_eq!(
+            "/dev/ttyAMA0:115200".parse(),
+            Ok(Endpoint::Serial {
+                path: "/dev/ttyAMA0".into(),
+                baud_rate: 115200,
+            })
+        );
+        assert_eq!(
+            "/dev/ttyACM0".parse(),
+            Ok(Endpoint::Serial {
+                path: "/dev/ttyACM0".into(),
+                baud_rate: DEFAULT_BAUD_RATE,
+            })
+        );
+        assert!("/dev/ttyAMA0:fast".parse::<Endpoint>().is_err());
+    }
+}
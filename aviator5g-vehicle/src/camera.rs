@@ -0,0 +1,718 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Captures camera frames and encodes them as JPEG on a dedicated thread, so that neither capture
+//! nor encoding ever holds up the handling of control messages.
+
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::Context;
+use aviator5g_common::DateTime;
+use jpeg_encoder::{
+    ColorType,
+    Encoder,
+};
+use tokio::sync::watch;
+
+const TEST_PATTERN: &str = "test-pattern";
+const FILE_PREFIX: &str = "file:";
+
+/// Number of frames in a row that may fail to be captured or encoded before the camera is given
+/// up on, so that a corrupted frame now and then does not end the capture.
+const MAX_CONSECUTIVE_FAILURES: u32 = 30;
+
+/// Number of buffers a V4L2 device captures into. The driver only fills the single buffer once
+/// the previous frame has been handed back, so frames never queue up while the capture is
+/// throttled below the camera's frame rate and each one is as recent as possible.
+#[cfg(feature = "v4l2")]
+const DEVICE_BUFFERS: u32 = 1;
+
+/// Where frames are captured from: a V4L2 device such as `/dev/video0`, a JPEG image given as
+/// `file:<path>` that is sent over and over, or a moving `test-pattern`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraSource {
+    Device(PathBuf),
+    File(PathBuf),
+    TestPattern,
+}
+
+impl FromStr for CameraSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == TEST_PATTERN {
+            Ok(Self::TestPattern)
+        } else if let Some(path) = s.strip_prefix(FILE_PREFIX) {
+            Ok(Self::File(path.into()))
+        } else if s.is_empty() {
+            Err("Missing camera source".into())
+        } else {
+            Ok(Self::Device(s.into()))
+        }
+    }
+}
+
+/// A camera given as `[<stream id>=]<source>`, streamed under the given id or a default one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    pub stream_id: Option<String>,
+    pub source: CameraSource,
+}
+
+impl FromStr for Camera {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Paths may contain '=' as well, but stream ids contain neither '/' nor ':'.
+        match s.split_once('=') {
+            Some((stream_id, source))
+                if !stream_id.is_empty() && !stream_id.contains(['/', ':']) =>
+            {
+                Ok(Self {
+                    stream_id: Some(stream_id.into()),
+                    source: source.parse()?,
+                })
+            }
+            _ => Ok(Self {
+                stream_id: None,
+                source: s.parse()?,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid resolution '{}', expected e.g. '640x480'", s);
+        let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+        let resolution = Self {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+        };
+
+        if resolution.width == 0 || resolution.height == 0 {
+            return Err(invalid());
+        }
+
+        Ok(resolution)
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// How frames are encoded, which may change while capturing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodingSettings {
+    pub resolution: Resolution,
+    /// JPEG quality between 1 and 100.
+    pub quality: u8,
+    pub frame_rate: u32,
+}
+
+/// A JPEG-encoded frame along with the local time at which it has been captured.
+#[derive(Debug)]
+pub struct EncodedFrame {
+    pub captured_at: DateTime,
+    pub jpeg: Vec<u8>,
+}
+
+/// The latest encoded frame, replaced by each new frame whether or not it has been sent.
+pub type LatestFrame = Option<Arc<EncodedFrame>>;
+
+/// Opens the camera and starts capturing on a dedicated thread. The camera captures at the
+/// initial settings, which are the highest the settings may ever be changed to. The thread stops
+/// once the receiver has been dropped or the camera keeps failing.
+pub fn start(
+    source: &CameraSource,
+    settings: watch::Receiver<EncodingSettings>,
+) -> anyhow::Result<watch::Receiver<LatestFrame>> {
+    let mut source = open(source, &settings.borrow())?;
+    let (tx, rx) = watch::channel(None);
+
+    std::thread::Builder::new()
+        .name("camera".into())
+        .spawn(move || {
+            if let Err(e) = capture(source.as_mut(), &settings, &tx) {
+                log::error!("Camera failed: {:#}", e);
+            }
+        })?;
+
+    Ok(rx)
+}
+
+fn capture(
+    source: &mut dyn FrameSource,
+    settings: &watch::Receiver<EncodingSettings>,
+    tx: &watch::Sender<LatestFrame>,
+) -> anyhow::Result<()> {
+    let mut next_capture = Instant::now();
+    let mut failures = 0;
+
+    while !tx.is_closed() {
+        let settings = *settings.borrow();
+
+        let now = Instant::now();
+        if next_capture > now {
+            std::thread::sleep(next_capture - now);
+        }
+        next_capture =
+            Instant::now().max(next_capture) + Duration::from_secs(1) / settings.frame_rate.max(1);
+
+        match capture_frame(source, &settings) {
+            Ok(frame) => {
+                failures = 0;
+                let _ = tx.send(Some(Arc::new(frame)));
+            }
+            Err(e) if failures < MAX_CONSECUTIVE_FAILURES => {
+                failures += 1;
+                log::warn!("Skipping frame: {:#}", e);
+            }
+            Err(e) => return Err(e.context(format!("{} frames in a row failed", failures + 1))),
+        }
+    }
+
+    Ok(())
+}
+
+fn capture_frame(
+    source: &mut dyn FrameSource,
+    settings: &EncodingSettings,
+) -> anyhow::Result<EncodedFrame> {
+    let mut image = source.capture()?;
+
+    if (image.width, image.height) != (settings.resolution.width, settings.resolution.height) {
+        image.data = scale(&image.data, image.width, image.height, settings.resolution);
+        image.width = settings.resolution.width;
+        image.height = settings.resolution.height;
+    }
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, settings.quality).encode(
+        &image.data,
+        image.width,
+        image.height,
+        image.color_type,
+    )?;
+
+    Ok(EncodedFrame {
+        captured_at: image.captured_at,
+        jpeg,
+    })
+}
+
+/// An uncompressed image as captured by a source, with 3 bytes per pixel.
+struct Image {
+    data: Vec<u8>,
+    width: u16,
+    height: u16,
+    color_type: ColorType,
+    captured_at: DateTime,
+}
+
+trait FrameSource: Send {
+    /// Blocks until the next image is available.
+    fn capture(&mut self) -> anyhow::Result<Image>;
+}
+
+fn open(
+    source: &CameraSource,
+    settings: &EncodingSettings,
+) -> anyhow::Result<Box<dyn FrameSource>> {
+    Ok(match source {
+        CameraSource::Device(path) => open_device(path, settings)?,
+        CameraSource::File(path) => Box::new(FileSource::open(path, settings.resolution)?),
+        CameraSource::TestPattern => Box::new(TestPattern {
+            resolution: settings.resolution,
+            frame: 0,
+        }),
+    })
+}
+
+#[cfg(feature = "v4l2")]
+fn open_device(
+    path: &std::path::Path,
+    settings: &EncodingSettings,
+) -> anyhow::Result<Box<dyn FrameSource>> {
+    Ok(Box::new(DeviceSource::open(path, settings)?))
+}
+
+#[cfg(not(feature = "v4l2"))]
+fn open_device(
+    path: &std::path::Path,
+    _: &EncodingSettings,
+) -> anyhow::Result<Box<dyn FrameSource>> {
+    anyhow::bail!(
+        "Cannot open {}, the vehicle has been built without the v4l2 feature",
+        path.display()
+    )
+}
+
+/// Captures YUYV images from a V4L2 device, which are converted to YCbCr without any colour
+/// space conversion.
+#[cfg(feature = "v4l2")]
+struct DeviceSource {
+    stream: v4l::io::mmap::Stream<'static>,
+    width: u16,
+    height: u16,
+    /// Bytes per row, which may include padding after the pixels.
+    stride: usize,
+}
+
+#[cfg(feature = "v4l2")]
+impl DeviceSource {
+    fn open(path: &std::path::Path, settings: &EncodingSettings) -> anyhow::Result<Self> {
+        use v4l::video::Capture;
+
+        let device = v4l::Device::with_path(path)
+            .with_context(|| format!("Could not open camera {}", path.display()))?;
+
+        let requested = v4l::Format::new(
+            settings.resolution.width.into(),
+            settings.resolution.height.into(),
+            v4l::FourCC::new(b"YUYV"),
+        );
+        let format = device.set_format(&requested)?;
+        if format.fourcc != requested.fourcc {
+            anyhow::bail!("Camera {} does not support YUYV", path.display());
+        }
+        if format.width % 2 != 0 {
+            anyhow::bail!(
+                "Camera {} captures at an odd width of {}, which YUYV cannot describe",
+                path.display(),
+                format.width
+            );
+        }
+        if format.width != requested.width || format.height != requested.height {
+            log::warn!(
+                "Camera {} captures at {}x{} instead of {}",
+                path.display(),
+                format.width,
+                format.height,
+                settings.resolution
+            );
+        }
+
+        device.set_params(&v4l::video::capture::Parameters::with_fps(
+            settings.frame_rate,
+        ))?;
+
+        let stream = v4l::io::mmap::Stream::with_buffers(
+            &device,
+            v4l::buffer::Type::VideoCapture,
+            DEVICE_BUFFERS,
+        )?;
+
+        Ok(Self {
+            stream,
+            width: format.width.try_into()?,
+            height: format.height.try_into()?,
+            // Drivers that do not report the stride do not pad their rows.
+            stride: match format.stride {
+                0 => format.width as usize * 2,
+                stride => stride as usize,
+            },
+        })
+    }
+}
+
+#[cfg(feature = "v4l2")]
+impl FrameSource for DeviceSource {
+    fn capture(&mut self) -> anyhow::Result<Image> {
+        use v4l::io::traits::CaptureStream;
+
+        let (buffer, metadata) = self.stream.next()?;
+        if metadata.flags.contains(v4l::buffer::Flags::ERROR) {
+            anyhow::bail!("Frame {} has been corrupted", metadata.sequence);
+        }
+
+        // Only the first bytes of the buffer hold the frame, the rest is left from earlier ones.
+        let yuyv = buffer
+            .get(..metadata.bytesused as usize)
+            .context("Frame exceeds its buffer")?;
+
+        let flags = metadata.flags & v4l::buffer::Flags::TIMESTAMP_MASK;
+        let captured_at = if flags == v4l::buffer::Flags::TIMESTAMP_MONOTONIC {
+            let timestamp = Duration::new(
+                metadata.timestamp.sec.max(0) as u64,
+                (metadata.timestamp.usec.clamp(0, 999_999) * 1000) as u32,
+            );
+            monotonic_to_wall_clock(timestamp, monotonic_now()?, chrono::Utc::now())
+        } else {
+            chrono::Utc::now()
+        };
+
+        Ok(Image {
+            data: yuyv_to_ycbcr(yuyv, self.width, self.height, self.stride)?,
+            width: self.width,
+            height: self.height,
+            color_type: ColorType::Ycbcr,
+            captured_at,
+        })
+    }
+}
+
+/// Current time of the monotonic clock, which V4L2 drivers take their timestamps from.
+#[cfg(feature = "v4l2")]
+fn monotonic_now() -> std::io::Result<Duration> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(Duration::new(now.tv_sec as u64, now.tv_nsec as u32))
+}
+
+/// Wall-clock time of a monotonic `timestamp`, given the time `now` on both clocks.
+#[cfg(any(feature = "v4l2", test))]
+fn monotonic_to_wall_clock(
+    timestamp: Duration,
+    monotonic_now: Duration,
+    now: DateTime,
+) -> DateTime {
+    let age = monotonic_now.saturating_sub(timestamp);
+    now - chrono::Duration::from_std(age).unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+/// Converts a YUYV image whose rows are `stride` bytes apart to YCbCr with 3 bytes per pixel.
+#[cfg(any(feature = "v4l2", test))]
+fn yuyv_to_ycbcr(yuyv: &[u8], width: u16, height: u16, stride: usize) -> anyhow::Result<Vec<u8>> {
+    let (width, height) = (usize::from(width), usize::from(height));
+    let row_length = width * 2;
+    anyhow::ensure!(
+        stride >= row_length,
+        "Stride of {} bytes is shorter than a row of {} pixels",
+        stride,
+        width
+    );
+
+    // The last row need not be padded.
+    let length = (stride * height).saturating_sub(stride - row_length);
+    anyhow::ensure!(
+        yuyv.len() >= length,
+        "Frame of {} bytes is shorter than the {} bytes of a {}x{} image",
+        yuyv.len(),
+        length,
+        width,
+        height
+    );
+
+    // Every four bytes Y0 U Y1 V describe two pixels sharing their chroma.
+    let mut data = Vec::with_capacity(width * height * 3);
+    for row in yuyv.chunks(stride).take(height) {
+        for pixels in row[..row_length].chunks_exact(4) {
+            data.extend_from_slice(&[pixels[0], pixels[1], pixels[3]]);
+            data.extend_from_slice(&[pixels[2], pixels[1], pixels[3]]);
+        }
+    }
+
+    Ok(data)
+}
+
+/// A still image scaled to the configured resolution, for development without a camera.
+struct FileSource {
+    rgb: Vec<u8>,
+    resolution: Resolution,
+}
+
+impl FileSource {
+    fn open(path: &std::path::Path, resolution: Resolution) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        let mut decoder = jpeg_decoder::Decoder::new(std::io::BufReader::new(file));
+        let pixels = decoder
+            .decode()
+            .with_context(|| format!("Could not decode {}", path.display()))?;
+        let info = decoder.info().context("Missing image info")?;
+
+        let rgb: Vec<u8> = match info.pixel_format {
+            jpeg_decoder::PixelFormat::RGB24 => pixels,
+            jpeg_decoder::PixelFormat::L8 => pixels.iter().flat_map(|&l| [l, l, l]).collect(),
+            format => anyhow::bail!(
+                "Unsupported pixel format {:?} in {}",
+                format,
+                path.display()
+            ),
+        };
+
+        Ok(Self {
+            rgb: scale(&rgb, info.width, info.height, resolution),
+            resolution,
+        })
+    }
+}
+
+impl FrameSource for FileSource {
+    fn capture(&mut self) -> anyhow::Result<Image> {
+        Ok(Image {
+            data: self.rgb.clone(),
+            width: self.resolution.width,
+            height: self.resolution.height,
+            color_type: ColorType::Rgb,
+            captured_at: chrono::Utc::now(),
+        })
+    }
+}
+
+/// Scales an image of 3 bytes per pixel with nearest-neighbour sampling.
+fn scale(pixels: &[u8], width: u16, height: u16, resolution: Resolution) -> Vec<u8> {
+    let (width, height) = (usize::from(width), usize::from(height));
+    let (target_width, target_height) = (
+        usize::from(resolution.width),
+        usize::from(resolution.height),
+    );
+
+    let mut scaled = Vec::with_capacity(target_width * target_height * 3);
+    for y in 0..target_height {
+        let row = y * height / target_height * width;
+        for x in 0..target_width {
+            let offset = (row + x * width / target_width) * 3;
+            scaled.extend_from_slice(&pixels[offset..offset + 3]);
+        }
+    }
+
+    scaled
+}
+
+/// Colour bars scrolling by one pixel per frame, which makes stalls and skipped frames visible.
+struct TestPattern {
+    resolution: Resolution,
+    frame: usize,
+}
+
+impl FrameSource for TestPattern {
+    fn capture(&mut self) -> anyhow::Result<Image> {
+        const BARS: [[u8; 3]; 8] = [
+            [255, 255, 255],
+            [255, 255, 0],
+            [0, 255, 255],
+            [0, 255, 0],
+            [255, 0, 255],
+            [255, 0, 0],
+            [0, 0, 255],
+            [0, 0, 0],
+        ];
+
+        let width = usize::from(self.resolution.width);
+        let height = usize::from(self.resolution.height);
+        let row: Vec<u8> = (0..width)
+            .flat_map(|x| BARS[(x + self.frame) % width * BARS.len() / width])
+            .collect();
+
+        self.frame = self.frame.wrapping_add(1);
+
+        Ok(Image {
+            data: row.repeat(height),
+            width: self.resolution.width,
+            height: self.resolution.height,
+            color_type: ColorType::Rgb,
+            captured_at: chrono::Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    const SETTINGS: EncodingSettings = EncodingSettings {
+        resolution: Resolution {
+            width: 8,
+            height: 8,
+        },
+        quality: 80,
+        frame_rate: 1000,
+    };
+
+    #[test]
+    fn yuyv_pixel_pairs_share_their_chroma() {
+        let yuyv = [10, 20, 30, 40, 50, 60, 70, 80];
+        let ycbcr = [10, 20, 40, 30, 20, 40, 50, 60, 80, 70, 60, 80];
+
+        assert_eq!(yuyv_to_ycbcr(&yuyv, 2, 2, 4).unwrap(), ycbcr);
+    }
+
+    #[test]
+    fn yuyv_rows_skip_their_padding() {
+        let yuyv = [10, 20, 30, 40, 0, 0, 50, 60, 70, 80, 0, 0];
+        let ycbcr = [10, 20, 40, 30, 20, 40, 50, 60, 80, 70, 60, 80];
+
+        assert_eq!(yuyv_to_ycbcr(&yuyv, 2, 2, 6).unwrap(), ycbcr);
+        assert_eq!(yuyv_to_ycbcr(&yuyv[..10], 2, 2, 6).unwrap(), ycbcr);
+    }
+
+    #[test]
+    fn short_yuyv_frames_are_rejected() {
+        let yuyv = [10, 20, 30, 40, 0, 0, 50, 60, 70, 80, 0, 0];
+
+        assert!(yuyv_to_ycbcr(&yuyv[..9], 2, 2, 6).is_err());
+        assert!(yuyv_to_ycbcr(&yuyv, 2, 2, 3).is_err());
+    }
+
+    #[test]
+    fn monotonic_timestamps_are_dated_back_from_now() {
+        let now = chrono::Utc::now();
+
+        assert_eq!(
+            monotonic_to_wall_clock(Duration::from_millis(9_950), Duration::from_secs(10), now),
+            now - chrono::Duration::milliseconds(50)
+        );
+        assert_eq!(
+            monotonic_to_wall_clock(Duration::from_secs(11), Duration::from_secs(10), now),
+            now
+        );
+    }
+
+    #[test]
+    fn frames_keep_the_time_of_their_capture() {
+        struct DelayedSource(DateTime);
+
+        impl FrameSource for DelayedSource {
+            fn capture(&mut self) -> anyhow::Result<Image> {
+                Ok(Image {
+                    captured_at: self.0,
+                    ..TestPattern {
+                        resolution: SETTINGS.resolution,
+                        frame: 0,
+                    }
+                    .capture()?
+                })
+            }
+        }
+
+        let captured_at = chrono::Utc::now() - chrono::Duration::milliseconds(80);
+        let frame = capture_frame(&mut DelayedSource(captured_at), &SETTINGS).unwrap();
+
+        assert_eq!(frame.captured_at, captured_at);
+    }
+
+    #[test]
+    fn scaling_samples_the_nearest_pixel() {
+        let pixels: Vec<u8> = (0..4).flat_map(|pixel| [pixel; 3]).collect();
+
+        let scaled = scale(
+            &pixels,
+            2,
+            2,
+            Resolution {
+                width: 4,
+                height: 2,
+            },
+        );
+        assert_eq!(
+            scaled.chunks(3).map(|pixel| pixel[0]).collect::<Vec<_>>(),
+            [0, 0, 1, 1, 2, 2, 3, 3]
+        );
+
+        let scaled = scale(
+            &pixels,
+            2,
+            2,
+            Resolution {
+                width: 1,
+                height: 1,
+            },
+        );
+        assert_eq!(scaled, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_pattern_scrolls_by_a_pixel_per_frame() {
+        let mut pattern = TestPattern {
+            resolution: Resolution {
+                width: 16,
+                height: 2,
+            },
+            frame: 0,
+        };
+
+        let first = pattern.capture().unwrap();
+        let second = pattern.capture().unwrap();
+        assert_eq!(first.data.len(), 16 * 2 * 3);
+        assert_eq!(first.data[..3], [255, 255, 255]);
+        assert_eq!(first.data[14 * 3..16 * 3], [0, 0, 0, 0, 0, 0]);
+        assert_eq!(second.data[..15 * 3], first.data[3..16 * 3]);
+        assert_eq!(first.data[..16 * 3], first.data[16 * 3..]);
+    }
+
+    /// Captures or fails as told, and stops the capture once it has run out of orders.
+    struct FlakySource {
+        orders: VecDeque<bool>,
+        frames: Option<watch::Receiver<LatestFrame>>,
+        delivered: bool,
+    }
+
+    impl FrameSource for FlakySource {
+        fn capture(&mut self) -> anyhow::Result<Image> {
+            match self.orders.pop_front() {
+                Some(true) => TestPattern {
+                    resolution: SETTINGS.resolution,
+                    frame: 0,
+                }
+                .capture(),
+                Some(false) => anyhow::bail!("Corrupted frame"),
+                None => {
+                    let frames = self.frames.take().unwrap();
+                    self.delivered = frames.borrow().is_some();
+                    anyhow::bail!("Stopped")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn failed_frames_are_skipped() {
+        let (_settings_tx, settings) = watch::channel(SETTINGS);
+        let (tx, rx) = watch::channel(None);
+        let mut orders = vec![false; MAX_CONSECUTIVE_FAILURES as usize];
+        orders.push(true);
+        orders.push(false);
+        let mut source = FlakySource {
+            orders: orders.into(),
+            frames: Some(rx),
+            delivered: false,
+        };
+
+        capture(&mut source, &settings, &tx).unwrap();
+        assert!(source.delivered);
+    }
+
+    #[test]
+    fn capture_ends_once_frames_keep_failing() {
+        let (_settings_tx, settings) = watch::channel(SETTINGS);
+        let (tx, rx) = watch::channel(None);
+        let mut source = FlakySource {
+            orders: vec![false; MAX_CONSECUTIVE_FAILURES as usize + 1].into(),
+            frames: Some(rx),
+            delivered: false,
+        };
+
+        assert!(capture(&mut source, &settings, &tx).is_err());
+    }
+}
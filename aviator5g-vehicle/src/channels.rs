@@ -0,0 +1,100 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Maps the axes of control messages onto RC channels for flight controllers, which expect
+//! ailerons, elevator, throttle and rudder on the first four channels by default (AETR).
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use aviator5g_common::ControlMessageData;
+
+/// Ailerons, elevator, rudder and throttle in the order of control messages.
+pub type Axes = [f64; 4];
+
+pub const MIN_PULSE: u16 = 1000;
+pub const NEUTRAL_PULSE: u16 = 1500;
+pub const MAX_PULSE: u16 = 2000;
+
+/// Pulse widths in microseconds of the first four channels before any control message has been
+/// received: sticks centered and throttle closed.
+pub const IDLE_PULSES: [u16; 4] = [NEUTRAL_PULSE, NEUTRAL_PULSE, MIN_PULSE, NEUTRAL_PULSE];
+
+/// Time without control messages after which the pilot is considered lost and outputs fall back
+/// to their failsafe.
+pub const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pulse widths of the first four channels and the time at which they were received.
+pub type ReceivedPulses = ([u16; 4], Instant);
+
+/// Pulse widths that are still to be sent at `now`, or `None` if they are older than
+/// [`CONTROL_TIMEOUT`].
+pub fn fresh_pulses(received: Option<ReceivedPulses>, now: Instant) -> Option<[u16; 4]> {
+    received
+        .filter(|&(_, at)| now.saturating_duration_since(at) < CONTROL_TIMEOUT)
+        .map(|(pulses, _)| pulses)
+}
+
+/// Takes the axes of a control message, which must contain exactly four.
+pub fn axes(data: ControlMessageData) -> Option<Axes> {
+    let axes = <Axes>::try_from(data.axes).ok();
+    if axes.is_none() {
+        log::error!("Expected data for exactly 4 axes");
+    }
+
+    axes
+}
+
+/// Pulse widths in microseconds of the first four channels. Sticks range from -1 to 1 around
+/// the neutral pulse, while the throttle ranges from 0 to 1 over the full range, as flight
+/// controllers expect. Servos driven directly keep the throttle between neutral and maximum.
+pub fn pulses([ailerons, elevator, rudder, throttle]: Axes) -> [u16; 4] {
+    let pulse = |amount: f64, from: u16| {
+        (f64::from(from) + amount * f64::from(MAX_PULSE - from)).round() as u16
+    };
+    let stick = |amount: f64| pulse(amount.clamp(-1.0, 1.0), NEUTRAL_PULSE);
+
+    [
+        stick(ailerons),
+        stick(elevator),
+        pulse(throttle.clamp(0.0, 1.0), MIN_PULSE),
+        stick(rudder),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_throttle_is_the_minimum_pulse() {
+        assert_eq!(pulses([0.0; 4]), IDLE_PULSES);
+        assert_eq!(pulses([0.0, 0.0, 0.0, -1.0]), IDLE_PULSES);
+        assert_eq!(pulses([0.0, 0.0, 0.0, 0.5])[2], 1500);
+        assert_eq!(pulses([0.0, 0.0, 0.0, 1.0])[2], MAX_PULSE);
+    }
+
+    #[test]
+    fn sticks_range_around_the_neutral_pulse() {
+        assert_eq!(pulses([-1.0, 1.0, 0.25, 0.0]), [1000, 2000, 1000, 1625]);
+        assert_eq!(pulses([-2.0, 2.0, 0.0, 0.0]), [1000, 2000, 1000, 1500]);
+    }
+
+    #[test]
+    fn pulses_are_dropped_once_stale() {
+        let at = Instant::now();
+        let received = Some((IDLE_PULSES, at));
+
+        assert_eq!(fresh_pulses(received, at), Some(IDLE_PULSES));
+        assert_eq!(
+            fresh_pulses(received, at + CONTROL_TIMEOUT / 2),
+            Some(IDLE_PULSES)
+        );
+        assert_eq!(fresh_pulses(received, at + CONTROL_TIMEOUT), None);
+        assert_eq!(fresh_pulses(None, at), None);
+    }
+}
@@ -3,6 +3,18 @@
  * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
  */
 
+mod autopilot;
+mod camera;
+mod channels;
+mod mavlink;
+mod pca9685;
+mod ppm;
+mod rover;
+mod sbus;
+mod serial;
+mod video;
+
+use std::path::PathBuf;
 use std::sync::{
     Arc,
     Mutex,
@@ -12,14 +24,43 @@ use std::time::Duration;
 use argh::FromArgs;
 use aviator5g_common::{
     ClientType,
+    ClockSample,
+    ClockSync,
     ControlMessage,
     ControlMessageData,
 };
 use futures_util::{
-    SinkExt,
     StreamExt,
     TryStreamExt,
 };
+use tokio::sync::watch;
+
+use crate::{
+    autopilot::{
+        Autopilot,
+        ControlMode,
+        Endpoint,
+    },
+    camera::{
+        Camera,
+        EncodingSettings,
+        Resolution,
+    },
+    pca9685::{
+        parse_address,
+        Pca9685,
+    },
+    ppm::PpmOutput,
+    rover::{
+        parse_deadband,
+        Steering,
+    },
+    sbus::SbusOutput,
+    video::{
+        SendQueue,
+        VideoSender,
+    },
+};
 
 /// Aviator5G Vehicle.
 #[derive(Debug, Clone, FromArgs)]
@@ -27,6 +68,74 @@ struct Args {
     /// the server's endpoint to which this vehicle should attempt to connect.
     #[argh(option)]
     url: String,
+
+    /// the credential required by the server's access control list for this vehicle's group.
+    #[argh(option)]
+    credential: Option<String>,
+
+    /// a camera to stream as '[<stream id>=]<source>', where the source is a V4L2 device such as
+    /// /dev/video0, 'file:<path>' to a JPEG image or 'test-pattern'; may be repeated, no video is
+    /// sent if omitted.
+    #[argh(option)]
+    camera: Vec<Camera>,
+
+    /// the id of the video stream of a camera given without one (default: camera).
+    #[argh(option, default = "String::from(\"camera\")")]
+    stream_id: String,
+
+    /// the highest resolution at which frames are encoded, lowered while the link is congested
+    /// (default: 640x480).
+    #[argh(option, default = "Resolution { width: 640, height: 480 }")]
+    camera_resolution: Resolution,
+
+    /// the highest JPEG quality between 1 and 100, lowered while the link is congested
+    /// (default: 70).
+    #[argh(option, default = "70")]
+    camera_quality: u8,
+
+    /// the highest number of frames sent per second, lowered while the link is congested
+    /// (default: 15).
+    #[argh(option, default = "15")]
+    camera_frame_rate: u32,
+
+    /// fly through an ArduPilot flight controller over MAVLink instead of driving servos
+    /// directly, reached at 'udp:<local address>', 'tcp:<address>' or '<serial port>[:<baud
+    /// rate>]'.
+    #[argh(option)]
+    mavlink: Option<Endpoint>,
+
+    /// how control is sent to the flight controller, either 'rc-override' or 'manual-control'
+    /// (default: rc-override).
+    #[argh(option, default = "ControlMode::RcOverride")]
+    mavlink_control: ControlMode,
+
+    /// send all channels to a flight controller as SBUS frames on the given serial port, such as
+    /// /dev/ttyAMA0, instead of driving servos directly.
+    #[argh(option)]
+    sbus: Option<PathBuf>,
+
+    /// send all channels to a flight controller as a PPM pulse train on the given BCM GPIO pin
+    /// instead of driving servos directly.
+    #[argh(option)]
+    ppm: Option<u8>,
+
+    /// drive the servos through a PCA9685 PWM board at the given I2C address, such as 0x40, on
+    /// channels 0 to 3 for ailerons, elevator, rudder and throttle instead of the Raspberry PI's
+    /// own PWM.
+    #[argh(option, from_str_fn(parse_address))]
+    pca9685: Option<u16>,
+
+    /// drive a rover or boat instead of an aircraft, steered with the ailerons axis and driven
+    /// forward and in reverse with the throttle axis, either 'differential' for reversing ESCs of
+    /// the left and right motors or 'ackermann' for a steering servo and a reversing ESC, on the
+    /// first two servo pins.
+    #[argh(option)]
+    rover: Option<Steering>,
+
+    /// the fraction of the throttle range on either side of neutral within which the rover's
+    /// ESCs stop the motors (default: 0.05).
+    #[argh(option, default = "0.05", from_str_fn(parse_deadband))]
+    rover_deadband: f64,
 }
 
 fn lerp(start: f64, end: f64, amount: f64) -> f64 {
@@ -44,12 +153,14 @@ enum ServoPin {
     Pwm1,  // Pin: GPIO 19 / Physical 35.
     Soft0, // Pin: GPIO 23 / Physical 16.
     Soft1, // Pin: GPIO 24 / Physical 18.
+    Pca9685(Arc<Mutex<Pca9685>>, u8),
 }
 
 #[derive(Debug)]
 enum ServoConnection {
     Hard(rppal::pwm::Pwm),
     Soft(rppal::gpio::OutputPin),
+    Pca9685(Arc<Mutex<Pca9685>>, u8),
 }
 
 #[derive(Debug)]
@@ -90,6 +201,7 @@ impl Servo {
             ServoPin::Soft1 => {
                 ServoConnection::Soft(rppal::gpio::Gpio::new()?.get(24)?.into_output())
             }
+            ServoPin::Pca9685(device, channel) => ServoConnection::Pca9685(device, channel),
         };
 
         let mut servo = Self {
@@ -111,13 +223,13 @@ impl Servo {
             lerp(
                 self.pulse_neutral.as_micros() as f64,
                 self.pulse_min.as_micros() as f64,
-                -amount as f64,
+                -amount,
             ) as u64
         } else if amount > 0.0 {
             lerp(
                 self.pulse_neutral.as_micros() as f64,
                 self.pulse_max.as_micros() as f64,
-                amount as f64,
+                amount,
             ) as u64
         } else {
             self.pulse_neutral.as_micros() as u64
@@ -126,6 +238,10 @@ impl Servo {
         match &mut self.connection {
             ServoConnection::Hard(c) => c.set_pulse_width(Duration::from_micros(pulse_us))?,
             ServoConnection::Soft(c) => c.set_pwm(self.period, Duration::from_micros(pulse_us))?,
+            ServoConnection::Pca9685(device, channel) => device
+                .lock()
+                .unwrap()
+                .set_pulse_width(*channel, Duration::from_micros(pulse_us))?,
         }
 
         Ok(())
@@ -135,6 +251,9 @@ impl Servo {
         match &mut self.connection {
             ServoConnection::Hard(c) => c.disable()?,
             ServoConnection::Soft(c) => c.set_low(),
+            ServoConnection::Pca9685(device, channel) => {
+                device.lock().unwrap().disable(*channel)?
+            }
         }
 
         Ok(())
@@ -154,7 +273,9 @@ struct VehicleController {
 }
 
 impl VehicleController {
-    fn new() -> anyhow::Result<Self> {
+    /// Drives the servos of ailerons, elevator, rudder and throttle on the given pins.
+    fn new(pins: [ServoPin; 4]) -> anyhow::Result<Self> {
+        let [ailerons_pin, elevator_pin, rudder_pin, throttle_pin] = pins;
         let controller = Self {
             ailerons_axis: 0.0,
             ailerons_servo: Servo::new(
@@ -162,7 +283,7 @@ impl VehicleController {
                 DEFAULT_SERVO_PULSE_MIN,
                 DEFAULT_SERVO_PULSE_NEUTRAL,
                 DEFAULT_SERVO_PULSE_MAX,
-                ServoPin::Pwm0,
+                ailerons_pin,
             )?,
             elevator_axis: 0.0,
             elevator_servo: Servo::new(
@@ -170,7 +291,7 @@ impl VehicleController {
                 DEFAULT_SERVO_PULSE_MIN,
                 DEFAULT_SERVO_PULSE_NEUTRAL,
                 DEFAULT_SERVO_PULSE_MAX,
-                ServoPin::Pwm1,
+                elevator_pin,
             )?,
             rudder_axis: 0.0,
             rudder_servo: Servo::new(
@@ -178,15 +299,17 @@ impl VehicleController {
                 DEFAULT_SERVO_PULSE_MIN,
                 DEFAULT_SERVO_PULSE_NEUTRAL,
                 DEFAULT_SERVO_PULSE_MAX,
-                ServoPin::Soft0,
+                rudder_pin,
             )?,
             throttle_axis: 0.0,
             throttle_servo: Servo::new(
                 DEFAULT_SERVO_PERIOD,
-                DEFAULT_SERVO_PULSE_NEUTRAL, // Prevent throttle from going negative.
+                // Prevent throttle from going negative. Unlike the channels sent to flight
+                // controllers, a closed throttle is the neutral pulse here.
+                DEFAULT_SERVO_PULSE_NEUTRAL,
                 DEFAULT_SERVO_PULSE_NEUTRAL,
                 DEFAULT_SERVO_PULSE_MAX,
-                ServoPin::Soft1,
+                throttle_pin,
             )?,
         };
 
@@ -232,37 +355,280 @@ impl VehicleController {
     }
 }
 
+/// Drives the two outputs of a rover, which are mixed from the ailerons axis for steering and
+/// the throttle axis.
+#[derive(Debug)]
+struct RoverController {
+    steering: Steering,
+    deadband: f64,
+    servos: [Servo; 2],
+}
+
+impl RoverController {
+    fn new(steering: Steering, deadband: f64, pins: [ServoPin; 2]) -> anyhow::Result<Self> {
+        // Reversing ESCs are neutral at the center of the full pulse range, just like servos.
+        let [first_pin, second_pin] = pins;
+        let controller = Self {
+            steering,
+            deadband,
+            servos: [
+                Servo::new(
+                    DEFAULT_SERVO_PERIOD,
+                    DEFAULT_SERVO_PULSE_MIN,
+                    DEFAULT_SERVO_PULSE_NEUTRAL,
+                    DEFAULT_SERVO_PULSE_MAX,
+                    first_pin,
+                )?,
+                Servo::new(
+                    DEFAULT_SERVO_PERIOD,
+                    DEFAULT_SERVO_PULSE_MIN,
+                    DEFAULT_SERVO_PULSE_NEUTRAL,
+                    DEFAULT_SERVO_PULSE_MAX,
+                    second_pin,
+                )?,
+            ],
+        };
+
+        Ok(controller)
+    }
+
+    fn update_from_control_message_data(&mut self, data: ControlMessageData) {
+        if let Some([ailerons, _, _, throttle]) = channels::axes(data) {
+            let outputs = rover::mix(self.steering, ailerons, throttle, self.deadband);
+            for (servo, output) in self.servos.iter_mut().zip(outputs) {
+                servo.rotate(output).unwrap();
+            }
+        }
+    }
+
+    fn disable_all(&mut self) {
+        for servo in &mut self.servos {
+            servo.rotate(0.0).unwrap();
+            servo.disable().unwrap();
+        }
+    }
+}
+
+/// Where control messages are applied.
+#[derive(Debug)]
+enum Output {
+    Pwm(Box<VehicleController>),
+    Rover(Box<RoverController>),
+    Mavlink(Autopilot),
+    Sbus(SbusOutput),
+    Ppm(PpmOutput),
+}
+
+impl Output {
+    fn update_from_control_message_data(&mut self, data: ControlMessageData) {
+        match self {
+            Self::Pwm(controller) => controller.update_from_control_message_data(data),
+            Self::Rover(controller) => controller.update_from_control_message_data(data),
+            Self::Mavlink(autopilot) => autopilot.update_from_control_message_data(data),
+            Self::Sbus(sbus) => sbus.update_from_control_message_data(data),
+            Self::Ppm(ppm) => ppm.update_from_control_message_data(data),
+        }
+    }
+
+    fn disable_all(&mut self) {
+        match self {
+            Self::Pwm(controller) => controller.disable_all(),
+            Self::Rover(controller) => controller.disable_all(),
+            Self::Mavlink(autopilot) => autopilot.release(),
+            Self::Sbus(sbus) => sbus.disable(),
+            Self::Ppm(ppm) => ppm.disable(),
+        }
+    }
+}
+
 const VEHICLE_GROUP_ID: &str = "14ed4af8-5256-4e74-a5d6-545dfc0b004c";
 const VEHICLE_ID: &str = "e72029c7-ce0f-45c7-bc3a-3e01e5c53944";
 
+/// Interval at which the vehicle synchronizes its clock with the server's clock.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args: Args = argh::from_env();
+    anyhow::ensure!(
+        (1..=100).contains(&args.camera_quality),
+        "Camera quality must be between 1 and 100"
+    );
+    anyhow::ensure!(
+        [
+            args.mavlink.is_some(),
+            args.sbus.is_some(),
+            args.ppm.is_some(),
+            args.pca9685.is_some(),
+        ]
+        .iter()
+        .filter(|&&output| output)
+        .count()
+            <= 1,
+        "Only one of --mavlink, --sbus, --ppm and --pca9685 may be given"
+    );
+    anyhow::ensure!(
+        args.rover.is_none()
+            || (args.mavlink.is_none() && args.sbus.is_none() && args.ppm.is_none()),
+        "--rover drives servos directly and cannot be combined with --mavlink, --sbus or --ppm"
+    );
+
+    let stream_ids: Vec<String> = args
+        .camera
+        .iter()
+        .map(|camera| {
+            camera
+                .stream_id
+                .clone()
+                .unwrap_or_else(|| args.stream_id.clone())
+        })
+        .collect();
+    for (index, stream_id) in stream_ids.iter().enumerate() {
+        anyhow::ensure!(
+            stream_id.len() <= aviator5g_common::MAX_STREAM_ID_LENGTH,
+            "Stream id must not be longer than {} bytes",
+            aviator5g_common::MAX_STREAM_ID_LENGTH
+        );
+        anyhow::ensure!(
+            !stream_ids[..index].contains(stream_id),
+            "Stream id '{}' is given to more than one camera",
+            stream_id
+        );
+    }
+
     let url = url::Url::parse(&args.url)?;
 
     log::info!("Connecting to server at {}", url);
     let (ws_stream, _) = tokio_tungstenite::connect_async(url).await?;
-    let (mut outgoing, incoming) = ws_stream.split();
+    let send_queue = SendQueue::of(&ws_stream);
+    let (sink, incoming) = ws_stream.split();
+    let (outgoing, outgoing_rx) = futures_channel::mpsc::unbounded();
 
     outgoing
-        .send(tungstenite::Message::Text(
+        .unbounded_send(tungstenite::Message::Text(
             aviator5g_common::build_control_message(
                 &aviator5g_common::ControlMessage::Identification(
                     aviator5g_common::IdentificationMessageData {
                         group_id: aviator5g_common::id_from_str(VEHICLE_GROUP_ID),
                         id: aviator5g_common::id_from_str(VEHICLE_ID),
                         client_type: ClientType::Vehicle,
+                        credential: args.credential.clone(),
                     },
                 ),
             ),
         ))
-        .await
         .expect("Failed to send identification payload");
 
-    let vehicle_controller = Arc::new(Mutex::new(VehicleController::new()?));
-    let outgoing = Arc::new(Mutex::new(outgoing));
+    if !stream_ids.is_empty() {
+        outgoing
+            .unbounded_send(tungstenite::Message::Text(
+                aviator5g_common::build_control_message(&ControlMessage::StreamAdvertisement(
+                    aviator5g_common::StreamAdvertisementMessageData {
+                        source_id: aviator5g_common::id_from_str(VEHICLE_ID),
+                        stream_ids: stream_ids.clone(),
+                    },
+                )),
+            ))
+            .expect("Failed to send stream advertisement");
+    }
+
+    let output = if let Some(endpoint) = &args.mavlink {
+        Output::Mavlink(
+            Autopilot::connect(
+                endpoint,
+                args.mavlink_control,
+                aviator5g_common::id_from_str(VEHICLE_ID),
+                outgoing.clone(),
+            )
+            .await?,
+        )
+    } else if let Some(path) = &args.sbus {
+        Output::Sbus(SbusOutput::open(path)?)
+    } else if let Some(pin) = args.ppm {
+        Output::Ppm(PpmOutput::open(pin)?)
+    } else {
+        let pins = match args.pca9685 {
+            Some(address) => {
+                let device = Arc::new(Mutex::new(Pca9685::open(address, DEFAULT_SERVO_PERIOD)?));
+                [0, 1, 2, 3].map(|channel| ServoPin::Pca9685(device.clone(), channel))
+            }
+            None => [
+                ServoPin::Pwm0,
+                ServoPin::Pwm1,
+                ServoPin::Soft0,
+                ServoPin::Soft1,
+            ],
+        };
+
+        match args.rover {
+            Some(steering) => {
+                let [first_pin, second_pin, ..] = pins;
+                Output::Rover(Box::new(RoverController::new(
+                    steering,
+                    args.rover_deadband,
+                    [first_pin, second_pin],
+                )?))
+            }
+            None => Output::Pwm(Box::new(VehicleController::new(pins)?)),
+        }
+    };
+    let vehicle_controller = Arc::new(Mutex::new(output));
+    let clock_sync = Arc::new(Mutex::new(ClockSync::new()));
+    let (rtt_tx, rtt_rx) = watch::channel(None);
+
+    let video = if args.camera.is_empty() {
+        None
+    } else {
+        let mut video = VideoSender::new(
+            aviator5g_common::id_from_str(VEHICLE_ID),
+            rtt_rx,
+            send_queue,
+            clock_sync.clone(),
+        );
+
+        for (camera, stream_id) in args.camera.iter().zip(stream_ids) {
+            let (settings_tx, settings_rx) = watch::channel(EncodingSettings {
+                resolution: args.camera_resolution,
+                quality: args.camera_quality,
+                frame_rate: args.camera_frame_rate,
+            });
+            let frames = camera::start(&camera.source, settings_rx)?;
+            video.add_stream(stream_id, frames, settings_tx);
+        }
+
+        Some(video)
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = video::send_messages(sink, outgoing_rx, video).await {
+            log::error!("Failed to send to server: {}", e);
+        }
+    });
+
+    tokio::spawn({
+        let outgoing = outgoing.clone();
+        async move {
+            loop {
+                let request = ControlMessage::ClockSyncRequest(
+                    aviator5g_common::ClockSyncRequestMessageData {
+                        client_transmit: chrono::Utc::now(),
+                    },
+                );
+
+                let result = outgoing.unbounded_send(tungstenite::Message::Text(
+                    aviator5g_common::build_control_message(&request),
+                ));
+
+                if result.is_err() {
+                    break;
+                }
+
+                tokio::time::sleep(CLOCK_SYNC_INTERVAL).await;
+            }
+        }
+    });
 
     simple_signal::set_handler(
         &[simple_signal::Signal::Int, simple_signal::Signal::Term],
@@ -295,10 +661,21 @@ async fn main() -> anyhow::Result<()> {
                             log::info!("Vehicle state updated: {:?}", vehicle_controller);
                         }
                         ControlMessage::LatencyRequest(data) => {
+                            let request_received_at = clock_sync.lock().unwrap().server_now();
+                            if let Some((sent_at, received_at)) =
+                                data.sent_at.zip(request_received_at)
+                            {
+                                let latency = received_at - sent_at;
+                                log::info!(
+                                    "Latency from {}: {}ms",
+                                    data.initiator_id,
+                                    latency.num_milliseconds()
+                                );
+                            }
+
+                            let sent_at = clock_sync.lock().unwrap().server_now();
                             outgoing
-                                .lock()
-                                .unwrap()
-                                .send(tungstenite::Message::Text(
+                                .unbounded_send(tungstenite::Message::Text(
                                     aviator5g_common::build_control_message(
                                         &ControlMessage::LatencyResponse(
                                             aviator5g_common::LatencyResponseMessageData {
@@ -307,13 +684,35 @@ async fn main() -> anyhow::Result<()> {
                                                     VEHICLE_ID,
                                                 ),
                                                 timestamp: data.timestamp,
+                                                request_sent_at: data.sent_at,
+                                                request_received_at,
+                                                sent_at,
                                             },
                                         ),
                                     ),
                                 ))
-                                .await
                                 .unwrap();
                         }
+                        ControlMessage::ClockSyncResponse(data) => {
+                            let sample = ClockSample::from_response(&data, chrono::Utc::now());
+                            let mut clock_sync = clock_sync.lock().unwrap();
+                            clock_sync.add(sample);
+
+                            // The round trip to the server is the link the video is sent over.
+                            let _ = rtt_tx.send(sample.delay.to_std().ok());
+
+                            log::debug!(
+                                "Clock offset to server: {:?} (delay {:?})",
+                                clock_sync.offset(),
+                                sample.delay
+                            );
+                        }
+                        ControlMessage::Error(data) => {
+                            log::error!("Received error from server: {:?}", data);
+                        }
+                        ControlMessage::GoingAway(data) => {
+                            log::warn!("Server is going away: {:?}", data);
+                        }
                         _ => {}
                     }
                 }
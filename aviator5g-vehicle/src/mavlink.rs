@@ -0,0 +1,628 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Encodes and parses the few MAVLink messages exchanged with a flight controller. Messages are
+//! sent as MAVLink 2, while both MAVLink 1 and 2 are understood.
+
+const STX_V1: u8 = 0xfe;
+const STX_V2: u8 = 0xfd;
+
+const HEADER_LENGTH_V1: usize = 6;
+const HEADER_LENGTH_V2: usize = 10;
+const CHECKSUM_LENGTH: usize = 2;
+const SIGNATURE_LENGTH: usize = 13;
+
+/// Set in the incompatibility flags of signed MAVLink 2 frames.
+const INCOMPAT_FLAG_SIGNED: u8 = 0x01;
+
+const HEARTBEAT: u32 = 0;
+const SYS_STATUS: u32 = 1;
+const ATTITUDE: u32 = 30;
+const GLOBAL_POSITION_INT: u32 = 33;
+const REQUEST_DATA_STREAM: u32 = 66;
+const MANUAL_CONTROL: u32 = 69;
+const RC_CHANNELS_OVERRIDE: u32 = 70;
+const VFR_HUD: u32 = 74;
+
+pub const MAV_TYPE_GCS: u8 = 6;
+pub const MAV_AUTOPILOT_INVALID: u8 = 8;
+pub const MAV_MODE_FLAG_SAFETY_ARMED: u8 = 0x80;
+pub const MAV_DATA_STREAM_ALL: u8 = 0;
+
+/// Seed mixed into the checksum of each message, derived from the message's definition, and the
+/// length of its payload.
+fn message_info(message_id: u32) -> Option<(u8, usize)> {
+    match message_id {
+        HEARTBEAT => Some((50, 9)),
+        SYS_STATUS => Some((124, 31)),
+        ATTITUDE => Some((39, 28)),
+        GLOBAL_POSITION_INT => Some((104, 28)),
+        REQUEST_DATA_STREAM => Some((148, 6)),
+        MANUAL_CONTROL => Some((243, 11)),
+        RC_CHANNELS_OVERRIDE => Some((124, 18)),
+        VFR_HUD => Some((20, 20)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub custom_mode: u32,
+    pub mav_type: u8,
+    pub autopilot: u8,
+    pub base_mode: u8,
+    pub system_status: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SysStatus {
+    /// Battery voltage in millivolts, `u16::MAX` if unknown.
+    pub voltage_battery: u16,
+    /// Remaining battery capacity in percent, -1 if unknown.
+    pub battery_remaining: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attitude {
+    /// Angles in radians.
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlobalPositionInt {
+    /// Position in degrees times 10^7.
+    pub lat: i32,
+    pub lon: i32,
+    /// Altitudes in millimeters.
+    pub alt: i32,
+    pub relative_alt: i32,
+    /// Heading in centidegrees, `u16::MAX` if unknown.
+    pub hdg: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VfrHud {
+    /// Speeds in meters per second.
+    pub airspeed: f32,
+    pub groundspeed: f32,
+    pub climb: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestDataStream {
+    pub target_system: u8,
+    pub target_component: u8,
+    pub stream_id: u8,
+    /// Rate in Hertz.
+    pub rate: u16,
+    pub start: bool,
+}
+
+/// Stick positions from -1000 to 1000, throttle from 0 to 1000.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ManualControl {
+    pub target: u8,
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub r: i16,
+}
+
+/// Pulse widths in microseconds for the first eight channels; 0 releases a channel back to the
+/// RC receiver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RcChannelsOverride {
+    pub target_system: u8,
+    pub target_component: u8,
+    pub channels: [u16; 8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    Heartbeat(Heartbeat),
+    SysStatus(SysStatus),
+    Attitude(Attitude),
+    GlobalPositionInt(GlobalPositionInt),
+    VfrHud(VfrHud),
+    RequestDataStream(RequestDataStream),
+    ManualControl(ManualControl),
+    RcChannelsOverride(RcChannelsOverride),
+}
+
+impl Message {
+    fn id(&self) -> u32 {
+        match self {
+            Self::Heartbeat(_) => HEARTBEAT,
+            Self::SysStatus(_) => SYS_STATUS,
+            Self::Attitude(_) => ATTITUDE,
+            Self::GlobalPositionInt(_) => GLOBAL_POSITION_INT,
+            Self::VfrHud(_) => VFR_HUD,
+            Self::RequestDataStream(_) => REQUEST_DATA_STREAM,
+            Self::ManualControl(_) => MANUAL_CONTROL,
+            Self::RcChannelsOverride(_) => RC_CHANNELS_OVERRIDE,
+        }
+    }
+
+    /// Serializes the message's fields in wire order, which sorts them by size.
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Self::Heartbeat(m) => {
+                payload.extend_from_slice(&m.custom_mode.to_le_bytes());
+                payload.extend_from_slice(&[
+                    m.mav_type,
+                    m.autopilot,
+                    m.base_mode,
+                    m.system_status,
+                    3,
+                ]);
+            }
+            Self::SysStatus(m) => {
+                payload.resize(14, 0);
+                payload.extend_from_slice(&m.voltage_battery.to_le_bytes());
+                payload.resize(30, 0);
+                payload.push(m.battery_remaining as u8);
+            }
+            Self::Attitude(m) => {
+                payload.resize(4, 0);
+                for angle in [m.roll, m.pitch, m.yaw, 0.0, 0.0, 0.0] {
+                    payload.extend_from_slice(&angle.to_le_bytes());
+                }
+            }
+            Self::GlobalPositionInt(m) => {
+                payload.resize(4, 0);
+                for value in [m.lat, m.lon, m.alt, m.relative_alt] {
+                    payload.extend_from_slice(&value.to_le_bytes());
+                }
+                payload.resize(26, 0);
+                payload.extend_from_slice(&m.hdg.to_le_bytes());
+            }
+            Self::VfrHud(m) => {
+                for value in [m.airspeed, m.groundspeed, 0.0, m.climb] {
+                    payload.extend_from_slice(&value.to_le_bytes());
+                }
+                payload.resize(20, 0);
+            }
+            Self::RequestDataStream(m) => {
+                payload.extend_from_slice(&m.rate.to_le_bytes());
+                payload.extend_from_slice(&[
+                    m.target_system,
+                    m.target_component,
+                    m.stream_id,
+                    u8::from(m.start),
+                ]);
+            }
+            Self::ManualControl(m) => {
+                for value in [m.x, m.y, m.z, m.r, 0] {
+                    payload.extend_from_slice(&value.to_le_bytes());
+                }
+                payload.push(m.target);
+            }
+            Self::RcChannelsOverride(m) => {
+                for channel in m.channels {
+                    payload.extend_from_slice(&channel.to_le_bytes());
+                }
+                payload.extend_from_slice(&[m.target_system, m.target_component]);
+            }
+        }
+
+        payload
+    }
+
+    /// Deserializes a payload whose trailing zeros have been restored.
+    fn parse(message_id: u32, p: &[u8]) -> Option<Self> {
+        let u16_at = |offset: usize| u16::from_le_bytes([p[offset], p[offset + 1]]);
+        let i32_at = |offset: usize| i32::from_le_bytes(p[offset..offset + 4].try_into().unwrap());
+        let f32_at = |offset: usize| f32::from_le_bytes(p[offset..offset + 4].try_into().unwrap());
+
+        let message = match message_id {
+            HEARTBEAT => Self::Heartbeat(Heartbeat {
+                custom_mode: i32_at(0) as u32,
+                mav_type: p[4],
+                autopilot: p[5],
+                base_mode: p[6],
+                system_status: p[7],
+            }),
+            SYS_STATUS => Self::SysStatus(SysStatus {
+                voltage_battery: u16_at(14),
+                battery_remaining: p[30] as i8,
+            }),
+            ATTITUDE => Self::Attitude(Attitude {
+                roll: f32_at(4),
+                pitch: f32_at(8),
+                yaw: f32_at(12),
+            }),
+            GLOBAL_POSITION_INT => Self::GlobalPositionInt(GlobalPositionInt {
+                lat: i32_at(4),
+                lon: i32_at(8),
+                alt: i32_at(12),
+                relative_alt: i32_at(16),
+                hdg: u16_at(26),
+            }),
+            VFR_HUD => Self::VfrHud(VfrHud {
+                airspeed: f32_at(0),
+                groundspeed: f32_at(4),
+                climb: f32_at(12),
+            }),
+            REQUEST_DATA_STREAM => Self::RequestDataStream(RequestDataStream {
+                rate: u16_at(0),
+                target_system: p[2],
+                target_component: p[3],
+                stream_id: p[4],
+                start: p[5] != 0,
+            }),
+            MANUAL_CONTROL => Self::ManualControl(ManualControl {
+                x: u16_at(0) as i16,
+                y: u16_at(2) as i16,
+                z: u16_at(4) as i16,
+                r: u16_at(6) as i16,
+                target: p[10],
+            }),
+            RC_CHANNELS_OVERRIDE => Self::RcChannelsOverride(RcChannelsOverride {
+                channels: [0, 1, 2, 3, 4, 5, 6, 7].map(|channel| u16_at(channel * 2)),
+                target_system: p[16],
+                target_component: p[17],
+            }),
+            _ => return None,
+        };
+
+        Some(message)
+    }
+}
+
+/// A message along with the system and component that have sent it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub message: Message,
+}
+
+impl Frame {
+    /// Serializes the frame as MAVLink 2.
+    pub fn encode(&self, sequence: u8) -> Vec<u8> {
+        let message_id = self.message.id();
+        let (crc_extra, _) = message_info(message_id).expect("Unknown message");
+
+        // MAVLink 2 truncates trailing zeros, but always sends at least one byte.
+        let mut payload = self.message.payload();
+        let length = payload.iter().rposition(|&b| b != 0).map_or(1, |i| i + 1);
+        payload.truncate(length);
+
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH_V2 + length + CHECKSUM_LENGTH);
+        bytes.extend_from_slice(&[
+            STX_V2,
+            length as u8,
+            0,
+            0,
+            sequence,
+            self.system_id,
+            self.component_id,
+        ]);
+        bytes.extend_from_slice(&message_id.to_le_bytes()[..3]);
+        bytes.extend_from_slice(&payload);
+
+        let checksum = checksum(&bytes[1..], crc_extra);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// Extracts frames from a byte stream, skipping garbage, corrupt frames and unknown messages.
+#[derive(Debug, Default)]
+pub struct Parser {
+    buffer: Vec<u8>,
+}
+
+impl Parser {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            let start = self.buffer.iter().position(|&b| b == STX_V1 || b == STX_V2);
+            self.buffer.drain(..start.unwrap_or(self.buffer.len()));
+
+            let b = &self.buffer;
+            let payload_length = usize::from(*b.get(1)?);
+            let (header_length, signature_length) = match b[0] {
+                STX_V2 if *b.get(2)? & INCOMPAT_FLAG_SIGNED != 0 => {
+                    (HEADER_LENGTH_V2, SIGNATURE_LENGTH)
+                }
+                STX_V2 => (HEADER_LENGTH_V2, 0),
+                _ => (HEADER_LENGTH_V1, 0),
+            };
+            let frame_length = header_length + payload_length + CHECKSUM_LENGTH + signature_length;
+            if b.len() < frame_length {
+                return None;
+            }
+
+            let (system_id, component_id, message_id) = match b[0] {
+                STX_V2 => (b[5], b[6], u32::from_le_bytes([b[7], b[8], b[9], 0])),
+                _ => (b[3], b[4], u32::from(b[5])),
+            };
+
+            // Frames of unknown messages cannot be told apart from garbage, so they are skipped
+            // like garbage by resynchronizing on the next start byte.
+            let checked = header_length + payload_length;
+            let expected = u16::from_le_bytes([b[checked], b[checked + 1]]);
+            let full_length = match message_info(message_id) {
+                Some((crc_extra, full_length))
+                    if checksum(&b[1..checked], crc_extra) == expected =>
+                {
+                    full_length
+                }
+                _ => {
+                    self.buffer.drain(..1);
+                    continue;
+                }
+            };
+
+            let mut payload = b[header_length..checked].to_vec();
+            payload.resize(full_length.max(payload_length), 0);
+            let message = Message::parse(message_id, &payload);
+            self.buffer.drain(..frame_length);
+
+            if let Some(message) = message {
+                return Some(Frame {
+                    system_id,
+                    component_id,
+                    message,
+                });
+            }
+        }
+    }
+}
+
+/// CRC-16/MCRF4XX over the given bytes followed by the message's seed.
+fn checksum(bytes: &[u8], crc_extra: u8) -> u16 {
+    bytes
+        .iter()
+        .chain(std::iter::once(&crc_extra))
+        .fold(0xffff, |crc: u16, &byte| {
+            let tmp = byte ^ (crc as u8);
+            let tmp = tmp ^ (tmp << 4);
+            (crc >> 8) ^ (u16::from(tmp) << 8) ^ (u16::from(tmp) << 3) ^ (u16::from(tmp) >> 4)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Heartbeat as sent by ArduPlane in AUTO mode.
+    const HEARTBEAT_V1: [u8; 17] = [
+        0xfe, 0x09, 0x07, 0x01, 0x01, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x01, 0x03, 0x59, 0x04, 0x03,
+        0x38, 0x75,
+    ];
+
+    fn frame(message: Message) -> Frame {
+        Frame {
+            system_id: 1,
+            component_id: 1,
+            message,
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Heartbeat(Heartbeat {
+                custom_mode: 10,
+                mav_type: 1,
+                autopilot: 3,
+                base_mode: MAV_MODE_FLAG_SAFETY_ARMED,
+                system_status: 4,
+            }),
+            Message::SysStatus(SysStatus {
+                voltage_battery: 12600,
+                battery_remaining: -1,
+            }),
+            Message::Attitude(Attitude {
+                roll: 0.25,
+                pitch: -0.5,
+                yaw: 3.0,
+            }),
+            Message::GlobalPositionInt(GlobalPositionInt {
+                lat: -353_632_621,
+                lon: 1_491_652_374,
+                alt: 584_090,
+                relative_alt: -1_200,
+                hdg: u16::MAX,
+            }),
+            Message::VfrHud(VfrHud {
+                airspeed: 18.5,
+                groundspeed: 21.0,
+                climb: -1.5,
+            }),
+            Message::RequestDataStream(RequestDataStream {
+                target_system: 1,
+                target_component: 1,
+                stream_id: MAV_DATA_STREAM_ALL,
+                rate: 4,
+                start: true,
+            }),
+            Message::ManualControl(ManualControl {
+                target: 1,
+                x: -1000,
+                y: 500,
+                z: 1000,
+                r: -250,
+            }),
+            Message::RcChannelsOverride(RcChannelsOverride {
+                target_system: 1,
+                target_component: 1,
+                channels: [1000, 2000, 1750, 1500, 0, 0, 0, 65535],
+            }),
+        ]
+    }
+
+    fn parse_all(bytes: &[u8]) -> Vec<Frame> {
+        let mut parser = Parser::default();
+        parser.push(bytes);
+        std::iter::from_fn(|| parser.next_frame()).collect()
+    }
+
+    /// Serializes the frame as MAVLink 1, which never truncates payloads.
+    fn encode_v1(frame: &Frame, sequence: u8) -> Vec<u8> {
+        let message_id = frame.message.id();
+        let (crc_extra, _) = message_info(message_id).unwrap();
+        let payload = frame.message.payload();
+
+        let mut bytes = vec![
+            STX_V1,
+            payload.len() as u8,
+            sequence,
+            frame.system_id,
+            frame.component_id,
+            message_id as u8,
+        ];
+        bytes.extend_from_slice(&payload);
+        let checksum = checksum(&bytes[1..], crc_extra);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    #[test]
+    fn checksum_is_crc16_mcrf4xx() {
+        assert_eq!(checksum(b"12345678", b'9'), 0x6f91);
+    }
+
+    #[test]
+    fn heartbeat_is_encoded_as_mavlink_2() {
+        let heartbeat = Frame {
+            system_id: 255,
+            component_id: 190,
+            message: Message::Heartbeat(Heartbeat {
+                custom_mode: 0,
+                mav_type: MAV_TYPE_GCS,
+                autopilot: MAV_AUTOPILOT_INVALID,
+                base_mode: 0,
+                system_status: 0,
+            }),
+        };
+
+        assert_eq!(
+            heartbeat.encode(0),
+            [
+                0xfd, 0x09, 0x00, 0x00, 0x00, 0xff, 0xbe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x06, 0x08, 0x00, 0x00, 0x03, 0x5c, 0x2b,
+            ]
+        );
+    }
+
+    #[test]
+    fn mavlink_1_heartbeat_is_parsed() {
+        assert_eq!(
+            parse_all(&HEARTBEAT_V1),
+            [frame(Message::Heartbeat(Heartbeat {
+                custom_mode: 10,
+                mav_type: 1,
+                autopilot: 3,
+                base_mode: 0x59,
+                system_status: 4,
+            }))]
+        );
+    }
+
+    #[test]
+    fn messages_survive_a_round_trip_through_mavlink_2() {
+        let frames: Vec<Frame> = messages().into_iter().map(frame).collect();
+        let bytes: Vec<u8> = frames
+            .iter()
+            .enumerate()
+            .flat_map(|(sequence, frame)| frame.encode(sequence as u8))
+            .collect();
+
+        assert_eq!(parse_all(&bytes), frames);
+    }
+
+    #[test]
+    fn messages_survive_a_round_trip_through_mavlink_1() {
+        let frames: Vec<Frame> = messages().into_iter().map(frame).collect();
+        let bytes: Vec<u8> = frames
+            .iter()
+            .enumerate()
+            .flat_map(|(sequence, frame)| encode_v1(frame, sequence as u8))
+            .collect();
+
+        assert_eq!(parse_all(&bytes), frames);
+    }
+
+    #[test]
+    fn trailing_zeros_of_mavlink_2_payloads_are_truncated_and_restored() {
+        let overrides = frame(Message::RcChannelsOverride(RcChannelsOverride {
+            target_system: 0,
+            target_component: 0,
+            channels: [1500, 1500, 1000, 1500, 0, 0, 0, 0],
+        }));
+        let bytes = overrides.encode(0);
+        assert_eq!(bytes[1], 8);
+        assert_eq!(bytes.len(), HEADER_LENGTH_V2 + 8 + CHECKSUM_LENGTH);
+        assert_eq!(parse_all(&bytes), [overrides]);
+
+        // At least one byte is sent even if the whole payload is zero.
+        let released = frame(Message::ManualControl(ManualControl {
+            target: 0,
+            x: 0,
+            y: 0,
+            z: 0,
+            r: 0,
+        }));
+        let bytes = released.encode(0);
+        assert_eq!(bytes[1], 1);
+        assert_eq!(parse_all(&bytes), [released]);
+    }
+
+    #[test]
+    fn signatures_of_signed_frames_are_skipped() {
+        let heartbeat = frame(messages()[0]);
+        let mut bytes = heartbeat.encode(0);
+        bytes[2] = INCOMPAT_FLAG_SIGNED;
+        let checked = bytes.len() - CHECKSUM_LENGTH;
+        let checksum = checksum(&bytes[1..checked], 50);
+        bytes[checked..].copy_from_slice(&checksum.to_le_bytes());
+        // A signature full of start bytes would be mistaken for frames if it were not skipped.
+        bytes.extend_from_slice(&[STX_V2; SIGNATURE_LENGTH]);
+
+        let attitude = frame(messages()[2]);
+        bytes.extend_from_slice(&attitude.encode(1));
+
+        assert_eq!(parse_all(&bytes), [heartbeat, attitude]);
+    }
+
+    #[test]
+    fn parser_resynchronizes_after_garbage_and_corrupt_frames() {
+        let heartbeat = frame(messages()[0]);
+        let mut corrupt = heartbeat.encode(0);
+        corrupt[12] ^= 0xff;
+
+        // The start byte in the garbage announces a frame that fails its checksum.
+        let mut bytes = vec![0x00, 0x42, STX_V1, 0x03, 0x07];
+        bytes.extend_from_slice(&corrupt);
+        bytes.extend_from_slice(&[0x13, 0x37]);
+        bytes.extend_from_slice(&HEARTBEAT_V1);
+        bytes.extend_from_slice(&heartbeat.encode(1));
+
+        assert_eq!(parse_all(&bytes), [parse_all(&HEARTBEAT_V1)[0], heartbeat]);
+    }
+
+    #[test]
+    fn incomplete_frames_are_parsed_once_complete() {
+        let heartbeat = frame(messages()[0]);
+        let bytes = heartbeat.encode(0);
+
+        let mut parser = Parser::default();
+        parser.push(&bytes[..7]);
+        assert_eq!(parser.next_frame(), None);
+        parser.push(&bytes[7..]);
+        assert_eq!(parser.next_frame(), Some(heartbeat));
+        assert_eq!(parser.next_frame(), None);
+    }
+}
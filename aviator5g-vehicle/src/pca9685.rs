@@ -0,0 +1,240 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Drives servos through a PCA9685 16-channel PWM controller on the I2C bus, which generates all
+//! pulses in hardware.
+
+use std::time::Duration;
+
+use anyhow::Context;
+
+pub const CHANNELS: u8 = 16;
+
+const MODE1: u8 = 0x00;
+const MODE2: u8 = 0x01;
+const LED0_ON_L: u8 = 0x06;
+const PRE_SCALE: u8 = 0xfe;
+
+const MODE1_ALLCALL: u8 = 0x01;
+const MODE1_SLEEP: u8 = 0x10;
+const MODE1_AI: u8 = 0x20;
+const MODE2_OUTDRV: u8 = 0x04;
+
+/// Set in a channel's OFF_H register to hold the output low.
+const LED_FULL_OFF: u8 = 0x10;
+
+const OSCILLATOR_HZ: u32 = 25_000_000;
+const STEPS: u32 = 4096;
+
+/// Time the oscillator needs to start after waking up.
+const OSCILLATOR_STARTUP: Duration = Duration::from_micros(500);
+
+/// Writes to a device on an I2C bus, so that the driver can be tested without the hardware.
+pub trait I2c: Send + std::fmt::Debug {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
+}
+
+impl I2c for rppal::i2c::I2c {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let written = rppal::i2c::I2c::write(self, bytes)?;
+        anyhow::ensure!(written == bytes.len(), "Short write to I2C device");
+
+        Ok(())
+    }
+}
+
+/// Parses an I2C address given in decimal or as hexadecimal prefixed with '0x'.
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    address
+        .ok()
+        .filter(|address| *address < 0x80)
+        .ok_or_else(|| format!("Invalid I2C address '{}', expected e.g. '0x40'", s))
+}
+
+#[derive(Debug)]
+pub struct Pca9685 {
+    i2c: Box<dyn I2c>,
+    prescale: u8,
+}
+
+impl Pca9685 {
+    /// Opens the board at the given address on the Raspberry PI's primary I2C bus.
+    pub fn open(address: u16, period: Duration) -> anyhow::Result<Self> {
+        let mut i2c = rppal::i2c::I2c::new().context("Could not open I2C bus")?;
+        i2c.set_slave_address(address)?;
+
+        Self::new(Box::new(i2c), period)
+            .with_context(|| format!("Could not set up PCA9685 at {:#04x}", address))
+    }
+
+    /// Sets up the board to generate pulses at the given period, with all outputs held low.
+    pub fn new(mut i2c: Box<dyn I2c>, period: Duration) -> anyhow::Result<Self> {
+        let steps_per_period = u64::from(STEPS) * 1_000_000;
+        let prescale = ((u64::from(OSCILLATOR_HZ) * period.as_micros() as u64
+            + steps_per_period / 2)
+            / steps_per_period)
+            .saturating_sub(1)
+            .clamp(3, 255) as u8;
+
+        // The prescaler may only be changed while the oscillator is asleep. Auto-increment must be
+        // on before the channels are written, as each write spans all four registers of a channel.
+        i2c.write(&[MODE1, MODE1_SLEEP | MODE1_AI | MODE1_ALLCALL])?;
+        i2c.write(&[PRE_SCALE, prescale])?;
+        i2c.write(&[MODE2, MODE2_OUTDRV])?;
+
+        let mut pca9685 = Self { i2c, prescale };
+        for channel in 0..CHANNELS {
+            pca9685.disable(channel)?;
+        }
+
+        pca9685.i2c.write(&[MODE1, MODE1_AI | MODE1_ALLCALL])?;
+        std::thread::sleep(OSCILLATOR_STARTUP);
+
+        Ok(pca9685)
+    }
+
+    /// Starts generating pulses of the given width on the channel, limited to the period.
+    pub fn set_pulse_width(&mut self, channel: u8, pulse_width: Duration) -> anyhow::Result<()> {
+        anyhow::ensure!(channel < CHANNELS, "Invalid PCA9685 channel {}", channel);
+
+        let step_nanos = (u64::from(self.prescale) + 1) * 1_000_000_000 / u64::from(OSCILLATOR_HZ);
+        let steps = ((pulse_width.as_nanos() as u64 + step_nanos / 2) / step_nanos)
+            .min(u64::from(STEPS - 1)) as u16;
+
+        let [off_l, off_h] = steps.to_le_bytes();
+        self.i2c
+            .write(&[LED0_ON_L + 4 * channel, 0, 0, off_l, off_h])
+    }
+
+    /// Holds the channel's output low.
+    pub fn disable(&mut self, channel: u8) -> anyhow::Result<()> {
+        anyhow::ensure!(channel < CHANNELS, "Invalid PCA9685 channel {}", channel);
+
+        self.i2c
+            .write(&[LED0_ON_L + 4 * channel, 0, 0, 0, LED_FULL_OFF])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    use super::*;
+
+    /// Records everything written to the bus.
+    #[derive(Debug, Default, Clone)]
+    struct MockI2c {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl MockI2c {
+        fn take_writes(&self) -> Vec<Vec<u8>> {
+            std::mem::take(&mut self.writes.lock().unwrap())
+        }
+    }
+
+    impl I2c for MockI2c {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.writes.lock().unwrap().push(bytes.to_vec());
+            Ok(())
+        }
+    }
+
+    fn pca9685() -> (Pca9685, MockI2c) {
+        let i2c = MockI2c::default();
+        let pca9685 = Pca9685::new(Box::new(i2c.clone()), Duration::from_millis(20)).unwrap();
+        i2c.take_writes();
+
+        (pca9685, i2c)
+    }
+
+    #[test]
+    fn setup_sets_the_prescaler_while_asleep_and_disables_all_channels() {
+        let i2c = MockI2c::default();
+        Pca9685::new(Box::new(i2c.clone()), Duration::from_millis(20)).unwrap();
+
+        let writes = i2c.take_writes();
+        assert_eq!(
+            writes[..3],
+            [vec![0x00, 0x31], vec![0xfe, 121], vec![0x01, 0x04]]
+        );
+        for channel in 0..CHANNELS {
+            assert_eq!(
+                writes[3 + usize::from(channel)],
+                [0x06 + 4 * channel, 0, 0, 0, 0x10]
+            );
+        }
+        assert_eq!(writes[19..], [vec![0x00, 0x21]]);
+    }
+
+    #[test]
+    fn pulse_widths_are_converted_to_steps_of_the_period() {
+        let (mut pca9685, i2c) = pca9685();
+
+        pca9685
+            .set_pulse_width(0, Duration::from_micros(1500))
+            .unwrap();
+        pca9685
+            .set_pulse_width(15, Duration::from_micros(1000))
+            .unwrap();
+        pca9685
+            .set_pulse_width(3, Duration::from_micros(2000))
+            .unwrap();
+
+        assert_eq!(
+            i2c.take_writes(),
+            [
+                vec![0x06, 0, 0, 0x33, 0x01],
+                vec![0x42, 0, 0, 0xcd, 0x00],
+                vec![0x12, 0, 0, 0x9a, 0x01],
+            ]
+        );
+    }
+
+    #[test]
+    fn pulse_widths_are_limited_to_the_period() {
+        let (mut pca9685, i2c) = pca9685();
+
+        pca9685.set_pulse_width(1, Duration::from_secs(1)).unwrap();
+
+        assert_eq!(i2c.take_writes(), [vec![0x0a, 0, 0, 0xff, 0x0f]]);
+    }
+
+    #[test]
+    fn disabled_channels_are_held_low() {
+        let (mut pca9685, i2c) = pca9685();
+
+        pca9685.disable(2).unwrap();
+
+        assert_eq!(i2c.take_writes(), [vec![0x0e, 0, 0, 0, 0x10]]);
+    }
+
+    #[test]
+    fn invalid_channels_are_rejected_without_writing() {
+        let (mut pca9685, i2c) = pca9685();
+
+        assert!(pca9685
+            .set_pulse_width(16, Duration::from_micros(1500))
+            .is_err());
+        assert!(pca9685.disable(16).is_err());
+        assert!(i2c.take_writes().is_empty());
+    }
+
+    #[test]
+    fn addresses_are_parsed_in_decimal_or_hexadecimal() {
+        assert_eq!(parse_address("0x40"), Ok(0x40));
+        assert_eq!(parse_address("65"), Ok(0x41));
+        assert!(parse_address("0x80").is_err());
+        assert!(parse_address("pca").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Sends the RC channels to a flight controller as a PPM pulse train on one GPIO pin.
+//!
+//! Each channel starts with a short high separator pulse, and its pulse width is the time until
+//! the next separator. The pulses are timed in software, which is accurate to a few microseconds
+//! on an otherwise idle Raspberry PI.
+
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use aviator5g_common::ControlMessageData;
+
+use crate::channels;
+
+pub const CHANNELS: usize = 8;
+
+/// Durations of one frame: a separator and the rest of each channel, followed by a separator and
+/// the synchronization gap that fills the frame.
+pub const FRAME_DURATIONS: usize = 2 * CHANNELS + 2;
+
+const FRAME_LENGTH_US: u32 = 22_500;
+const SEPARATOR_US: u32 = 300;
+
+/// Time before the end of a pulse from which it is waited for by spinning, as sleeping is not
+/// accurate enough.
+const SPIN_DURATION: Duration = Duration::from_micros(200);
+
+/// Durations in microseconds for which the pin is alternately held high and low in one frame,
+/// starting with the first channel's separator.
+pub fn encode_frame(pulses: &[u16; CHANNELS]) -> [u32; FRAME_DURATIONS] {
+    let mut durations = [SEPARATOR_US; FRAME_DURATIONS];
+    for (index, &pulse) in pulses.iter().enumerate() {
+        let pulse = u32::from(pulse.clamp(channels::MIN_PULSE, channels::MAX_PULSE));
+        durations[2 * index + 1] = pulse - SEPARATOR_US;
+    }
+
+    let channels_length: u32 = durations[..2 * CHANNELS].iter().sum();
+    durations[FRAME_DURATIONS - 1] = FRAME_LENGTH_US - channels_length - SEPARATOR_US;
+
+    durations
+}
+
+/// Pulse widths of a frame given the pulse widths of the first four channels, with all other
+/// channels neutral.
+fn frame_pulses(pulses: [u16; 4]) -> [u16; CHANNELS] {
+    let mut frame = [channels::NEUTRAL_PULSE; CHANNELS];
+    frame[..4].copy_from_slice(&pulses);
+
+    frame
+}
+
+/// PPM output on a GPIO pin, which holds the pin low until the first control message, once
+/// control messages have stopped arriving and after it has been disabled, so that the flight
+/// controller detects the loss of the signal.
+#[derive(Debug)]
+pub struct PpmOutput {
+    /// Pulse widths of the first four channels when they were received, or `None` while
+    /// disabled.
+    pulses: Arc<Mutex<Option<channels::ReceivedPulses>>>,
+}
+
+impl PpmOutput {
+    /// Starts sending frames on the given BCM GPIO pin on a dedicated thread, which stops once the
+    /// output has been dropped.
+    pub fn open(pin: u8) -> anyhow::Result<Self> {
+        let mut pin = rppal::gpio::Gpio::new()?.get(pin)?.into_output();
+        pin.set_low();
+        let pulses = Arc::new(Mutex::new(None));
+
+        let shared = Arc::downgrade(&pulses);
+        std::thread::Builder::new()
+            .name("ppm".into())
+            .spawn(move || {
+                let mut deadline = Instant::now();
+                while let Some(pulses) = shared.upgrade() {
+                    let pulses = channels::fresh_pulses(*pulses.lock().unwrap(), Instant::now());
+                    // A frame distorted by the thread having been preempted is not made up for.
+                    deadline = deadline.max(Instant::now());
+
+                    let pulses = match pulses {
+                        Some(pulses) => pulses,
+                        None => {
+                            pin.set_low();
+                            deadline += Duration::from_micros(FRAME_LENGTH_US.into());
+                            wait_until(deadline);
+                            continue;
+                        }
+                    };
+
+                    for (index, duration) in encode_frame(&frame_pulses(pulses)).iter().enumerate()
+                    {
+                        if index % 2 == 0 {
+                            pin.set_high();
+                        } else {
+                            pin.set_low();
+                        }
+
+                        deadline += Duration::from_micros((*duration).into());
+                        wait_until(deadline);
+                    }
+                }
+
+                pin.set_low();
+            })?;
+
+        Ok(Self { pulses })
+    }
+
+    pub fn update_from_control_message_data(&mut self, data: ControlMessageData) {
+        if let Some(axes) = channels::axes(data) {
+            *self.pulses.lock().unwrap() = Some((channels::pulses(axes), Instant::now()));
+        }
+    }
+
+    pub fn disable(&mut self) {
+        *self.pulses.lock().unwrap() = None;
+    }
+}
+
+fn wait_until(deadline: Instant) {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining > SPIN_DURATION {
+        std::thread::sleep(remaining - SPIN_DURATION);
+    }
+
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_channels_are_followed_by_the_synchronization_gap() {
+        assert_eq!(
+            encode_frame(&[1500; CHANNELS]),
+            [
+                300, 1200, 300, 1200, 300, 1200, 300, 1200, 300, 1200, 300, 1200, 300, 1200, 300,
+                1200, 300, 10200,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_axes_are_sent_as_aetr_channels() {
+        let pulses = channels::pulses([-1.0, 1.0, 0.5, 0.75]);
+
+        assert_eq!(
+            encode_frame(&frame_pulses(pulses)),
+            [
+                300, 700, 300, 1700, 300, 1450, 300, 1450, 300, 1200, 300, 1200, 300, 1200, 300,
+                1200, 300, 9700,
+            ]
+        );
+    }
+
+    #[test]
+    fn pulse_widths_are_limited_and_frames_keep_their_length() {
+        for pulse in [0, 1000, 2000, u16::MAX] {
+            let durations = encode_frame(&[pulse; CHANNELS]);
+            let expected = u32::from(pulse.clamp(1000, 2000)) - SEPARATOR_US;
+
+            assert!(durations[1..2 * CHANNELS]
+                .iter()
+                .step_by(2)
+                .all(|&duration| duration == expected));
+            assert_eq!(durations.iter().sum::<u32>(), FRAME_LENGTH_US);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Mixes steering and throttle into the outputs of rovers and boats, which either steer by
+//! running the motors of each side at different speeds or with a steering servo in front of a
+//! single motor.
+
+use std::str::FromStr;
+
+/// How a rover steers, which determines what its two outputs drive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Steering {
+    /// Left and right motors, each on a reversing ESC, as on tank-style and skid-steer rovers.
+    Differential,
+    /// A steering servo and a single motor on a reversing ESC, as on cars.
+    Ackermann,
+}
+
+impl FromStr for Steering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "differential" => Ok(Self::Differential),
+            "ackermann" => Ok(Self::Ackermann),
+            _ => Err(format!(
+                "Invalid steering '{}', expected 'differential' or 'ackermann'",
+                s
+            )),
+        }
+    }
+}
+
+/// Outputs from -1 to 1 given steering and throttle from -1 to 1, where negative throttle
+/// reverses. Commands for ESCs within the deadband around neutral stop the motor, and the rest
+/// of the range is stretched so that full speed can still be reached.
+pub fn mix(steering: Steering, steering_axis: f64, throttle_axis: f64, deadband: f64) -> [f64; 2] {
+    let steering_axis = steering_axis.clamp(-1.0, 1.0);
+    let throttle_axis = throttle_axis.clamp(-1.0, 1.0);
+
+    match steering {
+        Steering::Differential => {
+            let left = throttle_axis + steering_axis;
+            let right = throttle_axis - steering_axis;
+
+            // Scaling both sides alike keeps the ratio between them, and thereby the turn.
+            let scale = left.abs().max(right.abs()).max(1.0);
+
+            [
+                apply_deadband(left / scale, deadband),
+                apply_deadband(right / scale, deadband),
+            ]
+        }
+        Steering::Ackermann => [steering_axis, apply_deadband(throttle_axis, deadband)],
+    }
+}
+
+fn apply_deadband(amount: f64, deadband: f64) -> f64 {
+    if amount.abs() <= deadband {
+        0.0
+    } else {
+        amount.signum() * (amount.abs() - deadband) / (1.0 - deadband)
+    }
+}
+
+/// Parses the deadband as a fraction of the range on either side of neutral.
+pub fn parse_deadband(s: &str) -> Result<f64, String> {
+    s.parse()
+        .ok()
+        .filter(|deadband| (0.0..1.0).contains(deadband))
+        .ok_or_else(|| {
+            format!(
+                "Invalid deadband '{}', expected at least 0 and less than 1",
+                s
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn differential_steering_turns_by_running_the_sides_at_different_speeds() {
+        assert_eq!(mix(Steering::Differential, 0.0, 0.5, 0.0), [0.5, 0.5]);
+        assert_eq!(mix(Steering::Differential, 0.5, 0.0, 0.0), [0.5, -0.5]);
+        assert_eq!(mix(Steering::Differential, -0.25, 0.5, 0.0), [0.25, 0.75]);
+        assert_eq!(mix(Steering::Differential, 0.0, -1.0, 0.0), [-1.0, -1.0]);
+    }
+
+    #[test]
+    fn differential_steering_keeps_the_turn_at_full_throttle() {
+        assert_eq!(mix(Steering::Differential, 0.25, 1.0, 0.0), [1.0, 0.6]);
+        assert_eq!(mix(Steering::Differential, -1.0, -1.0, 0.0), [-1.0, 0.0]);
+    }
+
+    #[test]
+    fn ackermann_steering_passes_steering_to_the_servo() {
+        assert_eq!(mix(Steering::Ackermann, -0.75, 0.5, 0.0), [-0.75, 0.5]);
+        assert_eq!(mix(Steering::Ackermann, 2.0, -2.0, 0.0), [1.0, -1.0]);
+    }
+
+    #[test]
+    fn escs_stop_within_the_deadband_and_reach_full_speed_outside_it() {
+        assert_eq!(mix(Steering::Ackermann, 0.125, 0.125, 0.25), [0.125, 0.0]);
+        assert_eq!(mix(Steering::Ackermann, 0.0, -0.25, 0.25), [0.0, 0.0]);
+        assert_eq!(mix(Steering::Ackermann, 0.0, 0.625, 0.25), [0.0, 0.5]);
+        assert_eq!(mix(Steering::Ackermann, 0.0, -1.0, 0.25), [0.0, -1.0]);
+        assert_eq!(mix(Steering::Differential, 0.125, 0.0, 0.25), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn deadbands_are_parsed_as_fractions_of_the_range() {
+        assert_eq!(parse_deadband("0.05"), Ok(0.05));
+        assert_eq!(parse_deadband("0"), Ok(0.0));
+        assert!(parse_deadband("1").is_err());
+        assert!(parse_deadband("-0.1").is_err());
+        assert!(parse_deadband("wide").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Sends the RC channels to a flight controller as a stream of SBUS frames on a UART.
+//!
+//! SBUS is an inverted signal, so the UART's TX pin must be connected through an inverter unless
+//! the flight controller's SBUS input accepts an uninverted signal.
+
+use std::{
+    io::Write,
+    path::Path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::Context;
+use aviator5g_common::ControlMessageData;
+
+use crate::{
+    channels,
+    serial,
+};
+
+pub const CHANNELS: usize = 16;
+pub const FRAME_LENGTH: usize = 25;
+
+const HEADER: u8 = 0x0f;
+const FOOTER: u8 = 0x00;
+const FLAG_FRAME_LOST: u8 = 0x04;
+const FLAG_FAILSAFE: u8 = 0x08;
+
+const BAUD_RATE: u32 = 100_000;
+
+/// Interval between frames in the standard, non-high-speed mode.
+const FRAME_INTERVAL: Duration = Duration::from_millis(14);
+
+/// Channel value for a pulse width in microseconds, the inverse of the conversion done by flight
+/// controllers, which maps 1000µs to 192, 1500µs to 992 and 2000µs to 1792.
+pub fn channel_value(pulse: u16) -> u16 {
+    ((u32::from(pulse.max(880)) - 880) * 8 / 5).min(0x07ff) as u16
+}
+
+/// Packs the 11-bit channel values, least significant bit first, between the header and the
+/// flags. Failsafe frames tell the flight controller that the pilot has been lost.
+pub fn encode_frame(channels: &[u16; CHANNELS], failsafe: bool) -> [u8; FRAME_LENGTH] {
+    let mut frame = [0; FRAME_LENGTH];
+    frame[0] = HEADER;
+
+    for (index, value) in channels.iter().enumerate() {
+        let bit = index * 11;
+        let bits = u32::from(value & 0x07ff) << (bit % 8);
+        let bytes = (bit % 8 + 11).div_ceil(8);
+        for (offset, byte) in bits.to_le_bytes()[..bytes].iter().enumerate() {
+            frame[1 + bit / 8 + offset] |= byte;
+        }
+    }
+
+    if failsafe {
+        frame[23] = FLAG_FRAME_LOST | FLAG_FAILSAFE;
+    }
+    frame[24] = FOOTER;
+
+    frame
+}
+
+/// Channel values of a frame given the pulse widths of the first four channels, with all other
+/// channels neutral.
+fn frame_channels(pulses: [u16; 4]) -> [u16; CHANNELS] {
+    let mut values = [channel_value(channels::NEUTRAL_PULSE); CHANNELS];
+    for (value, pulse) in values.iter_mut().zip(pulses) {
+        *value = channel_value(pulse);
+    }
+
+    values
+}
+
+/// SBUS output on a UART, which sends failsafe frames until the first control message, once
+/// control messages have stopped arriving and after it has been disabled.
+#[derive(Debug)]
+pub struct SbusOutput {
+    /// Pulse widths of the first four channels when they were received, or `None` while
+    /// disabled.
+    pulses: Arc<Mutex<Option<channels::ReceivedPulses>>>,
+}
+
+impl SbusOutput {
+    /// Opens the UART and starts sending frames on a dedicated thread, which stops once the
+    /// output has been dropped.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut port = serial::open(path, BAUD_RATE, serial::Framing::EvenParityTwoStopBits)
+            .with_context(|| format!("Could not open SBUS port {}", path.display()))?;
+        let pulses = Arc::new(Mutex::new(None));
+
+        let shared = Arc::downgrade(&pulses);
+        std::thread::Builder::new()
+            .name("sbus".into())
+            .spawn(move || {
+                let mut next_frame = Instant::now();
+                while let Some(pulses) = shared.upgrade() {
+                    let pulses = channels::fresh_pulses(*pulses.lock().unwrap(), Instant::now());
+                    let frame = encode_frame(
+                        &frame_channels(pulses.unwrap_or(channels::IDLE_PULSES)),
+                        pulses.is_none(),
+                    );
+
+                    if let Err(e) = port.write_all(&frame) {
+                        log::error!("Stopped sending SBUS frames: {}", e);
+                        break;
+                    }
+
+                    next_frame += FRAME_INTERVAL;
+                    std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+                }
+            })?;
+
+        Ok(Self { pulses })
+    }
+
+    pub fn update_from_control_message_data(&mut self, data: ControlMessageData) {
+        if let Some(axes) = channels::axes(data) {
+            *self.pulses.lock().unwrap() = Some((channels::pulses(axes), Instant::now()));
+        }
+    }
+
+    pub fn disable(&mut self) {
+        *self.pulses.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_channels_are_packed_between_header_and_flags() {
+        assert_eq!(
+            encode_frame(&[992; CHANNELS], false),
+            [
+                0x0f, 0xe0, 0x03, 0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0xe0, 0x03,
+                0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn each_channel_occupies_its_own_eleven_bits() {
+        let channels: Vec<u16> = (0..16).map(|index| 172 + 109 * index).collect();
+
+        assert_eq!(
+            encode_frame(&channels.try_into().unwrap(), true),
+            [
+                0x0f, 0xac, 0xc8, 0x88, 0x61, 0xe6, 0x03, 0xa6, 0x66, 0xe9, 0xec, 0x74, 0x14, 0x0c,
+                0xa4, 0x3b, 0xb7, 0x8a, 0xdc, 0x1a, 0x8b, 0xfa, 0xe1, 0x0c, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_values_are_limited_to_eleven_bits() {
+        let frame = encode_frame(&[0xffff; CHANNELS], false);
+
+        assert_eq!(frame[0], HEADER);
+        assert!(frame[1..23].iter().all(|&byte| byte == 0xff));
+        assert_eq!(frame[23..], [0x00, 0x00]);
+    }
+
+    #[test]
+    fn control_axes_are_sent_as_aetr_channels() {
+        let pulses = channels::pulses([-1.0, 1.0, 1.0, 0.5]);
+
+        assert_eq!(
+            encode_frame(&frame_channels(pulses), false),
+            [
+                0x0f, 0xc0, 0x00, 0x38, 0xf8, 0x00, 0x0e, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0xe0, 0x03,
+                0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn pulse_widths_are_converted_as_flight_controllers_convert_back() {
+        assert_eq!(channel_value(1000), 192);
+        assert_eq!(channel_value(1500), 992);
+        assert_eq!(channel_value(2000), 1792);
+        assert_eq!(channel_value(0), 0);
+        assert_eq!(channel_value(u16::MAX), 0x07ff);
+    }
+}
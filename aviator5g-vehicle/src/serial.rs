@@ -0,0 +1,72 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Opens serial ports in raw mode at any baud rate the UART supports.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+/// How characters are framed on the wire, always with 8 data bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// No parity and 1 stop bit.
+    Standard,
+    /// Even parity and 2 stop bits, as used by SBUS.
+    EvenParityTwoStopBits,
+}
+
+/// Opens the serial port for reading and writing. Reads block until at least one byte has
+/// arrived.
+pub fn open(path: &Path, baud_rate: u32, framing: Framing) -> io::Result<File> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe { libc::cfmakeraw(&mut termios) };
+    termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+    termios.c_cflag &= !(libc::CRTSCTS | libc::PARENB | libc::PARODD | libc::CSTOPB);
+    if framing == Framing::EvenParityTwoStopBits {
+        termios.c_cflag |= libc::PARENB | libc::CSTOPB;
+    }
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Baud rates without a constant of their own, such as the 100000 of SBUS, can only be set
+    // through the extended interface.
+    let mut termios2 = unsafe { std::mem::zeroed::<libc::termios2>() };
+    if unsafe { libc::ioctl(fd, libc::TCGETS2, &mut termios2) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    termios2.c_cflag &= !libc::CBAUD;
+    termios2.c_cflag |= libc::BOTHER;
+    termios2.c_ispeed = baud_rate;
+    termios2.c_ospeed = baud_rate;
+
+    if unsafe { libc::ioctl(fd, libc::TCSETS2, &termios2) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
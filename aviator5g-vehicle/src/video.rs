@@ -0,0 +1,489 @@
+/*
+ * AVIATOR 5G SYSTEM
+ * Copyright (c) 2021 SilentByte <https://silentbyte.com/>
+ */
+
+//! Sends all messages to the server through a single writer that always sends queued control
+//! messages before video frames, and adapts the video to the conditions of the link.
+
+use std::{
+    os::unix::io::{
+        AsRawFd,
+        RawFd,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use aviator5g_common::{
+    ClockSync,
+    Id,
+    VideoFrame,
+};
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::{
+    Sink,
+    SinkExt,
+    StreamExt,
+};
+use tokio::{
+    net::TcpStream,
+    sync::watch,
+};
+use tokio_tungstenite::{
+    MaybeTlsStream,
+    WebSocketStream,
+};
+
+use crate::camera::{
+    EncodedFrame,
+    EncodingSettings,
+    LatestFrame,
+    Resolution,
+};
+
+/// Round-trip time above which the link is considered degraded.
+const DEGRADED_RTT: Duration = Duration::from_millis(300);
+
+/// Round-trip time below which the link is considered good enough to raise the quality again.
+const GOOD_RTT: Duration = Duration::from_millis(150);
+
+/// Bytes that may wait in the socket's send queue before the link is considered congested, unless
+/// the last frame has been larger.
+const MIN_CONGESTED_QUEUE: usize = 16 * 1024;
+
+/// Minimum time between two steps down, giving the link time to react to the previous one.
+const STEP_DOWN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time for which the link must have been good before the quality is raised by a step.
+const STEP_UP_AFTER: Duration = Duration::from_secs(5);
+
+/// Percentages of the configured resolution, quality and frame rate, from best to worst.
+const STEPS: [(u32, u32, u32); 5] = [
+    (100, 100, 100),
+    (100, 70, 100),
+    (75, 60, 75),
+    (50, 50, 50),
+    (25, 40, 25),
+];
+
+/// What the link looked like when a frame was about to be sent.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConditions {
+    /// Bytes waiting in the socket's send queue, including those not yet acknowledged.
+    pub queued_bytes: usize,
+    /// Size of the last frame that has been sent.
+    pub frame_bytes: usize,
+    /// Round-trip time to the server, as last measured when synchronizing the clock.
+    pub rtt: Option<Duration>,
+}
+
+impl LinkConditions {
+    /// Whether more than a whole frame is still waiting to be sent, in which case another frame
+    /// would only delay the control messages sent after it.
+    pub fn is_backlogged(&self) -> bool {
+        self.queued_bytes > self.frame_bytes.max(MIN_CONGESTED_QUEUE)
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.is_backlogged() || self.rtt.is_some_and(|rtt| rtt > DEGRADED_RTT)
+    }
+
+    pub fn is_good(&self) -> bool {
+        !self.is_congested() && self.rtt.is_none_or(|rtt| rtt <= GOOD_RTT)
+    }
+}
+
+/// Steps the encoding settings down as soon as the link is congested and back up once it has
+/// been good for a while, so that the quality does not oscillate.
+#[derive(Debug)]
+pub struct AdaptiveQuality {
+    max: EncodingSettings,
+    step: usize,
+    changed_at: Instant,
+    good_since: Option<Instant>,
+}
+
+impl AdaptiveQuality {
+    pub fn new(max: EncodingSettings, now: Instant) -> Self {
+        Self {
+            max,
+            step: 0,
+            changed_at: now,
+            good_since: None,
+        }
+    }
+
+    pub fn settings(&self) -> EncodingSettings {
+        let (resolution, quality, frame_rate) = STEPS[self.step];
+        let percent = |value: u32, percent: u32| (value * percent / 100).max(1);
+
+        EncodingSettings {
+            resolution: Resolution {
+                width: percent(self.max.resolution.width.into(), resolution) as u16,
+                height: percent(self.max.resolution.height.into(), resolution) as u16,
+            },
+            quality: percent(self.max.quality.into(), quality) as u8,
+            frame_rate: percent(self.max.frame_rate, frame_rate),
+        }
+    }
+
+    /// Returns the new settings if the conditions call for a change.
+    pub fn update(
+        &mut self,
+        conditions: &LinkConditions,
+        now: Instant,
+    ) -> Option<EncodingSettings> {
+        if conditions.is_congested() {
+            self.good_since = None;
+            if self.step + 1 < STEPS.len() && now - self.changed_at >= STEP_DOWN_INTERVAL {
+                self.step += 1;
+                self.changed_at = now;
+                return Some(self.settings());
+            }
+        } else if conditions.is_good() {
+            let good_since = *self.good_since.get_or_insert(now);
+            if self.step > 0 && now - good_since >= STEP_UP_AFTER {
+                self.step -= 1;
+                self.changed_at = now;
+                self.good_since = Some(now);
+                return Some(self.settings());
+            }
+        } else {
+            self.good_since = None;
+        }
+
+        None
+    }
+}
+
+/// Measures the bytes waiting in the send queue of the connection's TCP socket.
+#[derive(Debug, Clone, Copy)]
+pub struct SendQueue(Option<RawFd>);
+
+impl SendQueue {
+    /// The socket stays open as long as the stream or either of its halves exists, which the
+    /// writer owning the queue must ensure.
+    pub fn of(stream: &WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
+        match stream.get_ref() {
+            MaybeTlsStream::Plain(stream) => Self(Some(stream.as_raw_fd())),
+            _ => Self(None),
+        }
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        let fd = match self.0 {
+            Some(fd) => fd,
+            None => return 0,
+        };
+
+        let mut queued: libc::c_int = 0;
+        // SAFETY: TIOCOUTQ only writes the number of queued bytes to the given integer.
+        match unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut queued) } {
+            0 => queued.max(0) as usize,
+            _ => 0,
+        }
+    }
+}
+
+/// The frames of one camera along with the settings they are encoded at.
+struct VideoStream {
+    stream_id: String,
+    frames: watch::Receiver<LatestFrame>,
+    settings: watch::Sender<EncodingSettings>,
+    quality: AdaptiveQuality,
+    last_frame_bytes: usize,
+}
+
+impl VideoStream {
+    async fn next_frame(&mut self) -> Arc<EncodedFrame> {
+        loop {
+            if self.frames.changed().await.is_err() {
+                // The camera has stopped, control messages keep flowing.
+                return std::future::pending().await;
+            }
+
+            if let Some(frame) = self.frames.borrow().clone() {
+                return frame;
+            }
+        }
+    }
+}
+
+/// Turns captured frames into messages, adapting the encoding settings of each stream while the
+/// link is congested and dropping frames while the send queue is backlogged.
+pub struct VideoSender {
+    source_id: Id,
+    streams: Vec<VideoStream>,
+    rtt: watch::Receiver<Option<Duration>>,
+    send_queue: SendQueue,
+    clock_sync: Arc<Mutex<ClockSync>>,
+}
+
+impl VideoSender {
+    pub fn new(
+        source_id: Id,
+        rtt: watch::Receiver<Option<Duration>>,
+        send_queue: SendQueue,
+        clock_sync: Arc<Mutex<ClockSync>>,
+    ) -> Self {
+        Self {
+            source_id,
+            streams: Vec::new(),
+            rtt,
+            send_queue,
+            clock_sync,
+        }
+    }
+
+    /// The current value of `settings` is the best quality the stream is adapted from.
+    pub fn add_stream(
+        &mut self,
+        stream_id: String,
+        frames: watch::Receiver<LatestFrame>,
+        settings: watch::Sender<EncodingSettings>,
+    ) {
+        let quality = AdaptiveQuality::new(*settings.borrow(), Instant::now());
+
+        self.streams.push(VideoStream {
+            stream_id,
+            frames,
+            settings,
+            quality,
+            last_frame_bytes: 0,
+        });
+    }
+
+    /// Waits for the next frame of any stream and returns it along with the stream's index.
+    async fn next_frame(&mut self) -> (usize, Arc<EncodedFrame>) {
+        if self.streams.is_empty() {
+            return std::future::pending().await;
+        }
+
+        let frames = self
+            .streams
+            .iter_mut()
+            .map(|stream| Box::pin(stream.next_frame()));
+        let (frame, index, _) = futures_util::future::select_all(frames).await;
+        (index, frame)
+    }
+
+    fn message(&mut self, index: usize, frame: &EncodedFrame) -> Option<tungstenite::Message> {
+        let stream = &mut self.streams[index];
+        let conditions = LinkConditions {
+            queued_bytes: self.send_queue.queued_bytes(),
+            frame_bytes: stream.last_frame_bytes,
+            rtt: *self.rtt.borrow(),
+        };
+
+        if let Some(settings) = stream.quality.update(&conditions, Instant::now()) {
+            log::info!(
+                "Adapting stream '{}' to {:?} after {:?}",
+                stream.stream_id,
+                settings,
+                conditions
+            );
+            let _ = stream.settings.send(settings);
+        }
+
+        if conditions.is_backlogged() {
+            log::debug!("Dropping frame, send queue is backlogged: {:?}", conditions);
+            return None;
+        }
+
+        let captured_at = self
+            .clock_sync
+            .lock()
+            .unwrap()
+            .to_server_time(frame.captured_at)
+            .unwrap_or(frame.captured_at);
+
+        stream.last_frame_bytes = frame.jpeg.len();
+        let video_frame = VideoFrame {
+            source_id: self.source_id,
+            stream_id: stream.stream_id.clone(),
+            captured_at,
+            jpeg: frame.jpeg.clone(),
+        };
+
+        match video_frame.encode() {
+            Ok(bytes) => Some(tungstenite::Message::Binary(bytes)),
+            Err(e) => {
+                log::error!("Dropping frame of stream '{}': {}", stream.stream_id, e);
+                None
+            }
+        }
+    }
+}
+
+/// Sends messages until the channel has been closed or the connection fails. A frame is only sent
+/// when no control message is waiting, so control messages are never delayed by more than the
+/// frame that is being sent.
+pub async fn send_messages<S>(
+    mut sink: S,
+    mut messages: UnboundedReceiver<tungstenite::Message>,
+    mut video: Option<VideoSender>,
+) -> Result<(), tungstenite::Error>
+where
+    S: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    loop {
+        let message = tokio::select! {
+            biased;
+            message = messages.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            (index, frame) = next_frame(&mut video) => {
+                match video.as_mut().and_then(|video| video.message(index, &frame)) {
+                    Some(message) => message,
+                    None => continue,
+                }
+            }
+        };
+
+        sink.send(message).await?;
+    }
+
+    sink.close().await
+}
+
+async fn next_frame(video: &mut Option<VideoSender>) -> (usize, Arc<EncodedFrame>) {
+    match video {
+        Some(video) => video.next_frame().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: EncodingSettings = EncodingSettings {
+        resolution: Resolution {
+            width: 640,
+            height: 480,
+        },
+        quality: 80,
+        frame_rate: 30,
+    };
+
+    fn rtt(millis: u64) -> LinkConditions {
+        LinkConditions {
+            queued_bytes: 0,
+            frame_bytes: 8 * 1024,
+            rtt: Some(Duration::from_millis(millis)),
+        }
+    }
+
+    fn queued(queued_bytes: usize, frame_bytes: usize) -> LinkConditions {
+        LinkConditions {
+            queued_bytes,
+            frame_bytes,
+            rtt: None,
+        }
+    }
+
+    fn settings(width: u16, height: u16, quality: u8, frame_rate: u32) -> EncodingSettings {
+        EncodingSettings {
+            resolution: Resolution { width, height },
+            quality,
+            frame_rate,
+        }
+    }
+
+    #[test]
+    fn link_is_congested_by_round_trip_time_or_backlog() {
+        assert!(rtt(301).is_congested());
+        assert!(!rtt(300).is_congested() && !rtt(300).is_good());
+        assert!(rtt(150).is_good());
+        assert!(queued(0, 0).is_good());
+
+        // A single large frame in flight is not a backlog.
+        assert!(!queued(16 * 1024, 0).is_backlogged());
+        assert!(queued(16 * 1024 + 1, 0).is_congested());
+        assert!(!queued(48 * 1024, 64 * 1024).is_backlogged());
+        assert!(queued(64 * 1024 + 1, 64 * 1024).is_congested());
+    }
+
+    #[test]
+    fn quality_steps_down_at_most_once_per_interval_while_congested() {
+        let start = Instant::now();
+        let mut quality = AdaptiveQuality::new(MAX, start);
+        assert_eq!(quality.settings(), MAX);
+
+        assert_eq!(quality.update(&rtt(400), start), None);
+        assert_eq!(
+            quality.update(&rtt(400), start + STEP_DOWN_INTERVAL),
+            Some(settings(640, 480, 56, 30))
+        );
+        assert_eq!(
+            quality.update(&queued(64 * 1024, 0), start + STEP_DOWN_INTERVAL * 3 / 2),
+            None
+        );
+        assert_eq!(
+            quality.update(&queued(64 * 1024, 0), start + STEP_DOWN_INTERVAL * 2),
+            Some(settings(480, 360, 48, 22))
+        );
+    }
+
+    #[test]
+    fn quality_steps_up_only_after_the_link_has_been_good_for_a_while() {
+        let start = Instant::now();
+        let mut quality = AdaptiveQuality::new(MAX, start);
+        quality.update(&rtt(400), start + STEP_DOWN_INTERVAL);
+        quality.update(&rtt(400), start + STEP_DOWN_INTERVAL * 2);
+
+        // Round-trip times between good and degraded neither lower nor raise the quality.
+        let good_at = start + STEP_DOWN_INTERVAL * 3;
+        assert_eq!(quality.update(&rtt(100), good_at), None);
+        assert_eq!(quality.update(&rtt(200), good_at + STEP_UP_AFTER), None);
+
+        let good_at = good_at + STEP_UP_AFTER;
+        assert_eq!(quality.update(&rtt(100), good_at), None);
+        assert_eq!(quality.update(&rtt(100), good_at + STEP_UP_AFTER / 2), None);
+        assert_eq!(
+            quality.update(&rtt(100), good_at + STEP_UP_AFTER),
+            Some(settings(640, 480, 56, 30))
+        );
+        assert_eq!(
+            quality.update(&rtt(100), good_at + STEP_UP_AFTER * 2),
+            Some(MAX)
+        );
+    }
+
+    #[test]
+    fn quality_stays_within_its_steps() {
+        let start = Instant::now();
+        let mut quality = AdaptiveQuality::new(MAX, start);
+        assert_eq!(quality.update(&rtt(100), start + STEP_UP_AFTER * 2), None);
+
+        let mut now = start;
+        for _ in 1..STEPS.len() {
+            now += STEP_DOWN_INTERVAL;
+            assert!(quality.update(&rtt(400), now).is_some());
+        }
+        assert_eq!(quality.settings(), settings(160, 120, 32, 7));
+        assert_eq!(quality.update(&rtt(400), now + STEP_DOWN_INTERVAL), None);
+        assert_eq!(quality.settings(), settings(160, 120, 32, 7));
+    }
+
+    #[test]
+    fn settings_never_drop_to_zero() {
+        let start = Instant::now();
+        let mut quality = AdaptiveQuality::new(settings(2, 2, 1, 1), start);
+        let mut now = start;
+        for _ in 1..STEPS.len() {
+            now += STEP_DOWN_INTERVAL;
+            quality.update(&rtt(400), now);
+        }
+
+        assert_eq!(quality.settings(), settings(1, 1, 1, 1));
+    }
+}